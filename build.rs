@@ -1,6 +1,11 @@
 static MANIFEST: &str = include_str!("manifest.xml");
 
 fn main() {
+    // only the windows dll gets stamped. linux builds are just for running the tests
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("windows") {
+        return;
+    }
+
     // stamp dll with project metadata
     let mut res = winres::WindowsResource::new();
 
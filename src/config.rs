@@ -4,18 +4,66 @@ use std::path::Path;
 use eyre::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    debugger::DebuggerConfig, game_version::UnknownVersion, hooks::ConflictPolicy,
+    patches::PatchConfig, watchdog::DEFAULT_DEADLINE,
+};
+
+/// Name of the config file, next to the dll
+pub const CONFIG_FILENAME: &str = "my-config.toml";
+
 /// Need to figure out how to make a proper config?
 /// quicktype.io can help you by converting json to Rust
 ///
 /// serde(default) will use defaults of lines that're missing in the config;
 /// this can help if users don't have a fully valid config file, and won't
 /// fail to deserialize on them.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub opt1: bool,
     pub opt2: String,
+    /// Log SEH exceptions (access violations, etc) that happen in our code to the logfile
+    pub exception_handler: bool,
+    /// Write a minidump to the logs folder on panics and SEH exceptions
+    pub minidumps: bool,
+    /// How many minidumps to keep before the oldest get deleted
+    pub max_minidumps: usize,
+    /// Warn in the log if any single init phase takes longer than this many milliseconds
+    pub init_phase_deadline_ms: u64,
+    /// How long to wait for the plugin's ready conditions before giving up on loading it
+    pub ready_timeout_ms: u64,
+    /// What to do if the game isn't a version the plugin supports: `unverified` runs anyways,
+    /// `refuse` runs without installing hooks or patches
+    pub unknown_game_version: UnknownVersion,
+    /// What to do when another mod already hooked a function we hook: `chain` hooks on top of theirs,
+    /// `skip` leaves our hook disabled, `fail` refuses to install it
+    pub hook_conflicts: ConflictPolicy,
     // etc
+
+    // sections (tables) go below all plain values
+    pub debugger: DebuggerConfig,
+    /// Byte patches, see `patches::load`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<PatchConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            opt1: false,
+            opt2: String::new(),
+            exception_handler: false,
+            minidumps: false,
+            max_minidumps: 5,
+            init_phase_deadline_ms: DEFAULT_DEADLINE.as_millis() as u64,
+            ready_timeout_ms: 60_000,
+            unknown_game_version: UnknownVersion::default(),
+            hook_conflicts: ConflictPolicy::default(),
+            debugger: DebuggerConfig::default(),
+            patches: Vec::new(),
+        }
+    }
 }
 
 impl Config {
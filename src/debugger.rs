@@ -0,0 +1,112 @@
+use std::{
+    env, process, thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use windows::Win32::System::Diagnostics::Debug::{DebugBreak, IsDebuggerPresent};
+
+use crate::{
+    popup::{MessageBoxIcon, display_popup},
+    worker,
+};
+
+/// Set to `1`/`true` or `0`/`false` to override `debugger.wait` from the config
+/// without having to touch the config file
+pub const WAIT_ENV_VAR: &str = "MY_PLUGIN_WAIT_FOR_DEBUGGER";
+
+/// `[debugger]` section of the config
+///
+/// Waiting for a debugger is on by default in debug builds only, but it can be enabled in
+/// release builds too if you need to debug something out in the field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebuggerConfig {
+    /// Wait for a debugger to attach before running any plugin code
+    pub wait: bool,
+    /// Give up waiting after this many milliseconds. 0 waits forever
+    pub timeout_ms: u64,
+    /// Show a popup with our PID while waiting, so you know what to attach to
+    pub popup: bool,
+    /// Break right after the debugger attached
+    pub breakpoint: bool,
+}
+
+impl Default for DebuggerConfig {
+    fn default() -> Self {
+        Self {
+            wait: cfg!(debug_assertions),
+            timeout_ms: 30_000,
+            popup: false,
+            breakpoint: false,
+        }
+    }
+}
+
+/// Whether we should wait, after applying the env var override
+fn should_wait(config: &DebuggerConfig) -> bool {
+    match env::var(WAIT_ENV_VAR).as_deref() {
+        Ok("1" | "true") => true,
+        Ok("0" | "false") => false,
+        _ => config.wait,
+    }
+}
+
+/// Wait for a debugger to attach, if enabled
+///
+/// If you're getting a hang on the game when you start it, it's because this is enabled
+/// and you haven't attached a debugger! The config has a timeout for exactly that reason.
+pub fn wait_for_debugger(config: &DebuggerConfig) {
+    if !should_wait(config) {
+        return;
+    }
+
+    let is_debugger_present = || unsafe { IsDebuggerPresent().as_bool() };
+    if is_debugger_present() {
+        return;
+    }
+
+    let pid = process::id();
+    info!("waiting for debugger (PID {pid})");
+
+    if config.popup {
+        // don't block the wait on someone clicking ok. there's no cancelling a message box,
+        // but as a worker it at least gets reported if it's still open when we're unloaded
+        let popup = worker::spawn("debugger popup", move |_| {
+            display_popup(
+                "Waiting for debugger",
+                &format!("Waiting for debugger (PID {pid})"),
+                MessageBoxIcon::Information,
+            );
+        });
+
+        if let Err(e) = popup {
+            warn!("failed to show the debugger popup: {e}");
+        }
+    }
+
+    let timeout = (config.timeout_ms > 0).then(|| Duration::from_millis(config.timeout_ms));
+    let start = Instant::now();
+
+    while !is_debugger_present() {
+        if timeout.is_some_and(|t| start.elapsed() >= t) {
+            warn!(
+                "no debugger attached after {}ms, continuing",
+                config.timeout_ms
+            );
+            return;
+        }
+
+        // 60hz polling
+        thread::sleep(Duration::from_millis(16));
+    }
+
+    info!("debugger attached");
+
+    if config.breakpoint {
+        unsafe {
+            DebugBreak();
+        }
+    }
+}
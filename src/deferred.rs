@@ -0,0 +1,226 @@
+use std::{
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+use eyre::{Result, bail};
+use libmem::Address;
+use log::{debug, info};
+use windows::{Win32::System::LibraryLoader::GetModuleHandleW, core::HSTRING};
+
+use crate::{
+    memory::{CurrentProcess, MemoryBackend},
+    pattern::{Pattern, scan_module},
+    resolver::Resolver,
+    worker::CancellationToken,
+};
+
+/// How often conditions are checked
+const POLL: Duration = Duration::from_millis(100);
+/// Longest wait between scans for a pattern that wasn't found yet
+const MAX_SCAN_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Something that must be true before the plugin's `on_load` runs
+///
+/// Return these from `Plugin::ready_when` if the game isn't ready for us yet at the time
+/// we're loaded, e.g. a module isn't loaded yet, or some global singleton is still null
+pub enum Condition {
+    /// A module with this name (e.g. `bg3.exe`, `some.dll`) is loaded
+    ModuleLoaded(String),
+    /// A pattern can be found inside the code of this module, and `resolver` gets from the match
+    /// to a non-null address. e.g. the `mov rax, [rip+disp32]` that loads a global singleton, and
+    /// the singleton is created. With no steps in `resolver`, being found is enough
+    Pattern {
+        module: String,
+        pattern: Pattern,
+        resolver: Resolver,
+    },
+    /// At least this much time has passed since we started waiting
+    Delay(Duration),
+    /// Anything else. e.g. "this pointer resolves to something non-null"
+    Custom {
+        name: String,
+        check: Box<dyn Fn() -> bool + Send>,
+    },
+}
+
+impl Condition {
+    pub fn module_loaded(module: impl Into<String>) -> Self {
+        Self::ModuleLoaded(module.into())
+    }
+
+    /// `pattern` can be found in `module`
+    pub fn pattern(module: impl Into<String>, pattern: Pattern) -> Self {
+        Self::pattern_resolves(module, pattern, Resolver::new())
+    }
+
+    /// `pattern` can be found in `module`, and `resolver` turns the match into a non-null address
+    ///
+    /// ```ignore
+    /// // 48 8B 0D ?? ?? ?? ?? is `mov rcx, [rip+disp32]` loading the global
+    /// let pattern = Pattern::parse("48 8B 0D ?? ?? ?? ?? E8")?;
+    /// Condition::pattern_resolves("bg3.exe", pattern, Resolver::new().rip_relative(3, 7).deref())
+    /// ```
+    pub fn pattern_resolves(
+        module: impl Into<String>,
+        pattern: Pattern,
+        resolver: Resolver,
+    ) -> Self {
+        Self::Pattern {
+            module: module.into(),
+            pattern,
+            resolver,
+        }
+    }
+
+    pub fn delay(delay: Duration) -> Self {
+        Self::Delay(delay)
+    }
+
+    pub fn custom(name: impl Into<String>, check: impl Fn() -> bool + Send + 'static) -> Self {
+        Self::Custom {
+            name: name.into(),
+            check: Box::new(check),
+        }
+    }
+
+    fn is_met(&self, waiting_since: Instant, scan: &mut Option<Scan>) -> bool {
+        match self {
+            Self::ModuleLoaded(module) => unsafe {
+                GetModuleHandleW(&HSTRING::from(module.as_str())).is_ok()
+            },
+
+            Self::Pattern {
+                module,
+                pattern,
+                resolver,
+            } => {
+                let Some(found) = Scan::update(scan, module, pattern) else {
+                    return false;
+                };
+
+                // a step failing is normal while the game is still setting things up
+                resolver
+                    .resolve(&CurrentProcess, found)
+                    .is_ok_and(|address| address != 0)
+            }
+
+            Self::Delay(delay) => waiting_since.elapsed() >= *delay,
+
+            Self::Custom { check, .. } => check(),
+        }
+    }
+}
+
+/// Where a [`Condition::Pattern`] was found. Scanning all of a module's code every poll is a lot of
+/// work for something that doesn't move, so only the resolver runs again until the module is reloaded
+struct Scan {
+    /// The module is scanned again if it's loaded somewhere else
+    base: Address,
+    found: Option<Address>,
+    /// Not found yet, e.g. the code isn't unpacked yet. Scanned again then, waiting longer every time
+    next: Instant,
+    backoff: Duration,
+}
+
+impl Scan {
+    /// Scan if there's no up to date scan, and return the match
+    fn update(scan: &mut Option<Self>, module: &str, pattern: &Pattern) -> Option<Address> {
+        let loaded = CurrentProcess.find_module(module)?;
+
+        let backoff = match scan {
+            Some(scan) if scan.base != loaded.base => POLL,
+            Some(scan) if scan.found.is_some() || Instant::now() < scan.next => return scan.found,
+            Some(scan) => (scan.backoff * 2).min(MAX_SCAN_BACKOFF),
+            None => POLL,
+        };
+
+        let found = scan_module(&CurrentProcess, module, pattern).ok().flatten();
+
+        *scan = Some(Self {
+            base: loaded.base,
+            found,
+            next: Instant::now() + backoff,
+            backoff,
+        });
+
+        found
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ModuleLoaded(module) => write!(f, "module `{module}` loaded"),
+            Self::Pattern {
+                module,
+                pattern,
+                resolver,
+            } => {
+                write!(f, "pattern `{pattern}` in `{module}`")?;
+
+                if !resolver.steps().is_empty() {
+                    write!(f, " resolving to non-null")?;
+                }
+
+                Ok(())
+            }
+            Self::Delay(delay) => write!(f, "delay of {}ms", delay.as_millis()),
+            Self::Custom { name, .. } => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Block until every condition has been met at least once, `timeout` runs out, or `token` is cancelled
+///
+/// A condition only needs to be met once; after that it isn't checked again
+pub fn wait(
+    conditions: Vec<Condition>,
+    timeout: Duration,
+    token: &CancellationToken,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut pending = conditions
+        .into_iter()
+        .map(|condition| (condition, None))
+        .collect::<Vec<_>>();
+
+    loop {
+        pending.retain_mut(|(condition, scan)| {
+            let met = condition.is_met(start, scan);
+            if met {
+                debug!("ready condition met: {condition}");
+            }
+
+            !met
+        });
+
+        if pending.is_empty() {
+            info!(
+                "all ready conditions met after {}ms",
+                start.elapsed().as_millis()
+            );
+            return Ok(());
+        }
+
+        if start.elapsed() >= timeout {
+            bail!(
+                "timed out after {}ms waiting for: {}",
+                timeout.as_millis(),
+                describe(&pending)
+            );
+        }
+
+        if token.wait(POLL) {
+            bail!("cancelled while waiting for: {}", describe(&pending));
+        }
+    }
+}
+
+fn describe(pending: &[(Condition, Option<Scan>)]) -> String {
+    pending
+        .iter()
+        .map(|(condition, _)| condition.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, Waker},
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+/// Queue for tasks that have to run on the game's own thread
+///
+/// Many game apis may only be called from the game thread, but our code runs on the Init thread
+/// (or a worker). Dispatch tasks to [`GAME_THREAD`] from anywhere, and drain it from a detour that
+/// runs on the game thread, e.g. a hooked per-frame function:
+///
+/// ```ignore
+/// extern "C" fn on_frame() {
+///     dispatch::GAME_THREAD.drain(Duration::from_millis(2));
+///     // call original..
+/// }
+/// ```
+pub static GAME_THREAD: DispatchQueue = DispatchQueue::new();
+
+type Task = Box<dyn FnOnce() + Send>;
+
+pub struct DispatchQueue {
+    tasks: Mutex<VecDeque<Task>>,
+    /// The thread currently draining this queue, if any
+    draining: Mutex<Option<ThreadId>>,
+}
+
+impl Default for DispatchQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DispatchQueue {
+    pub const fn new() -> Self {
+        Self {
+            tasks: Mutex::new(VecDeque::new()),
+            draining: Mutex::new(None),
+        }
+    }
+
+    /// Queue a task, without caring about the result
+    pub fn dispatch(&self, f: impl FnOnce() + Send + 'static) {
+        self.push(Box::new(f));
+    }
+
+    /// Queue a task, and get a future that resolves to its result
+    ///
+    /// The future can be `.await`ed, or blocked on with [`TaskFuture::wait`]
+    pub fn dispatch_with_result<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> TaskFuture<T> {
+        let (completer, future) = channel();
+
+        self.push(Box::new(move || completer.complete(f())));
+
+        future
+    }
+
+    /// Queue a task and block until it ran, for up to `timeout`
+    ///
+    /// If this is called from the thread draining the queue, the task runs right away instead
+    /// (waiting would never finish). Fails if the task panicked or the queue was cleared.
+    ///
+    /// If the task hasn't started by the time `timeout` runs out, it's skipped and this fails too,
+    /// e.g. when the game is stuck on a loading screen and doesn't drain the queue. A task that
+    /// already started is waited for, so the task either ran and you get its result, or never runs
+    pub fn dispatch_and_wait<T: Send + 'static>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, Abandoned> {
+        if self.is_draining_thread() {
+            return Ok(f());
+        }
+
+        // whoever sets this first decides whether the task runs
+        let started = Arc::new(AtomicBool::new(false));
+
+        let future = {
+            let started = started.clone();
+            self.dispatch_with_result(move || {
+                if started.swap(true, Ordering::AcqRel) {
+                    return None;
+                }
+
+                Some(f())
+            })
+        };
+
+        match future.wait_timeout(timeout) {
+            Ok(result) => result?.ok_or(Abandoned),
+
+            // too late to skip it, and it won't be long now
+            Err(future) if started.swap(true, Ordering::AcqRel) => future.wait()?.ok_or(Abandoned),
+
+            Err(_) => Err(Abandoned),
+        }
+    }
+
+    /// Run queued tasks until the queue is empty or `budget` is used up
+    ///
+    /// At least one task always runs, so a tiny budget can't starve the queue.
+    /// Tasks dispatched while draining run in the same drain if there's budget left.
+    /// Returns how many tasks ran
+    pub fn drain(&self, budget: Duration) -> usize {
+        let start = Instant::now();
+        let previous = self.set_draining(Some(thread::current().id()));
+
+        let mut ran = 0;
+        while ran == 0 || start.elapsed() < budget {
+            let Some(task) = self.pop() else {
+                break;
+            };
+
+            // a panicking task must not take the game thread down with it.
+            // its future (if any) resolves to `Abandoned`
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(task)) {
+                // same as in Init; the panic hook already logged it
+                mem::forget(e);
+            }
+
+            ran += 1;
+        }
+
+        self.set_draining(previous);
+
+        ran
+    }
+
+    /// Number of queued tasks
+    pub fn len(&self) -> usize {
+        self.tasks.lock().map(|t| t.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every queued task without running it. Their futures resolve to `Abandoned`
+    pub fn clear(&self) {
+        let tasks = match self.tasks.lock() {
+            Ok(mut tasks) => mem::take(&mut *tasks),
+            Err(_) => return,
+        };
+
+        // dropped outside of the lock, dropping a task may dispatch another
+        drop(tasks);
+    }
+
+    fn push(&self, task: Task) {
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push_back(task);
+        }
+    }
+
+    fn pop(&self) -> Option<Task> {
+        self.tasks.lock().ok()?.pop_front()
+    }
+
+    fn set_draining(&self, thread: Option<ThreadId>) -> Option<ThreadId> {
+        match self.draining.lock() {
+            Ok(mut draining) => mem::replace(&mut *draining, thread),
+            Err(_) => None,
+        }
+    }
+
+    fn is_draining_thread(&self) -> bool {
+        self.draining
+            .lock()
+            .is_ok_and(|d| *d == Some(thread::current().id()))
+    }
+}
+
+/// The task was dropped before it produced a result, because it panicked, the queue was cleared,
+/// or [`DispatchQueue::dispatch_and_wait`] gave up on it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Abandoned;
+
+enum State<T> {
+    Pending(Option<Waker>),
+    Done(T),
+    Abandoned,
+    Taken,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    cvar: Condvar,
+}
+
+fn channel<T>() -> (Completer<T>, TaskFuture<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State::Pending(None)),
+        cvar: Condvar::new(),
+    });
+
+    let completer = Completer {
+        shared: Some(shared.clone()),
+    };
+
+    (completer, TaskFuture { shared })
+}
+
+/// Resolves its future. If dropped without completing, the future is abandoned
+struct Completer<T> {
+    shared: Option<Arc<Shared<T>>>,
+}
+
+impl<T> Completer<T> {
+    fn complete(mut self, value: T) {
+        if let Some(shared) = self.shared.take() {
+            resolve(&shared, State::Done(value));
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            resolve(&shared, State::Abandoned);
+        }
+    }
+}
+
+fn resolve<T>(shared: &Shared<T>, new: State<T>) {
+    let waker = match shared.state.lock() {
+        Ok(mut state) => match mem::replace(&mut *state, new) {
+            State::Pending(waker) => waker,
+            _ => None,
+        },
+        Err(_) => None,
+    };
+
+    shared.cvar.notify_all();
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Result of a dispatched task
+pub struct TaskFuture<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> TaskFuture<T> {
+    /// Block until the task ran
+    ///
+    /// Never call this from the thread draining the queue, it would wait forever
+    pub fn wait(self) -> Result<T, Abandoned> {
+        let Ok(state) = self.shared.state.lock() else {
+            return Err(Abandoned);
+        };
+
+        let Ok(mut state) = self
+            .shared
+            .cvar
+            .wait_while(state, |s| matches!(s, State::Pending(_)))
+        else {
+            return Err(Abandoned);
+        };
+
+        take(&mut state)
+    }
+
+    /// Block until the task ran, or `timeout` passed. On timeout the future is handed back
+    pub fn wait_timeout(self, timeout: Duration) -> Result<Result<T, Abandoned>, Self> {
+        let result = {
+            let Ok(state) = self.shared.state.lock() else {
+                return Ok(Err(Abandoned));
+            };
+
+            let Ok((mut state, _)) = self
+                .shared
+                .cvar
+                .wait_timeout_while(state, timeout, |s| matches!(s, State::Pending(_)))
+            else {
+                return Ok(Err(Abandoned));
+            };
+
+            match *state {
+                State::Pending(_) => None,
+                _ => Some(take(&mut state)),
+            }
+        };
+
+        result.ok_or(self)
+    }
+
+    /// Whether the task already ran (or was abandoned)
+    pub fn is_ready(&self) -> bool {
+        self.shared
+            .state
+            .lock()
+            .map(|s| !matches!(*s, State::Pending(_)))
+            .unwrap_or(true)
+    }
+}
+
+fn take<T>(state: &mut State<T>) -> Result<T, Abandoned> {
+    match mem::replace(state, State::Taken) {
+        State::Done(value) => Ok(value),
+        _ => Err(Abandoned),
+    }
+}
+
+impl<T> Future for TaskFuture<T> {
+    type Output = Result<T, Abandoned>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Ok(mut state) = self.shared.state.lock() else {
+            return Poll::Ready(Err(Abandoned));
+        };
+
+        match &mut *state {
+            State::Pending(waker) => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => Poll::Ready(take(&mut state)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_in_order() {
+        let queue = DispatchQueue::new();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        for n in 0..3 {
+            let ran = ran.clone();
+            queue.dispatch(move || ran.lock().unwrap().push(n));
+        }
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.drain(Duration::from_secs(1)), 3);
+        assert!(queue.is_empty());
+        assert_eq!(*ran.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn drain_runs_at_least_one() {
+        let queue = DispatchQueue::new();
+        queue.dispatch(|| ());
+        queue.dispatch(|| ());
+
+        assert_eq!(queue.drain(Duration::ZERO), 1);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn wait_from_another_thread() {
+        let queue = DispatchQueue::new();
+        let game_thread = thread::current().id();
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                queue.dispatch_and_wait(Duration::from_secs(10), || thread::current().id())
+            });
+
+            while !waiter.is_finished() {
+                queue.drain(Duration::from_millis(1));
+                thread::yield_now();
+            }
+
+            assert_eq!(waiter.join().unwrap(), Ok(game_thread));
+        });
+    }
+
+    #[test]
+    fn wait_on_draining_thread_runs_inline() {
+        static QUEUE: DispatchQueue = DispatchQueue::new();
+
+        let future =
+            QUEUE.dispatch_with_result(|| QUEUE.dispatch_and_wait(Duration::from_secs(10), || 42));
+
+        assert_eq!(QUEUE.drain(Duration::from_secs(1)), 1);
+        assert_eq!(future.wait(), Ok(Ok(42)));
+    }
+
+    #[test]
+    fn timed_out_task_never_runs() {
+        let queue = DispatchQueue::new();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let result = {
+            let ran = ran.clone();
+            queue.dispatch_and_wait(Duration::from_millis(10), move || {
+                ran.store(true, Ordering::Release)
+            })
+        };
+
+        assert_eq!(result, Err(Abandoned));
+
+        // still in the queue, but skipped
+        assert_eq!(queue.drain(Duration::from_secs(1)), 1);
+        assert!(!ran.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn cleared_on_shutdown() {
+        let queue = DispatchQueue::new();
+        let future = queue.dispatch_with_result(|| 1);
+
+        assert!(!future.is_ready());
+
+        queue.clear();
+
+        assert!(queue.is_empty());
+        assert!(future.is_ready());
+        assert_eq!(future.wait(), Err(Abandoned));
+    }
+
+    #[test]
+    fn panicking_task_is_abandoned() {
+        let queue = DispatchQueue::new();
+        let future = queue.dispatch_with_result(|| -> u32 { panic!("task panicked") });
+        let after = queue.dispatch_with_result(|| 2);
+
+        assert_eq!(queue.drain(Duration::from_secs(1)), 2);
+        assert_eq!(future.wait(), Err(Abandoned));
+        assert_eq!(after.wait(), Ok(2));
+    }
+
+    #[test]
+    fn wait_timeout_hands_the_future_back() {
+        let queue = DispatchQueue::new();
+
+        let Err(future) = queue
+            .dispatch_with_result(|| 3)
+            .wait_timeout(Duration::from_millis(1))
+        else {
+            panic!("nothing drained the queue");
+        };
+
+        queue.drain(Duration::from_secs(1));
+
+        assert!(matches!(future.wait_timeout(Duration::ZERO), Ok(Ok(3))));
+    }
+}
@@ -0,0 +1,520 @@
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+    sync::RwLock,
+};
+#[cfg(windows)]
+use std::{
+    path::Path,
+    sync::{
+        Once,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+#[cfg(windows)]
+use log::error;
+#[cfg(windows)]
+use windows::{
+    Win32::{
+        Foundation::{HINSTANCE, HMODULE, MAX_PATH},
+        System::{
+            Diagnostics::Debug::{
+                AddVectoredExceptionHandler, EXCEPTION_CONTINUE_SEARCH, EXCEPTION_POINTERS,
+                IMAGE_NT_HEADERS64, RemoveVectoredExceptionHandler,
+            },
+            LibraryLoader::{
+                GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT, GetModuleFileNameW,
+                GetModuleHandleExW,
+            },
+            SystemServices::IMAGE_DOS_HEADER,
+        },
+    },
+    core::PCWSTR,
+};
+
+#[cfg(windows)]
+use crate::{
+    minidump,
+    shutdown::{self, Detach},
+    utils::ThreadedWrapper,
+};
+
+/// Address ranges we care about. Exceptions that fault inside of any of these get reported.
+/// Our own dll is always added when the handler is set. Hooks and patches add the bytes they
+/// changed while they're enabled
+static REGIONS: RwLock<Vec<Range<usize>>> = RwLock::new(Vec::new());
+
+/// Set a vectored exception handler which logs SEH exceptions (access violations, etc)
+/// that happen inside our dll or a region we patched. The exception is always passed on
+/// afterwards, so the game (or whoever else is handling it) still gets to see it.
+///
+/// This catches things the panic hook never will, e.g. a bad pointer deref inside of a detour.
+///
+/// Is safe to call multiple times since subsequent calls are noops
+#[cfg(windows)]
+pub fn set_handler(module: HINSTANCE) {
+    static HANDLER: Once = Once::new();
+
+    HANDLER.call_once(|| {
+        watch_region(module_range(module));
+
+        // first = 1 so we get to see it before anyone else can swallow it
+        let handle = unsafe { AddVectoredExceptionHandler(1, Some(handler)) };
+        if handle.is_null() {
+            error!("failed to add vectored exception handler");
+            return;
+        }
+
+        // the handler lives in our dll, so it can't outlive it
+        let handle = unsafe { ThreadedWrapper::new(handle) };
+        shutdown::register("exception handler", move |reason| {
+            if reason == Detach::FreeLibrary {
+                unsafe {
+                    RemoveVectoredExceptionHandler(*handle.inner());
+                }
+            }
+        });
+    });
+}
+
+/// Report any fatal exception that faults inside of `range`
+pub fn watch_region(range: Range<usize>) {
+    if let Ok(mut regions) = REGIONS.write() {
+        regions.push(range);
+    }
+}
+
+/// Stop reporting exceptions that fault inside of `range`
+pub fn unwatch_region(range: &Range<usize>) {
+    if let Ok(mut regions) = REGIONS.write() {
+        regions.retain(|r| r != range);
+    }
+}
+
+fn is_watched(address: usize) -> bool {
+    // we are inside an exception handler. if the faulting thread is holding the write lock,
+    // blocking here will deadlock. missing one report beats claiming every crash in the game
+    match REGIONS.try_read() {
+        Ok(regions) => regions.iter().any(|r| r.contains(&address)),
+        Err(_) => false,
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn handler(pointers: *mut EXCEPTION_POINTERS) -> i32 {
+    // if we fault while reporting, don't try to report that one too
+    static REPORTING: AtomicBool = AtomicBool::new(false);
+
+    let Some(info) = (unsafe { pointers.as_ref() }) else {
+        return EXCEPTION_CONTINUE_SEARCH;
+    };
+
+    let record = unsafe { info.ExceptionRecord.as_ref() };
+    let context = unsafe { info.ContextRecord.as_ref() };
+    let (Some(record), Some(context)) = (record, context) else {
+        return EXCEPTION_CONTINUE_SEARCH;
+    };
+
+    let code = record.ExceptionCode.0 as u32;
+    let address = record.ExceptionAddress as usize;
+
+    // games raise (and handle) plenty of exceptions on their own. only errors are fatal,
+    // anything informational like debug prints or thread naming isn't interesting
+    if !is_fatal(code) || !is_watched(address) {
+        return EXCEPTION_CONTINUE_SEARCH;
+    }
+
+    if REPORTING.swap(true, Ordering::Acquire) {
+        return EXCEPTION_CONTINUE_SEARCH;
+    }
+
+    let params = &record.ExceptionInformation[..(record.NumberParameters as usize).min(15)];
+
+    let report = ExceptionReport {
+        code,
+        address,
+        location: module_offset(address),
+        access: Access::from_params(code, params),
+        registers: Registers {
+            rax: context.Rax,
+            rbx: context.Rbx,
+            rcx: context.Rcx,
+            rdx: context.Rdx,
+            rsi: context.Rsi,
+            rdi: context.Rdi,
+            rbp: context.Rbp,
+            rsp: context.Rsp,
+            r8: context.R8,
+            r9: context.R9,
+            r10: context.R10,
+            r11: context.R11,
+            r12: context.R12,
+            r13: context.R13,
+            r14: context.R14,
+            r15: context.R15,
+            rip: context.Rip,
+            eflags: context.EFlags,
+        },
+    };
+
+    error!("{report}");
+
+    // noop unless minidumps were enabled
+    minidump::write_dump(Some(pointers));
+
+    REPORTING.store(false, Ordering::Release);
+
+    EXCEPTION_CONTINUE_SEARCH
+}
+
+/// Severity bits of an NTSTATUS. 0b11 is STATUS_SEVERITY_ERROR
+fn is_fatal(code: u32) -> bool {
+    // msvc c++ exceptions are errors too, but they're normally caught by the game itself
+    const MSVC_CPP_EXCEPTION: u32 = 0xE06D7363;
+
+    code >> 30 == 0b11 && code != MSVC_CPP_EXCEPTION
+}
+
+/// Get the memory range a loaded module occupies
+#[cfg(windows)]
+fn module_range(module: HINSTANCE) -> Range<usize> {
+    let base = module.0 as usize;
+
+    let dos = unsafe { &*(base as *const IMAGE_DOS_HEADER) };
+    let nt = unsafe { &*((base + dos.e_lfanew as usize) as *const IMAGE_NT_HEADERS64) };
+
+    base..base + nt.OptionalHeader.SizeOfImage as usize
+}
+
+/// Resolve an address to `module+offset`
+#[cfg(windows)]
+fn module_offset(address: usize) -> Option<ModuleOffset> {
+    let mut module = HMODULE::default();
+
+    unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            PCWSTR(address as *const u16),
+            &mut module,
+        )
+        .ok()?;
+    }
+
+    let mut path = [0u16; MAX_PATH as usize];
+    let len = unsafe { GetModuleFileNameW(Some(module), &mut path) as usize };
+
+    let path = String::from_utf16_lossy(&path[..len]);
+    let name = Path::new(&path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or(path);
+
+    Some(ModuleOffset {
+        name,
+        offset: address - module.0 as usize,
+    })
+}
+
+/// Everything we know about an exception, ready to be written to the log
+///
+/// This is kept free of any win32 types so it can be built by hand
+#[derive(Debug, Clone)]
+pub struct ExceptionReport {
+    pub code: u32,
+    pub address: usize,
+    pub location: Option<ModuleOffset>,
+    pub access: Option<Access>,
+    pub registers: Registers,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleOffset {
+    pub name: String,
+    pub offset: usize,
+}
+
+/// What an access violation / in page error tried to do, and where
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Read(usize),
+    Write(usize),
+    Execute(usize),
+}
+
+impl Access {
+    /// Decode the `ExceptionInformation` parameters of an exception record
+    pub fn from_params(code: u32, params: &[usize]) -> Option<Self> {
+        const EXCEPTION_ACCESS_VIOLATION: u32 = 0xC0000005;
+        const EXCEPTION_IN_PAGE_ERROR: u32 = 0xC0000006;
+
+        if !matches!(code, EXCEPTION_ACCESS_VIOLATION | EXCEPTION_IN_PAGE_ERROR) {
+            return None;
+        }
+
+        let &[kind, address, ..] = params else {
+            return None;
+        };
+
+        let access = match kind {
+            0 => Self::Read(address),
+            1 => Self::Write(address),
+            8 => Self::Execute(address),
+            _ => return None,
+        };
+
+        Some(access)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub eflags: u32,
+}
+
+/// Readable name for the most common exception codes
+pub fn exception_name(code: u32) -> Option<&'static str> {
+    let name = match code {
+        0x80000002 => "EXCEPTION_DATATYPE_MISALIGNMENT",
+        0xC0000005 => "EXCEPTION_ACCESS_VIOLATION",
+        0xC0000006 => "EXCEPTION_IN_PAGE_ERROR",
+        0xC0000008 => "EXCEPTION_INVALID_HANDLE",
+        0xC000001D => "EXCEPTION_ILLEGAL_INSTRUCTION",
+        0xC0000025 => "EXCEPTION_NONCONTINUABLE_EXCEPTION",
+        0xC000008C => "EXCEPTION_ARRAY_BOUNDS_EXCEEDED",
+        0xC000008D => "EXCEPTION_FLT_DENORMAL_OPERAND",
+        0xC000008E => "EXCEPTION_FLT_DIVIDE_BY_ZERO",
+        0xC0000090 => "EXCEPTION_FLT_INVALID_OPERATION",
+        0xC0000094 => "EXCEPTION_INT_DIVIDE_BY_ZERO",
+        0xC0000095 => "EXCEPTION_INT_OVERFLOW",
+        0xC0000096 => "EXCEPTION_PRIV_INSTRUCTION",
+        0xC00000FD => "EXCEPTION_STACK_OVERFLOW",
+        0xC0000409 => "STATUS_STACK_BUFFER_OVERRUN",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+impl Display for ExceptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // vectored handlers see it before anyone had the chance to handle it. the game still might
+        write!(f, "exception {:#010X}", self.code)?;
+
+        if let Some(name) = exception_name(self.code) {
+            write!(f, " ({name})")?;
+        }
+
+        write!(f, " in a watched region at ")?;
+        match &self.location {
+            Some(ModuleOffset { name, offset }) => {
+                write!(f, "{name}+{offset:#x} ({:#018x})", self.address)?
+            }
+            None => write!(f, "{:#018x}", self.address)?,
+        }
+
+        match self.access {
+            Some(Access::Read(addr)) => write!(f, "\nattempted to read from {addr:#018x}")?,
+            Some(Access::Write(addr)) => write!(f, "\nattempted to write to {addr:#018x}")?,
+            Some(Access::Execute(addr)) => write!(f, "\nattempted to execute {addr:#018x}")?,
+            None => (),
+        }
+
+        write!(f, "\n\nregisters:\n{}", self.registers)
+    }
+}
+
+impl Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let regs = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("rsp", self.rsp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ];
+
+        // 4 registers per line
+        for line in regs.chunks(4) {
+            let line = line
+                .iter()
+                .map(|(name, value)| format!("{name:>3}={value:016x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            writeln!(f, "{line}")?;
+        }
+
+        write!(f, "rip={:016x} eflags={:08x}", self.rip, self.eflags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers() -> Registers {
+        Registers {
+            rax: 0x1,
+            rbx: 0x2,
+            rcx: 0xdead_beef,
+            rdx: 0x4,
+            rsi: 0x5,
+            rdi: 0x6,
+            rbp: 0x7,
+            rsp: 0x8,
+            r8: 0x9,
+            r9: 0xa,
+            r10: 0xb,
+            r11: 0xc,
+            r12: 0xd,
+            r13: 0xe,
+            r14: 0xf,
+            r15: 0xffff_ffff_ffff_ffff,
+            rip: 0x7ff6_1234_5678,
+            eflags: 0x246,
+        }
+    }
+
+    #[test]
+    fn access_from_params() {
+        const AV: u32 = 0xC0000005;
+        const IN_PAGE: u32 = 0xC0000006;
+
+        assert_eq!(
+            Access::from_params(AV, &[0, 0x10]),
+            Some(Access::Read(0x10))
+        );
+        assert_eq!(
+            Access::from_params(AV, &[1, 0x20]),
+            Some(Access::Write(0x20))
+        );
+        assert_eq!(
+            Access::from_params(AV, &[8, 0x30]),
+            Some(Access::Execute(0x30))
+        );
+
+        // in page errors have an NTSTATUS as the third parameter
+        assert_eq!(
+            Access::from_params(IN_PAGE, &[0, 0x40, 0xC000009C]),
+            Some(Access::Read(0x40))
+        );
+
+        assert_eq!(Access::from_params(AV, &[2, 0x10]), None);
+        assert_eq!(Access::from_params(AV, &[0]), None);
+        assert_eq!(Access::from_params(AV, &[]), None);
+        assert_eq!(Access::from_params(0xC0000094, &[0, 0x10]), None);
+    }
+
+    #[test]
+    fn fatal_codes() {
+        assert!(is_fatal(0xC0000005));
+        assert!(is_fatal(0xC0000409));
+
+        // warnings and informational
+        assert!(!is_fatal(0x80000003));
+        assert!(!is_fatal(0x406D1388));
+
+        // c++ throw
+        assert!(!is_fatal(0xE06D7363));
+    }
+
+    #[test]
+    fn registers_display() {
+        let expected = "\
+rax=0000000000000001 rbx=0000000000000002 rcx=00000000deadbeef rdx=0000000000000004
+rsi=0000000000000005 rdi=0000000000000006 rbp=0000000000000007 rsp=0000000000000008
+ r8=0000000000000009  r9=000000000000000a r10=000000000000000b r11=000000000000000c
+r12=000000000000000d r13=000000000000000e r14=000000000000000f r15=ffffffffffffffff
+rip=00007ff612345678 eflags=00000246";
+
+        assert_eq!(registers().to_string(), expected);
+    }
+
+    #[test]
+    fn report_display() {
+        let report = ExceptionReport {
+            code: 0xC0000005,
+            address: 0x7ff6_1234_5678,
+            location: Some(ModuleOffset {
+                name: "bg3.exe".to_owned(),
+                offset: 0x5678,
+            }),
+            access: Some(Access::Write(0x18)),
+            registers: registers(),
+        };
+
+        let expected = format!(
+            "\
+exception 0xC0000005 (EXCEPTION_ACCESS_VIOLATION) in a watched region at bg3.exe+0x5678 (0x00007ff612345678)
+attempted to write to 0x0000000000000018
+
+registers:
+{}",
+            registers()
+        );
+
+        assert_eq!(report.to_string(), expected);
+    }
+
+    #[test]
+    fn report_display_unknown() {
+        let report = ExceptionReport {
+            code: 0xE0001234,
+            address: 0x1000,
+            location: None,
+            access: None,
+            registers: Registers::default(),
+        };
+
+        let report = report.to_string();
+        let first = report.lines().next().unwrap();
+
+        assert_eq!(
+            first,
+            "exception 0xE0001234 in a watched region at 0x0000000000001000"
+        );
+        assert!(!report.contains("attempted"));
+    }
+
+    #[test]
+    fn watched_regions() {
+        watch_region(0x5000..0x5010);
+
+        assert!(is_watched(0x5000));
+        assert!(is_watched(0x500F));
+        assert!(!is_watched(0x5010));
+
+        unwatch_region(&(0x5000..0x5010));
+        assert!(!is_watched(0x5000));
+    }
+}
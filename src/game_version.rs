@@ -0,0 +1,535 @@
+use std::{
+    env,
+    fmt::{self, Display},
+    fs::File,
+    io::{BufReader, Read as _},
+    path::{Path, PathBuf},
+    sync::{
+        OnceLock,
+        atomic::{AtomicU8, Ordering},
+    },
+};
+
+use eyre::{OptionExt as _, Result, bail, eyre};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{CurrentProcess, MemoryBackend, ModuleInfo},
+    pe::{Layout, PeImage},
+};
+
+/// What we know about the game exe we're loaded into
+pub struct GameInfo {
+    name: String,
+    path: PathBuf,
+    version: Option<String>,
+    timestamp: Option<u32>,
+    hash: OnceLock<Result<u64, String>>,
+}
+
+static GAME: OnceLock<GameInfo> = OnceLock::new();
+
+impl GameInfo {
+    /// File name of the exe, e.g. `bg3.exe`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// File version from the exe's version resource, e.g. `4.1.1.5`. Not every game has one
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Link time from the exe's PE header. Changes with every build
+    pub fn timestamp(&self) -> Option<u32> {
+        self.timestamp
+    }
+
+    /// Hash of the whole exe file. Changes whenever the exe does
+    ///
+    /// This reads the whole file the first time, so it's only done when something asks for it
+    pub fn hash(&self) -> Result<u64> {
+        self.hash
+            .get_or_init(|| {
+                hash_file(&self.path)
+                    .map_err(|e| format!("failed to hash {}: {e}", self.path.display()))
+            })
+            .clone()
+            .map_err(|e| eyre!(e))
+    }
+}
+
+impl Display for GameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if let Some(version) = &self.version {
+            write!(f, " {version}")?;
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            write!(f, " (built {timestamp:#010x})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The game we're loaded into. Detected the first time this is called
+pub fn current() -> Result<&'static GameInfo> {
+    if let Some(game) = GAME.get() {
+        return Ok(game);
+    }
+
+    let path = env::current_exe()?;
+    let name = path
+        .file_name()
+        .ok_or_eyre("game exe has no file name")?
+        .to_string_lossy()
+        .into_owned();
+
+    let game = GameInfo {
+        version: file_version(&CurrentProcess, &name),
+        timestamp: link_timestamp(&CurrentProcess, &name),
+        name,
+        path,
+        hash: OnceLock::new(),
+    };
+
+    Ok(GAME.get_or_init(|| game))
+}
+
+/// The PE headers of a loaded module. They're all in the first page
+fn headers(memory: &impl MemoryBackend, module: &ModuleInfo) -> Option<Vec<u8>> {
+    let mut headers = vec![0; module.size.min(0x1000)];
+    memory.read_bytes(module.base, &mut headers).ok()?;
+
+    Some(headers)
+}
+
+/// File version from the loaded exe's version resource
+fn file_version(memory: &impl MemoryBackend, name: &str) -> Option<String> {
+    let module = memory.find_module(name)?;
+    let headers = headers(memory, &module)?;
+
+    let rsrc = PeImage::parse(&headers, Layout::Mapped)
+        .ok()?
+        .section(".rsrc")?
+        .clone();
+
+    // just the headers and .rsrc, where they'd be in the mapped image. the rest of the exe can be huge
+    let start = rsrc.virtual_address as usize;
+    let mut image = vec![0; start + rsrc.virtual_size as usize];
+
+    let header_len = headers.len().min(start);
+    image[..header_len].copy_from_slice(&headers[..header_len]);
+    memory
+        .read_bytes(module.base + start, &mut image[start..])
+        .ok()?;
+
+    PeImage::parse(&image, Layout::Mapped)
+        .ok()?
+        .version_info()
+        .ok()??
+        .file_version_string()
+}
+
+/// `TimeDateStamp` from the loaded exe's PE header
+fn link_timestamp(memory: &impl MemoryBackend, name: &str) -> Option<u32> {
+    let module = memory.find_module(name)?;
+    let headers = headers(memory, &module)?;
+
+    let image = PeImage::parse(&headers, Layout::Mapped).ok()?;
+    Some(image.timestamp())
+}
+
+/// 64 bit FNV-1a of a file's contents
+fn hash_file(path: &Path) -> Result<u64> {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = vec![0; 64 * 1024];
+    let mut hash = OFFSET;
+
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+
+        for byte in &buf[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+
+    Ok(hash)
+}
+
+/// A game build the plugin is known to work with
+///
+/// Every field that's set has to match. Use whichever your game has: the version resource is the
+/// nicest, but some games never bump it, the timestamp changes with every build, and the hash always works
+///
+/// ```ignore
+/// fn supported_versions(&self) -> &[SupportedVersion] {
+///     const SUPPORTED: &[SupportedVersion] = &[
+///         SupportedVersion::new("patch 7").version("4.1.1.5"),
+///         SupportedVersion::new("patch 7 hotfix 1").timestamp(0x66f1a2b3),
+///     ];
+///
+///     SUPPORTED
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct SupportedVersion {
+    pub label: &'static str,
+    pub version: Option<&'static str>,
+    pub timestamp: Option<u32>,
+    pub hash: Option<u64>,
+}
+
+impl SupportedVersion {
+    pub const fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            version: None,
+            timestamp: None,
+            hash: None,
+        }
+    }
+
+    pub const fn version(mut self, version: &'static str) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub const fn timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub const fn hash(mut self, hash: u64) -> Self {
+        self.hash = Some(hash);
+        self
+    }
+
+    fn matches(&self, game: &GameInfo) -> Result<bool> {
+        // an entry without anything to compare would match every build there is
+        if self.version.is_none() && self.timestamp.is_none() && self.hash.is_none() {
+            return Ok(false);
+        }
+
+        if let Some(version) = self.version
+            && game.version() != Some(version)
+        {
+            return Ok(false);
+        }
+
+        if let Some(timestamp) = self.timestamp
+            && game.timestamp() != Some(timestamp)
+        {
+            return Ok(false);
+        }
+
+        if let Some(hash) = self.hash
+            && game.hash()? != hash
+        {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+/// What to do when the game isn't one of the plugin's supported versions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownVersion {
+    /// Load anyway with a warning in the log
+    #[default]
+    Unverified,
+    /// Load, but don't install any hooks or patches
+    Refuse,
+}
+
+/// The outcome of [`check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Compatibility {
+    /// A supported version, or the plugin doesn't say which versions it supports
+    Supported,
+    /// Not a supported version, but we're running anyways
+    Unverified,
+    /// Not a supported version, so hooks and patches won't be installed
+    Refused,
+}
+
+static COMPATIBILITY: AtomicU8 = AtomicU8::new(Compatibility::Supported as u8);
+
+impl Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Supported => "supported",
+            Self::Unverified => "unverified",
+            Self::Refused => "refused",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Check the game against the plugin's supported versions, log what we decided and why, and remember it
+pub fn check(supported: &[SupportedVersion], unknown: UnknownVersion) -> Compatibility {
+    let compatibility = decide(current(), supported, unknown);
+    COMPATIBILITY.store(compatibility as u8, Ordering::Release);
+
+    compatibility
+}
+
+fn decide(
+    game: Result<&GameInfo>,
+    supported: &[SupportedVersion],
+    unknown: UnknownVersion,
+) -> Compatibility {
+    if supported.is_empty() {
+        info!("plugin doesn't list any supported game versions, not checking");
+        return Compatibility::Supported;
+    }
+
+    let game = match game {
+        Ok(game) => game,
+        Err(e) => {
+            return unsupported(unknown, &format!("failed to detect the game version: {e}"));
+        }
+    };
+
+    for version in supported {
+        match version.matches(game) {
+            Ok(true) => {
+                info!("{game} is supported ({})", version.label);
+                return Compatibility::Supported;
+            }
+
+            Ok(false) => (),
+
+            Err(e) => warn!("couldn't compare {game} to {}: {e}", version.label),
+        }
+    }
+
+    let labels = supported
+        .iter()
+        .map(|v| v.label)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    unsupported(
+        unknown,
+        &format!("{game} isn't one of the supported versions ({labels})"),
+    )
+}
+
+fn unsupported(unknown: UnknownVersion, reason: &str) -> Compatibility {
+    match unknown {
+        UnknownVersion::Unverified => {
+            warn!("{reason}. running unverified, things might break");
+            Compatibility::Unverified
+        }
+
+        UnknownVersion::Refuse => {
+            error!("{reason}. refusing to install hooks and patches");
+            Compatibility::Refused
+        }
+    }
+}
+
+/// What [`check`] decided. `Supported` until it's called
+pub fn compatibility() -> Compatibility {
+    match COMPATIBILITY.load(Ordering::Acquire) {
+        1 => Compatibility::Unverified,
+        2 => Compatibility::Refused,
+        _ => Compatibility::Supported,
+    }
+}
+
+/// Fails if [`check`] refused this game version. Hooks and patches call this before touching the game
+pub fn ensure_allowed(what: &str) -> Result<()> {
+    allowed(compatibility(), what)
+}
+
+fn allowed(compatibility: Compatibility, what: &str) -> Result<()> {
+    if compatibility == Compatibility::Refused {
+        bail!("not installing {what}: this game version isn't supported");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::{memory::FakeMemory, pe::fixture::PeBuilder};
+
+    #[test]
+    fn from_the_loaded_exe() {
+        let mut pe = PeBuilder::new();
+        pe.section(".data", 0, vec![0; 0x10]);
+        let rsrc = pe.version([4, 1, 1, 5], &[("ProductName", "Test Game")]) as usize;
+
+        let image = pe.build(Layout::Mapped);
+        let base = 0x1_4000_0000;
+
+        // only the headers and .rsrc are readable, nothing else gets touched
+        let memory = FakeMemory::new();
+        memory.map(base, image[..0x1000].to_vec(), Prot::R);
+        memory.map(base + 0x1000, image[0x1000..rsrc].to_vec(), Prot::X);
+        memory.map(base + rsrc, image[rsrc..].to_vec(), Prot::R);
+        memory.add_module("game.exe", base, image.len());
+
+        assert_eq!(
+            file_version(&memory, "GAME.exe").as_deref(),
+            Some("4.1.1.5")
+        );
+        assert_eq!(link_timestamp(&memory, "game.exe"), Some(0x66F1_A2B3));
+
+        assert_eq!(file_version(&memory, "other.exe"), None);
+    }
+
+    #[test]
+    fn no_version_resource() {
+        let mut pe = PeBuilder::new();
+        pe.section(".text", 0, vec![0xC3]);
+
+        let image = pe.build(Layout::Mapped);
+        let memory = FakeMemory::new();
+        memory.add_module("game.exe", 0x1000, image.len());
+        memory.map(0x1000, image, Prot::R);
+
+        assert_eq!(file_version(&memory, "game.exe"), None);
+        assert!(link_timestamp(&memory, "game.exe").is_some());
+    }
+
+    /// `bg3.exe 4.1.1.5 (built 0x66f1a2b3)`, hashing to 0xABCD
+    fn game() -> GameInfo {
+        GameInfo {
+            name: "bg3.exe".to_owned(),
+            path: PathBuf::from("bg3.exe"),
+            version: Some("4.1.1.5".to_owned()),
+            timestamp: Some(0x66F1_A2B3),
+            hash: OnceLock::from(Ok(0xABCD)),
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let unhashable = GameInfo {
+            hash: OnceLock::from(Err("no file".to_owned())),
+            ..game()
+        };
+
+        let unversioned = GameInfo {
+            version: None,
+            ..game()
+        };
+
+        let v = SupportedVersion::new;
+        let cases = [
+            // nothing to compare matches nothing
+            (v("empty"), &game(), Some(false)),
+            (v("version").version("4.1.1.5"), &game(), Some(true)),
+            (v("version").version("4.1.1.6"), &game(), Some(false)),
+            (v("version").version("4.1.1.5"), &unversioned, Some(false)),
+            (v("timestamp").timestamp(0x66F1_A2B3), &game(), Some(true)),
+            (v("timestamp").timestamp(0x66F1_A2B4), &game(), Some(false)),
+            (v("hash").hash(0xABCD), &game(), Some(true)),
+            (v("hash").hash(0xABCE), &game(), Some(false)),
+            (v("hash").hash(0xABCD), &unhashable, None),
+            // every field that's set has to match
+            (
+                v("all")
+                    .version("4.1.1.5")
+                    .timestamp(0x66F1_A2B3)
+                    .hash(0xABCD),
+                &game(),
+                Some(true),
+            ),
+            (
+                v("all").version("4.1.1.5").timestamp(0x66F1_A2B4),
+                &game(),
+                Some(false),
+            ),
+            // the hash isn't even needed when something else already doesn't match
+            (
+                v("all").version("4.1.1.6").hash(0xABCD),
+                &unhashable,
+                Some(false),
+            ),
+        ];
+
+        for (supported, game, expected) in cases {
+            assert_eq!(
+                supported.matches(game).ok(),
+                expected,
+                "{supported:?} against {game}"
+            );
+        }
+    }
+
+    #[test]
+    fn decide_by_policy() {
+        use Compatibility::*;
+        use UnknownVersion::{Refuse, Unverified as Warn};
+
+        let game = game();
+        let unhashable = GameInfo {
+            hash: OnceLock::from(Err("no file".to_owned())),
+            ..self::game()
+        };
+
+        let patch7 = [SupportedVersion::new("patch 7").version("4.1.1.5")];
+        let patch8 = [SupportedVersion::new("patch 8").version("4.1.1.6")];
+        let hashed = [SupportedVersion::new("patch 7").hash(0xABCD)];
+
+        let cases = [
+            // supported no matter the policy
+            (&patch7[..], Ok(&game), Warn, Supported),
+            (&patch7[..], Ok(&game), Refuse, Supported),
+            // nothing listed, nothing to check
+            (&[][..], Ok(&game), Refuse, Supported),
+            (&[][..], Err(eyre!("no exe")), Refuse, Supported),
+            // not one of them
+            (&patch8[..], Ok(&game), Warn, Unverified),
+            (&patch8[..], Ok(&game), Refuse, Refused),
+            // couldn't tell
+            (&patch7[..], Err(eyre!("no exe")), Warn, Unverified),
+            (&patch7[..], Err(eyre!("no exe")), Refuse, Refused),
+            (&hashed[..], Ok(&unhashable), Warn, Unverified),
+            (&hashed[..], Ok(&unhashable), Refuse, Refused),
+        ];
+
+        for (i, (supported, game, unknown, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(decide(game, supported, unknown), expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn refused_installs_nothing() {
+        assert!(allowed(Compatibility::Supported, "hook a").is_ok());
+        assert!(allowed(Compatibility::Unverified, "hook a").is_ok());
+
+        assert_eq!(
+            allowed(Compatibility::Refused, "hook a")
+                .unwrap_err()
+                .to_string(),
+            "not installing hook a: this game version isn't supported"
+        );
+    }
+}
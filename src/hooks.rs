@@ -0,0 +1,854 @@
+use std::{
+    fmt::{self, Display},
+    mem,
+    ops::Range,
+    sync::{
+        Arc, Mutex, Once,
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use eyre::{OptionExt as _, Result, bail};
+use libmem::{Address, Trampoline, hook_code, unhook_code};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    exception_handler, game_version,
+    memory::{CurrentProcess, MemoryBackend},
+    shutdown::{self, Detach},
+};
+
+/// Every installed hook, in install order
+static HOOKS: Mutex<Vec<Arc<Hook>>> = Mutex::new(Vec::new());
+
+static CONFLICT_POLICY: AtomicU8 = AtomicU8::new(ConflictPolicy::Chain as u8);
+
+/// What to do when a function we're about to hook was already hooked by someone else, e.g. another mod
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum ConflictPolicy {
+    /// Hook on top of theirs. Their jump ends up in our trampoline (pointed at the same place
+    /// again if it was relative), so calling the original runs their hook, and then the function
+    #[default]
+    Chain,
+    /// Leave their hook alone and don't enable ours. The hook is still installed, just disabled
+    Skip,
+    /// Don't hook, and fail the install
+    Fail,
+}
+
+/// Set what [`install`] and enabling a hook do about functions someone else already hooked
+pub fn set_conflict_policy(policy: ConflictPolicy) {
+    CONFLICT_POLICY.store(policy as u8, Ordering::Release);
+}
+
+pub fn conflict_policy() -> ConflictPolicy {
+    match CONFLICT_POLICY.load(Ordering::Acquire) {
+        1 => ConflictPolicy::Skip,
+        2 => ConflictPolicy::Fail,
+        _ => ConflictPolicy::Chain,
+    }
+}
+
+/// A jump at the start of a function, the usual sign someone else hooked it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExistingJump {
+    pub kind: JumpKind,
+    /// Where it goes. `None` if it jumps through a pointer we couldn't read
+    pub destination: Option<Address>,
+    /// The pointer a [`JumpKind::Indirect`] jumps through
+    pub via: Option<Address>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpKind {
+    /// `E9 rel32`
+    Rel32,
+    /// `EB rel8`
+    Rel8,
+    /// `FF 25 disp32`, i.e. `jmp [rip+disp32]`
+    Indirect,
+    /// `48 B8 imm64 FF E0`, i.e. `mov rax, imm64; jmp rax`
+    MovRax,
+    /// `49 BB imm64 41 FF E3`, i.e. `mov r11, imm64; jmp r11`
+    MovR11,
+}
+
+impl Display for JumpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Rel32 => "jmp rel32",
+            Self::Rel8 => "jmp rel8",
+            Self::Indirect => "jmp [rip+disp32]",
+            Self::MovRax => "mov rax; jmp rax",
+            Self::MovR11 => "mov r11; jmp r11",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Look for a jump at the start of the function at `address`
+pub fn existing_jump(
+    memory: &impl MemoryBackend,
+    address: Address,
+) -> Result<Option<ExistingJump>> {
+    // long enough for the longest one we know
+    let mut code = [0u8; 13];
+    memory.read_bytes(address, &mut code[..2])?;
+
+    // the rest may run off the end of the section, which just rules out the ones that don't fit
+    let len = [13, 12, 6, 5]
+        .into_iter()
+        .find(|len| memory.read_bytes(address, &mut code[..*len]).is_ok())
+        .unwrap_or(2);
+
+    let i32_at =
+        |at: usize| i32::from_le_bytes([code[at], code[at + 1], code[at + 2], code[at + 3]]);
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&code[at..at + 8]);
+        u64::from_le_bytes(bytes) as Address
+    };
+
+    let jump = match code {
+        [0xEB, rel, ..] => ExistingJump {
+            kind: JumpKind::Rel8,
+            destination: Some((address + 2).wrapping_add_signed(rel as i8 as isize)),
+            via: None,
+        },
+
+        [0xE9, ..] if len >= 5 => ExistingJump {
+            kind: JumpKind::Rel32,
+            destination: Some((address + 5).wrapping_add_signed(i32_at(1) as isize)),
+            via: None,
+        },
+
+        [0xFF, 0x25, ..] if len >= 6 => {
+            let pointer = (address + 6).wrapping_add_signed(i32_at(2) as isize);
+
+            ExistingJump {
+                kind: JumpKind::Indirect,
+                destination: memory.read::<Address>(pointer).ok(),
+                via: Some(pointer),
+            }
+        }
+
+        [0x48, 0xB8, .., 0xFF, 0xE0, _] if len >= 12 => ExistingJump {
+            kind: JumpKind::MovRax,
+            destination: Some(u64_at(2)),
+            via: None,
+        },
+
+        [0x49, 0xBB, .., 0x41, 0xFF, 0xE3] if len >= 13 => ExistingJump {
+            kind: JumpKind::MovR11,
+            destination: Some(u64_at(2)),
+            via: None,
+        },
+
+        _ => return Ok(None),
+    };
+
+    Ok(Some(jump))
+}
+
+/// What to write over the start of the trampoline at `trampoline`, which begins with a copy of
+/// `jump`. `None` if the copy works as is
+///
+/// libmem copies the instructions it overwrites into the trampoline as they are. A relative jump
+/// is relative to where it is, so the copy would go somewhere else entirely. `size` is how many
+/// bytes were copied; anything after the jump never runs, so all of them can be overwritten
+fn relocated_jump(
+    jump: &ExistingJump,
+    trampoline: Address,
+    size: usize,
+) -> Result<Option<Vec<u8>>> {
+    let code = match jump.kind {
+        // absolute already
+        JumpKind::MovRax | JumpKind::MovR11 => return Ok(None),
+
+        JumpKind::Rel8 | JumpKind::Rel32 => {
+            let destination = jump
+                .destination
+                .ok_or_eyre("relative jump without a destination")?;
+
+            if size >= 14 {
+                absolute_jump(destination)
+            } else if let Some(rel) = rel32(trampoline + 5, destination) {
+                [&[0xE9][..], &rel.to_le_bytes()].concat()
+            } else {
+                bail!(
+                    "can't fit a jump from the trampoline at {trampoline:#x} to {destination:#x} in {size} bytes"
+                );
+            }
+        }
+
+        // still through the same pointer, if it can be reached from the trampoline
+        JumpKind::Indirect => {
+            let via = jump.via.ok_or_eyre("indirect jump without a pointer")?;
+
+            match rel32(trampoline + 6, via) {
+                Some(disp) => [&[0xFF, 0x25][..], &disp.to_le_bytes()].concat(),
+
+                None if size >= 14 => {
+                    let destination = jump.destination.ok_or_eyre(format!(
+                        "can't reach {via:#x} from the trampoline at {trampoline:#x}, and can't read where it points"
+                    ))?;
+
+                    absolute_jump(destination)
+                }
+
+                None => bail!(
+                    "can't reach {via:#x} from the trampoline at {trampoline:#x} in {size} bytes"
+                ),
+            }
+        }
+    };
+
+    Ok(Some(code))
+}
+
+/// `to - from` if it fits a rel32
+fn rel32(from: Address, to: Address) -> Option<i32> {
+    i32::try_from((to as isize).wrapping_sub(from as isize)).ok()
+}
+
+/// `jmp [rip+0]` followed by `destination`
+fn absolute_jump(destination: Address) -> Vec<u8> {
+    let mut code = vec![0xFF, 0x25, 0, 0, 0, 0];
+    code.extend_from_slice(&(destination as u64).to_le_bytes());
+
+    code
+}
+
+/// Point the copy of `jump` at the start of the trampoline back where it went, see [`relocated_jump`]
+fn fix_trampoline(
+    memory: &impl MemoryBackend,
+    jump: &ExistingJump,
+    trampoline: Address,
+    size: usize,
+) -> Result<()> {
+    // nothing to do if the jump wasn't copied as is, e.g. if libmem ever learns to relocate it
+    let copied = existing_jump(memory, trampoline)?;
+    if copied
+        .is_some_and(|copied| copied.kind == jump.kind && copied.destination == jump.destination)
+    {
+        return Ok(());
+    }
+
+    if let Some(code) = relocated_jump(jump, trampoline, size)? {
+        memory.write_code(trampoline, &code)?;
+    }
+
+    Ok(())
+}
+
+/// Whether hooking should go ahead, going by the conflict policy
+fn check_conflict(name: &str, from: Address, jump: &ExistingJump) -> Result<bool> {
+    let memory = CurrentProcess;
+
+    let into = match jump.destination {
+        Some(destination) => {
+            let module = memory
+                .modules()
+                .ok()
+                .and_then(|modules| modules.into_iter().find(|m| m.contains(destination)));
+
+            match module {
+                Some(module) => format!("{destination:#x} in {}", module.name),
+                // where hooking libraries put their trampolines
+                None => format!("{destination:#x}, which isn't in any module"),
+            }
+        }
+
+        None => "an unreadable pointer".to_owned(),
+    };
+
+    let conflict = format!(
+        "hook {name}: {from:#x} already starts with `{}` to {into}. another mod probably hooked it",
+        jump.kind
+    );
+
+    match conflict_policy() {
+        ConflictPolicy::Chain => {
+            warn!("{conflict}, hooking on top of it");
+            Ok(true)
+        }
+
+        ConflictPolicy::Skip => {
+            warn!("{conflict}, leaving it alone");
+            Ok(false)
+        }
+
+        ConflictPolicy::Fail => bail!("{conflict}, refusing to hook"),
+    }
+}
+
+enum State {
+    Enabled(Trampoline),
+    Disabled,
+    Removed,
+}
+
+struct Hook {
+    name: String,
+    from: Address,
+    to: Address,
+    /// Whether a jump at `from` means someone else hooked it, see [`install_with_conflict_check`]
+    check_conflicts: bool,
+    state: Mutex<State>,
+    /// What `original` returns, so calling the original from a detour doesn't take the lock.
+    /// 0 while the hook is being enabled or disabled. Leaked, so a typed hook can keep reading
+    /// it after the hook is dropped, see [`original`]
+    original: &'static AtomicUsize,
+}
+
+impl Hook {
+    fn enable(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        match *state {
+            State::Enabled(_) => return Ok(()),
+            State::Removed => bail!("hook {} was removed", self.name),
+            State::Disabled => (),
+        }
+
+        let memory = CurrentProcess;
+
+        let jump = existing_jump(&memory, self.from).unwrap_or_else(|e| {
+            warn!(
+                "hook {}: couldn't check {:#x} for a jump: {e}",
+                self.name, self.from
+            );
+            None
+        });
+
+        if let Some(jump) = &jump
+            && self.check_conflicts
+            && !check_conflict(&self.name, self.from, jump)?
+        {
+            return Ok(());
+        }
+
+        // a thread that gets into the detour before we know the trampoline waits for it
+        self.original.store(0, Ordering::Release);
+
+        let Some(trampoline) = (unsafe { hook_code(self.from, self.to) }) else {
+            self.original.store(self.from, Ordering::Release);
+            bail!("failed to hook {} at {:#x}", self.name, self.from);
+        };
+
+        // the jump is in the trampoline now, calling the original has to still go where it went
+        if let Some(jump) = &jump
+            && let Err(e) = fix_trampoline(&memory, jump, trampoline.address, trampoline.size)
+        {
+            if unsafe { unhook_code(self.from, trampoline) }.is_none() {
+                error!(
+                    "hook {}: failed to unhook {:#x} again. calling the original will crash",
+                    self.name, self.from
+                );
+            }
+
+            self.original.store(self.from, Ordering::Release);
+            bail!(
+                "hook {}: can't move the `{}` at {:#x} into the trampoline: {e}",
+                self.name,
+                jump.kind,
+                self.from
+            );
+        }
+
+        // crashes in the patched prologue or trampoline are ours
+        for range in self.regions(&trampoline) {
+            exception_handler::watch_region(range);
+        }
+
+        self.original.store(trampoline.address, Ordering::Release);
+        *state = State::Enabled(trampoline);
+
+        debug!("enabled hook {} at {:#x}", self.name, self.from);
+
+        Ok(())
+    }
+
+    fn disable(&self, remove: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let next = if remove {
+            State::Removed
+        } else {
+            State::Disabled
+        };
+
+        let State::Enabled(trampoline) = mem::replace(&mut *state, next) else {
+            return Ok(());
+        };
+
+        for range in self.regions(&trampoline) {
+            exception_handler::unwatch_region(&range);
+        }
+
+        // the trampoline is freed along with the hook
+        let address = trampoline.address;
+        self.original.store(0, Ordering::Release);
+
+        let unhooked = unsafe { unhook_code(self.from, trampoline) };
+        if unhooked.is_none() {
+            // still hooked, so the trampoline is still what runs the function
+            self.original.store(address, Ordering::Release);
+            bail!("failed to unhook {} at {:#x}", self.name, self.from);
+        }
+
+        self.original.store(self.from, Ordering::Release);
+
+        debug!("disabled hook {} at {:#x}", self.name, self.from);
+
+        Ok(())
+    }
+
+    fn regions(&self, trampoline: &Trampoline) -> [Range<usize>; 2] {
+        [
+            self.from..self.from + trampoline.size,
+            trampoline.address..trampoline.address + trampoline.size,
+        ]
+    }
+
+    fn trampoline(&self) -> Option<Address> {
+        match &*self.state.lock().unwrap_or_else(|e| e.into_inner()) {
+            State::Enabled(trampoline) => Some(trampoline.address),
+            _ => None,
+        }
+    }
+}
+
+/// An installed hook. Unhooks when dropped!
+///
+/// If you want a hook to stay for the lifetime of the plugin, call [`HookHandle::keep`].
+/// Either way, everything that's still hooked is unhooked on unload in reverse install order
+pub struct HookHandle {
+    hook: Arc<Hook>,
+}
+
+impl HookHandle {
+    pub fn name(&self) -> &str {
+        &self.hook.name
+    }
+
+    /// The hooked function
+    pub fn target(&self) -> Address {
+        self.hook.from
+    }
+
+    /// Address to call to run the original function
+    ///
+    /// This is the trampoline while enabled, and the function itself while disabled.
+    /// Don't cache it; it changes every time the hook is re-enabled
+    pub fn original(&self) -> Address {
+        original(self.hook.original)
+    }
+
+    /// Where [`HookHandle::original`] comes from. Stays valid after the hook is dropped, and then
+    /// holds the function itself
+    pub(crate) fn original_cell(&self) -> &'static AtomicUsize {
+        self.hook.original
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.hook.trampoline().is_some()
+    }
+
+    /// Hook the function again after it was disabled
+    ///
+    /// If someone else hooked it in the meantime, this goes by the conflict policy.
+    /// With [`ConflictPolicy::Skip`] that leaves it disabled
+    pub fn enable(&self) -> Result<()> {
+        self.hook.enable()
+    }
+
+    /// Unhook the function, but keep it registered so it can be enabled again later
+    pub fn disable(&self) -> Result<()> {
+        self.hook.disable(false)
+    }
+
+    /// Keep the hook installed until the plugin unloads, instead of until this is dropped
+    pub fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for HookHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.hook.disable(true) {
+            error!("{e}");
+        }
+
+        if let Ok(mut hooks) = HOOKS.lock() {
+            hooks.retain(|h| !Arc::ptr_eq(h, &self.hook));
+        }
+    }
+}
+
+/// Detour the function at `from` to `to`
+///
+/// `name` is for the log, and for [`enable`]/[`disable`] by name. It has to be unique.
+///
+/// If `from` already starts with a jump, someone else most likely hooked it first. What happens
+/// then is up to the [`ConflictPolicy`] from the config. When it says to skip, the returned hook is disabled.
+///
+/// Fails if the game version check refused this version of the game
+///
+/// ```ignore
+/// static HOOK: OnceLock<HookHandle> = OnceLock::new();
+///
+/// extern "C" fn detour(this: *mut c_void, delta: f32) {
+///     let original: extern "C" fn(*mut c_void, f32) =
+///         unsafe { mem::transmute(HOOK.get().unwrap().original()) };
+///
+///     original(this, delta * 0.5)
+/// }
+///
+/// let hook = unsafe { hooks::install("slow motion", update, detour as Address)? };
+/// _ = HOOK.set(hook);
+/// ```
+///
+/// `typed_hook::hook!` does the transmuting for you
+///
+/// # Safety
+/// `from` must be the start of a function, and `to` a function with the exact same signature
+/// and calling convention. Other threads may be executing `from` while it's being patched;
+/// hook before the game gets to it when you can
+pub unsafe fn install(name: &str, from: Address, to: Address) -> Result<HookHandle> {
+    unsafe { install_with_conflict_check(name, from, to, true) }
+}
+
+/// [`install`], but `check_conflicts: false` doesn't take a jump at `from` to mean another hook.
+/// For hooks in the middle of a function, where a jump is just part of the code
+///
+/// # Safety
+/// See [`install`]
+pub(crate) unsafe fn install_with_conflict_check(
+    name: &str,
+    from: Address,
+    to: Address,
+    check_conflicts: bool,
+) -> Result<HookHandle> {
+    game_version::ensure_allowed(&format!("hook {name}"))?;
+    register_teardown();
+
+    let mut hooks = HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+
+    if hooks.iter().any(|h| h.name == name) {
+        bail!("a hook named {name} is already installed");
+    }
+
+    if hooks.iter().any(|h| h.from == from) {
+        bail!("{from:#x} is already hooked");
+    }
+
+    let hook = Arc::new(Hook {
+        name: name.to_owned(),
+        from,
+        to,
+        check_conflicts,
+        state: Mutex::new(State::Disabled),
+        original: Box::leak(Box::new(AtomicUsize::new(from))),
+    });
+
+    hook.enable()?;
+
+    if hook.trampoline().is_some() {
+        info!("installed hook {name} at {from:#x}");
+    } else {
+        info!("installed hook {name} at {from:#x} (disabled)");
+    }
+
+    hooks.push(hook.clone());
+
+    Ok(HookHandle { hook })
+}
+
+/// Read a hook's original, without taking any locks. See [`HookHandle::original_cell`]
+pub(crate) fn original(cell: &AtomicUsize) -> Address {
+    loop {
+        match cell.load(Ordering::Acquire) {
+            // the hook is being enabled or disabled, which doesn't take long
+            0 => thread::yield_now(),
+            address => return address,
+        }
+    }
+}
+
+fn find(name: &str) -> Result<Arc<Hook>> {
+    let hooks = HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+
+    hooks
+        .iter()
+        .find(|h| h.name == name)
+        .cloned()
+        .ok_or_eyre(format!("no hook named {name}"))
+}
+
+/// Enable a hook by name
+pub fn enable(name: &str) -> Result<()> {
+    find(name)?.enable()
+}
+
+/// Disable a hook by name
+pub fn disable(name: &str) -> Result<()> {
+    find(name)?.disable(false)
+}
+
+/// Whether a hook by this name is installed and enabled
+pub fn is_enabled(name: &str) -> bool {
+    find(name).is_ok_and(|h| h.trampoline().is_some())
+}
+
+/// Names of every installed hook, in install order
+pub fn names() -> Vec<String> {
+    let hooks = HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+    hooks.iter().map(|h| h.name.clone()).collect()
+}
+
+/// Unhook everything, in reverse install order
+pub fn unhook_all() {
+    let hooks = match HOOKS.lock() {
+        Ok(mut hooks) => mem::take(&mut *hooks),
+        Err(_) => return,
+    };
+
+    for hook in hooks.into_iter().rev() {
+        if let Err(e) = hook.disable(true) {
+            error!("{e}");
+        }
+    }
+}
+
+fn register_teardown() {
+    static TEARDOWN: Once = Once::new();
+
+    TEARDOWN.call_once(|| {
+        shutdown::register("hooks", |reason| {
+            // the game is going away with us, nobody will call into the hooks anymore
+            if reason == Detach::ProcessExit {
+                return;
+            }
+
+            // the detours live in our dll. leaving them in place means the next call jumps
+            // into unmapped memory
+            unhook_all();
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    /// Code at 0x1000 starting with one of each kind of jump, 0x20 bytes apart
+    fn code() -> FakeMemory {
+        let mut code = vec![0xCC; 0x100];
+
+        // jmp 0x1025
+        code[..5].copy_from_slice(&[0xE9, 0x20, 0, 0, 0]);
+
+        // jmp $
+        code[0x20..0x22].copy_from_slice(&[0xEB, 0xFE]);
+
+        // jmp [0x1048]
+        code[0x40..0x46].copy_from_slice(&[0xFF, 0x25, 0x02, 0, 0, 0]);
+        code[0x48..0x50].copy_from_slice(&0x7777usize.to_le_bytes());
+
+        // mov rax, 0x123456789abc / jmp rax
+        code[0x60..0x62].copy_from_slice(&[0x48, 0xB8]);
+        code[0x62..0x6A].copy_from_slice(&0x1234_5678_9ABCusize.to_le_bytes());
+        code[0x6A..0x6C].copy_from_slice(&[0xFF, 0xE0]);
+
+        // mov r11, 0xabcd / jmp r11
+        code[0x80..0x82].copy_from_slice(&[0x49, 0xBB]);
+        code[0x82..0x8A].copy_from_slice(&0xABCDusize.to_le_bytes());
+        code[0x8A..0x8D].copy_from_slice(&[0x41, 0xFF, 0xE3]);
+
+        // mov [rsp+8], rbx, a normal prologue
+        code[0xA0..0xA5].copy_from_slice(&[0x48, 0x89, 0x5C, 0x24, 0x08]);
+
+        // jmp [0x100010c6], which isn't mapped
+        code[0xC0..0xC6].copy_from_slice(&[0xFF, 0x25, 0, 0, 0, 0x10]);
+
+        let memory = FakeMemory::new();
+        memory.map(0x1000, code, Prot::XR);
+
+        memory
+    }
+
+    fn jump(kind: JumpKind, destination: Option<Address>, via: Option<Address>) -> ExistingJump {
+        ExistingJump {
+            kind,
+            destination,
+            via,
+        }
+    }
+
+    #[test]
+    fn every_kind() {
+        let memory = code();
+        let at = |address| existing_jump(&memory, address).unwrap();
+
+        assert_eq!(at(0x1000), Some(jump(JumpKind::Rel32, Some(0x1025), None)));
+        assert_eq!(at(0x1020), Some(jump(JumpKind::Rel8, Some(0x1020), None)));
+        assert_eq!(
+            at(0x1040),
+            Some(jump(JumpKind::Indirect, Some(0x7777), Some(0x1048)))
+        );
+        assert_eq!(
+            at(0x1060),
+            Some(jump(JumpKind::MovRax, Some(0x1234_5678_9ABC), None))
+        );
+        assert_eq!(at(0x1080), Some(jump(JumpKind::MovR11, Some(0xABCD), None)));
+
+        assert_eq!(at(0x10A0), None);
+        assert_eq!(
+            at(0x10C0),
+            Some(jump(JumpKind::Indirect, None, Some(0x1000_10C6)))
+        );
+
+        assert!(existing_jump(&memory, 0x2000).is_err());
+    }
+
+    #[test]
+    fn at_the_end_of_a_section() {
+        let memory = FakeMemory::new();
+
+        let mut code = vec![0xCC; 0x20];
+        code[0x1E..].copy_from_slice(&[0xEB, 0x00]);
+        code[0x1C..0x1E].copy_from_slice(&[0xE9, 0x00]);
+        memory.map(0x1000, code, Prot::XR);
+
+        let mut mov_rax = vec![0x48, 0xB8];
+        mov_rax.extend_from_slice(&0x4242usize.to_le_bytes());
+        mov_rax.extend_from_slice(&[0xFF, 0xE0]);
+        memory.map(0x2000, mov_rax, Prot::XR);
+
+        // two bytes are enough for a short jump
+        assert_eq!(
+            existing_jump(&memory, 0x101E).unwrap(),
+            Some(jump(JumpKind::Rel8, Some(0x1020), None))
+        );
+
+        // a jmp rel32 cut off by the end isn't one
+        assert_eq!(existing_jump(&memory, 0x101C).unwrap(), None);
+
+        // 12 bytes, with no 13th after it
+        assert_eq!(
+            existing_jump(&memory, 0x2000).unwrap(),
+            Some(jump(JumpKind::MovRax, Some(0x4242), None))
+        );
+
+        // not even the two bytes
+        assert!(existing_jump(&memory, 0x101F).is_err());
+    }
+
+    #[test]
+    fn relocate_relative() {
+        let rel32 = jump(JumpKind::Rel32, Some(0x1025), None);
+
+        // room for an absolute jump
+        let mut absolute = vec![0xFF, 0x25, 0, 0, 0, 0];
+        absolute.extend_from_slice(&0x1025u64.to_le_bytes());
+        assert_eq!(
+            relocated_jump(&rel32, 0x7FF0_0000, 14).unwrap(),
+            Some(absolute)
+        );
+
+        // otherwise rel32 from the trampoline, if it reaches
+        assert_eq!(
+            relocated_jump(&rel32, 0x2000, 5).unwrap(),
+            Some(vec![0xE9, 0x20, 0xF0, 0xFF, 0xFF])
+        );
+        assert!(relocated_jump(&rel32, 0x7FF0_0000_0000, 5).is_err());
+
+        // short jumps turn into long ones
+        let rel8 = jump(JumpKind::Rel8, Some(0x1020), None);
+        assert_eq!(
+            relocated_jump(&rel8, 0x1100, 5).unwrap(),
+            Some(vec![0xE9, 0x1B, 0xFF, 0xFF, 0xFF])
+        );
+    }
+
+    #[test]
+    fn relocate_indirect() {
+        let indirect = jump(JumpKind::Indirect, Some(0x7777), Some(0x1048));
+
+        // through the same pointer
+        assert_eq!(
+            relocated_jump(&indirect, 0x3000, 6).unwrap(),
+            Some(vec![0xFF, 0x25, 0x42, 0xE0, 0xFF, 0xFF])
+        );
+
+        // too far from the pointer, straight to where it points
+        let mut absolute = vec![0xFF, 0x25, 0, 0, 0, 0];
+        absolute.extend_from_slice(&0x7777u64.to_le_bytes());
+        assert_eq!(
+            relocated_jump(&indirect, 0x7FF0_0000_0000, 14).unwrap(),
+            Some(absolute)
+        );
+
+        assert!(relocated_jump(&indirect, 0x7FF0_0000_0000, 6).is_err());
+
+        let unreadable = jump(JumpKind::Indirect, None, Some(0x1048));
+        assert!(relocated_jump(&unreadable, 0x7FF0_0000_0000, 14).is_err());
+    }
+
+    #[test]
+    fn relocate_absolute() {
+        for kind in [JumpKind::MovRax, JumpKind::MovR11] {
+            let jump = jump(kind, Some(0xABCD), None);
+            assert_eq!(relocated_jump(&jump, 0x7FF0_0000_0000, 13).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn trampoline_fixed_up() {
+        let memory = code();
+
+        // what libmem leaves us with: the copied jmp rel32, some nops and the jump back
+        let mut trampoline = vec![0xE9, 0x20, 0, 0, 0];
+        trampoline.extend_from_slice(&[0x90; 9]);
+        trampoline.extend_from_slice(&absolute_jump(0x100E));
+        memory.map(0x40_0000, trampoline, Prot::XR);
+
+        let rel32 = existing_jump(&memory, 0x1000).unwrap().unwrap();
+        fix_trampoline(&memory, &rel32, 0x40_0000, 14).unwrap();
+
+        assert_eq!(
+            existing_jump(&memory, 0x40_0000).unwrap(),
+            Some(jump(JumpKind::Indirect, Some(0x1025), Some(0x40_0006)))
+        );
+
+        // the jump back is untouched
+        assert_eq!(memory.peek(0x40_000E, 14).unwrap(), absolute_jump(0x100E));
+
+        // already going the right way, left alone
+        let before = memory.peek(0x40_0000, 14).unwrap();
+        fix_trampoline(&memory, &rel32, 0x40_0000, 14).unwrap();
+        assert_eq!(memory.peek(0x40_0000, 14).unwrap(), before);
+    }
+
+    #[test]
+    fn policy() {
+        assert_eq!(conflict_policy(), ConflictPolicy::Chain);
+
+        #[derive(Deserialize)]
+        struct Config {
+            policy: ConflictPolicy,
+        }
+
+        let config = toml::from_str::<Config>("policy = 'skip'").unwrap();
+        assert_eq!(config.policy, ConflictPolicy::Skip);
+    }
+}
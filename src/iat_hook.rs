@@ -0,0 +1,515 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex, Once},
+};
+
+use eyre::{OptionExt as _, Result, bail, eyre};
+use libmem::Address;
+use log::{debug, error, info, warn};
+
+use crate::{
+    game_version,
+    memory::{CurrentProcess, MemoryBackend, ModuleInfo},
+    pe::{Layout, PeImage},
+    shutdown::{self, Detach},
+};
+
+/// Every installed import hook, in install order
+static IAT_HOOKS: Mutex<Vec<Arc<IatHook>>> = Mutex::new(Vec::new());
+
+struct IatHook {
+    /// `game.exe: KERNEL32.dll!CreateFileW`
+    name: String,
+    /// The import address table entry
+    slot: Address,
+    detour: Address,
+    /// What the slot pointed at before we got to it
+    original: Address,
+    enabled: Mutex<bool>,
+}
+
+impl IatHook {
+    fn set_enabled(&self, enable: bool) -> Result<()> {
+        let mut enabled = self.enabled.lock().unwrap_or_else(|e| e.into_inner());
+        if *enabled == enable {
+            return Ok(());
+        }
+
+        let (from, to) = if enable {
+            (self.original, self.detour)
+        } else {
+            (self.detour, self.original)
+        };
+
+        let memory = CurrentProcess;
+
+        // someone else swapped the slot after us. putting ours back cuts them off,
+        // but leaving it would have calls go to wherever we point after we're gone
+        let current = memory.read::<Address>(self.slot)?;
+        if current != from {
+            warn!(
+                "import hook {}: slot at {:#x} was changed to {current:#x} by someone else, overwriting it",
+                self.name, self.slot
+            );
+        }
+
+        // the import table is read only once the loader is done with it
+        memory
+            .write_code(self.slot, &to.to_le_bytes())
+            .map_err(|e| eyre!("import hook {}: {e}", self.name))?;
+
+        *enabled = enable;
+
+        debug!(
+            "{} import hook {} at {:#x}",
+            if enable { "enabled" } else { "disabled" },
+            self.name,
+            self.slot
+        );
+
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        *self.enabled.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An installed import hook. Puts the original pointer back when dropped!
+///
+/// If you want a hook to stay for the lifetime of the plugin, call [`IatHookHandle::keep`].
+/// Either way, everything that's still hooked is restored on unload in reverse install order
+pub struct IatHookHandle {
+    hook: Arc<IatHook>,
+}
+
+impl IatHookHandle {
+    pub fn name(&self) -> &str {
+        &self.hook.name
+    }
+
+    /// The import address table entry we swapped
+    pub fn slot(&self) -> Address {
+        self.hook.slot
+    }
+
+    /// The imported function, to call through to. Unlike an inline hook this never changes
+    pub fn original(&self) -> Address {
+        self.hook.original
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.hook.is_enabled()
+    }
+
+    /// Point the import at the detour again after it was disabled
+    pub fn enable(&self) -> Result<()> {
+        self.hook.set_enabled(true)
+    }
+
+    /// Point the import back at the original function, but keep the hook around to enable again later
+    pub fn disable(&self) -> Result<()> {
+        self.hook.set_enabled(false)
+    }
+
+    /// Keep the hook installed until the plugin unloads, instead of until this is dropped
+    pub fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for IatHookHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.hook.set_enabled(false) {
+            error!("{e}");
+        }
+
+        if let Ok(mut hooks) = IAT_HOOKS.lock() {
+            hooks.retain(|h| !Arc::ptr_eq(h, &self.hook));
+        }
+    }
+}
+
+/// Hook `module`'s import of `import` by pointing its import address table entry at `detour`
+///
+/// `import` is `dll!function`, or `dll!#ordinal` for imports by ordinal, e.g. `KERNEL32.dll!CreateFileW`.
+/// The dll name isn't case sensitive, and the `.dll` can be left off.
+///
+/// Only calls from `module` through its imports are hooked. Calls from other modules, or through
+/// `GetProcAddress`, go straight to the function; use an inline hook from [`hooks`](crate::hooks)
+/// for those.
+///
+/// Fails if the game version check refused this version of the game
+///
+/// ```ignore
+/// static HOOK: OnceLock<IatHookHandle> = OnceLock::new();
+///
+/// extern "system" fn sleep(ms: u32) {
+///     let original: extern "system" fn(u32) = unsafe { mem::transmute(HOOK.get().unwrap().original()) };
+///
+///     // the game only ever sleeps half as long
+///     original(ms / 2)
+/// }
+///
+/// let hook = unsafe { iat_hook::install("game.exe", "kernel32!Sleep", sleep as Address)? };
+/// _ = HOOK.set(hook);
+/// ```
+///
+/// # Safety
+/// `detour` must be a function with the exact same signature and calling convention as the import
+pub unsafe fn install(module: &str, import: &str, detour: Address) -> Result<IatHookHandle> {
+    let name = format!("{module}: {import}");
+
+    game_version::ensure_allowed(&format!("import hook {name}"))?;
+    register_teardown();
+
+    let memory = CurrentProcess;
+    let module = memory
+        .find_module(module)
+        .ok_or_eyre(format!("import hook {name}: module {module} isn't loaded"))?;
+
+    let slot = find_slot(&memory, &module, import).map_err(|e| eyre!("import hook {name}: {e}"))?;
+
+    let mut hooks = IAT_HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(other) = hooks.iter().find(|h| h.slot == slot) {
+        bail!("import hook {name}: already hooked by {}", other.name);
+    }
+
+    let original = memory.read::<Address>(slot)?;
+
+    let hook = Arc::new(IatHook {
+        name,
+        slot,
+        detour,
+        original,
+        enabled: Mutex::new(false),
+    });
+
+    hook.set_enabled(true)?;
+
+    info!(
+        "installed import hook {} at {slot:#x} (original {original:#x})",
+        hook.name
+    );
+
+    hooks.push(hook.clone());
+
+    Ok(IatHookHandle { hook })
+}
+
+/// Address of the import address table entry `module` calls `import` through. See [`install`]
+/// for the format of `import`
+///
+/// If `module` was linked without an import lookup table, there are no names left in memory. Then
+/// the function is looked up in the exports of the loaded dll, and found by the address the loader wrote
+pub fn find_slot(
+    memory: &impl MemoryBackend,
+    module: &ModuleInfo,
+    import: &str,
+) -> Result<Address> {
+    let (dll, function) = import
+        .split_once('!')
+        .ok_or_eyre(format!("`{import}` isn't `dll!function`"))?;
+
+    let ordinal = function
+        .strip_prefix('#')
+        .map(|ordinal| {
+            ordinal
+                .parse::<u16>()
+                .map_err(|_| eyre!("invalid ordinal in `{import}`"))
+        })
+        .transpose()?;
+
+    let image = memory.module_image(module)?;
+    let pe = PeImage::parse(&image, Layout::Mapped)?;
+
+    let imports = pe.imports()?;
+    let from_dll = imports
+        .iter()
+        .filter(|i| same_dll(&i.dll, dll))
+        .collect::<Vec<_>>();
+
+    let Some(first) = from_dll.first() else {
+        bail!("{} doesn't import anything from {dll}", module.name);
+    };
+
+    let functions = || from_dll.iter().flat_map(|i| &i.functions);
+
+    let found = functions().find(|f| match ordinal {
+        Some(ordinal) => f.ordinal == Some(ordinal),
+        None => f.name.as_deref() == Some(function),
+    });
+
+    if let Some(found) = found {
+        return Ok(module.base + found.iat_rva as usize);
+    }
+
+    // without a lookup table, all that's left once the loader is done are the addresses it wrote
+    let unnamed = functions()
+        .filter(|f| f.name.is_none() && f.ordinal.is_none())
+        .collect::<Vec<_>>();
+
+    if unnamed.is_empty() {
+        bail!("{} doesn't import {function} from {dll}", module.name);
+    }
+
+    let address = export_address(memory, &first.dll, function, ordinal)?;
+
+    for unnamed in unnamed {
+        let slot = module.base + unnamed.iat_rva as usize;
+
+        if memory.read::<Address>(slot)? == address {
+            return Ok(slot);
+        }
+    }
+
+    bail!(
+        "{} doesn't import {function} from {dll}, or something else already swapped it",
+        module.name
+    );
+}
+
+/// Where the loaded `dll` exports `function`, to find it among imports that don't have names
+fn export_address(
+    memory: &impl MemoryBackend,
+    dll: &str,
+    function: &str,
+    ordinal: Option<u16>,
+) -> Result<Address> {
+    let module = memory
+        .find_module(dll)
+        .ok_or_eyre(format!("{dll} isn't loaded"))?;
+
+    let image = memory.module_image(&module)?;
+    let exports = PeImage::parse(&image, Layout::Mapped)?.exports()?;
+
+    let export = exports
+        .into_iter()
+        .find(|e| match ordinal {
+            Some(ordinal) => e.ordinal == ordinal,
+            None => e.name.as_deref() == Some(function),
+        })
+        .ok_or_eyre(format!("{dll} doesn't export {function}"))?;
+
+    // the loader wrote wherever it ended up, which we'd have to follow the forwarder to
+    if let Some(forwarder) = export.forwarder {
+        bail!(
+            "{dll}!{function} is forwarded to {forwarder}, and the import table doesn't have names to find it by"
+        );
+    }
+
+    Ok(module.base + export.rva as usize)
+}
+
+/// `kernel32` is `KERNEL32.dll`
+fn same_dll(a: &str, b: &str) -> bool {
+    fn strip(name: &str) -> &str {
+        let stem = name.len().saturating_sub(4);
+
+        match name.split_at_checked(stem) {
+            Some((stem, ext)) if !stem.is_empty() && ext.eq_ignore_ascii_case(".dll") => stem,
+            _ => name,
+        }
+    }
+
+    strip(a).eq_ignore_ascii_case(strip(b))
+}
+
+/// Put every import back, in reverse install order
+pub fn unhook_all() {
+    let hooks = match IAT_HOOKS.lock() {
+        Ok(mut hooks) => mem::take(&mut *hooks),
+        Err(_) => return,
+    };
+
+    for hook in hooks.into_iter().rev() {
+        if let Err(e) = hook.set_enabled(false) {
+            error!("{e}");
+        }
+    }
+}
+
+fn register_teardown() {
+    static TEARDOWN: Once = Once::new();
+
+    TEARDOWN.call_once(|| {
+        shutdown::register("import hooks", |reason| {
+            // the process is going away, nobody will call through the imports anymore
+            if reason == Detach::ProcessExit {
+                return;
+            }
+
+            // the detours live in our dll, and the game keeps calling its imports after we're gone
+            unhook_all();
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::{
+        memory::FakeMemory,
+        pe::fixture::{Exported, Imported, PeBuilder},
+    };
+
+    const KERNEL32: Address = 0x7FF8_0000_0000;
+    const GAME: Address = 0x1_4000_0000;
+
+    /// What the game imports from kernel32
+    const FROM_KERNEL32: &[Imported] = &[
+        Imported::Name("Sleep"),
+        Imported::Ordinal(3),
+        Imported::Name("HeapAlloc"),
+    ];
+
+    fn load(memory: &FakeMemory, name: &str, base: Address, pe: &PeBuilder) -> ModuleInfo {
+        let image = pe.build(Layout::Mapped);
+
+        memory.add_module(name, base, image.len());
+        memory.map(base, image, Prot::R);
+
+        memory.find_module(name).unwrap()
+    }
+
+    /// Two modules: kernel32 exporting `Sleep`, `HeapAlloc` (forwarded) and `#3`, and the game
+    /// importing them, with or without a lookup table
+    fn process(lookup_table: bool) -> (FakeMemory, ModuleInfo) {
+        let memory = FakeMemory::new();
+
+        let mut kernel32 = PeBuilder::new();
+        let code = kernel32.section(".text", 0, vec![0xC3; 0x20]);
+        kernel32.exports(
+            "KERNEL32.dll",
+            1,
+            &[
+                (Some("Sleep"), Exported::Rva(code)),
+                (
+                    Some("HeapAlloc"),
+                    Exported::Forward("NTDLL.RtlAllocateHeap"),
+                ),
+                (None, Exported::Rva(code + 0x10)),
+            ],
+        );
+        load(&memory, "KERNEL32.DLL", KERNEL32, &kernel32);
+
+        let mut game = PeBuilder::new();
+        game.imports(
+            &[
+                ("KERNEL32.dll", FROM_KERNEL32),
+                ("USER32.dll", &[Imported::Name("MessageBoxW")]),
+            ],
+            lookup_table,
+        );
+        let game = load(&memory, "game.exe", GAME, &game);
+
+        // bind the kernel32 imports like the loader would
+        for (slot, address) in slots(&memory, &game).into_iter().zip([
+            KERNEL32 + 0x1000,
+            KERNEL32 + 0x1010,
+            0x7FF9_0000_1234,
+        ]) {
+            memory.write_code(slot, &address.to_le_bytes()).unwrap();
+        }
+
+        (memory, game)
+    }
+
+    /// The game's kernel32 import address table
+    fn slots(memory: &FakeMemory, game: &ModuleInfo) -> Vec<Address> {
+        let image = memory.module_image(game).unwrap();
+        let imports = PeImage::parse(&image, Layout::Mapped)
+            .unwrap()
+            .imports()
+            .unwrap();
+
+        imports[0]
+            .functions
+            .iter()
+            .map(|f| game.base + f.iat_rva as usize)
+            .collect()
+    }
+
+    #[test]
+    fn by_name_and_ordinal() {
+        let (memory, game) = process(true);
+        let slots = slots(&memory, &game);
+
+        for import in ["KERNEL32.dll!Sleep", "kernel32!Sleep", "Kernel32.DLL!Sleep"] {
+            assert_eq!(find_slot(&memory, &game, import).unwrap(), slots[0]);
+        }
+
+        assert_eq!(find_slot(&memory, &game, "kernel32!#3").unwrap(), slots[1]);
+        assert_eq!(
+            find_slot(&memory, &game, "kernel32!HeapAlloc").unwrap(),
+            slots[2]
+        );
+        assert!(find_slot(&memory, &game, "user32!MessageBoxW").is_ok());
+    }
+
+    #[test]
+    fn bad_imports() {
+        let (memory, game) = process(true);
+
+        for bad in [
+            "kernel32",
+            "kernel32!Nope",
+            "kernel32!#x",
+            "kernel32!#5",
+            "nope.dll!Sleep",
+            "dll!Sleep",
+            "user32!Sleep",
+        ] {
+            assert!(find_slot(&memory, &game, bad).is_err(), "`{bad}` found");
+        }
+
+        assert_eq!(
+            find_slot(&memory, &game, "kernel32!Nope")
+                .unwrap_err()
+                .to_string(),
+            "game.exe doesn't import Nope from kernel32"
+        );
+    }
+
+    #[test]
+    fn without_lookup_table() {
+        let (memory, game) = process(false);
+        let slots = slots(&memory, &game);
+
+        // found by what kernel32 exports them at
+        assert_eq!(
+            find_slot(&memory, &game, "kernel32!Sleep").unwrap(),
+            slots[0]
+        );
+        assert_eq!(find_slot(&memory, &game, "kernel32!#3").unwrap(), slots[1]);
+
+        let forwarded = find_slot(&memory, &game, "kernel32!HeapAlloc").unwrap_err();
+        assert!(
+            forwarded
+                .to_string()
+                .contains("forwarded to NTDLL.RtlAllocateHeap")
+        );
+
+        assert!(find_slot(&memory, &game, "kernel32!Nope").is_err());
+
+        // someone else hooked it already, there's no telling which slot it is anymore
+        memory
+            .write_code(slots[0], &0xDEADusize.to_le_bytes())
+            .unwrap();
+        assert!(find_slot(&memory, &game, "kernel32!Sleep").is_err());
+    }
+
+    #[test]
+    fn dll_names() {
+        assert!(same_dll("KERNEL32.dll", "kernel32"));
+        assert!(same_dll("kernel32", "KERNEL32.DLL"));
+        assert!(same_dll("d3d11.dll", "D3D11.dll"));
+
+        assert!(!same_dll("kernel32.dll", "kernelbase.dll"));
+        assert!(!same_dll(".dll", ""));
+        assert!(!same_dll("kernel32.exe", "kernel32"));
+    }
+}
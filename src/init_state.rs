@@ -0,0 +1,96 @@
+use std::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use log::debug;
+
+/// Where we are in the dll's lifecycle
+///
+/// `Init` may be called by yabg3nml, by our own `DllMain` bootstrap thread, or by hand.
+/// If a loader misreports itself we could easily get it twice, so this is what makes `Init` idempotent
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InitState {
+    Uninitialized = 0,
+    Initializing,
+    Ready,
+    Failed,
+    ShuttingDown,
+}
+
+static STATE: AtomicU8 = AtomicU8::new(InitState::Uninitialized as u8);
+
+impl InitState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Uninitialized,
+            1 => Self::Initializing,
+            2 => Self::Ready,
+            3 => Self::Failed,
+            _ => Self::ShuttingDown,
+        }
+    }
+}
+
+impl Display for InitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::Uninitialized => "uninitialized",
+            Self::Initializing => "initializing",
+            Self::Ready => "ready",
+            Self::Failed => "failed",
+            Self::ShuttingDown => "shutting down",
+        };
+
+        f.write_str(state)
+    }
+}
+
+/// Current state
+pub fn state() -> InitState {
+    InitState::from_u8(STATE.load(Ordering::Acquire))
+}
+
+/// Move from `Uninitialized` to `Initializing`
+///
+/// Only one caller will ever win this. Everyone else gets the state it was in instead
+pub fn begin() -> Result<(), InitState> {
+    STATE
+        .compare_exchange(
+            InitState::Uninitialized as u8,
+            InitState::Initializing as u8,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map(|_| ())
+        .map_err(InitState::from_u8)
+}
+
+/// Move from `Initializing` to `Ready` or `Failed`
+///
+/// Does nothing if we're already shutting down
+pub fn finish(success: bool) {
+    let to = if success {
+        InitState::Ready
+    } else {
+        InitState::Failed
+    };
+
+    if STATE
+        .compare_exchange(
+            InitState::Initializing as u8,
+            to as u8,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_ok()
+    {
+        debug!("init state: {to}");
+    }
+}
+
+/// Move to `ShuttingDown` from any state
+pub fn shutdown() {
+    STATE.store(InitState::ShuttingDown as u8, Ordering::Release);
+}
@@ -1,72 +1,108 @@
+// the dll only runs on windows, but everything that isn't tied to win32 builds on linux too,
+// so the tests can run there. without `Init` to call into it, most of it is unused on linux
+#![cfg_attr(not(windows), allow(dead_code))]
+
+#[cfg(windows)]
 mod backtrace;
+#[cfg(windows)]
 mod config;
+#[cfg(windows)]
 mod console;
+#[cfg(windows)]
+mod debugger;
+#[cfg(windows)]
+mod deferred;
+mod dispatch;
+mod exception_handler;
+mod game_version;
+mod hooks;
+mod iat_hook;
+mod init_state;
+#[cfg(windows)]
 mod logging;
+mod memory;
+mod mid_hook;
+mod minidump;
+#[cfg(windows)]
+mod my_plugin;
+#[cfg(windows)]
 mod panic_hook;
+mod patches;
+#[cfg(windows)]
 mod paths;
+mod pattern;
+mod pe;
+#[cfg(windows)]
+mod plugin;
+mod pointer_chain;
+#[cfg(windows)]
 mod popup;
+mod resolver;
+mod scheduler;
+mod shutdown;
+mod signatures;
+mod typed_hook;
+#[cfg(windows)]
 mod utils;
+mod vmt_hook;
+mod watchdog;
+mod worker;
 
-use std::{ffi::c_void, mem, panic, sync::OnceLock, thread, time};
+#[cfg(windows)]
+use std::{
+    ffi::c_void,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
 
+#[cfg(windows)]
 use eyre::{Context, ContextCompat, Error};
-// this imports all of libmem's functions so you can use them
-// alternatively, you can import the specific ones you want to use
-// instead of a glob import
-use libmem::*;
-use log::{LevelFilter, error};
+#[cfg(windows)]
+use log::{LevelFilter, error, warn};
+#[cfg(windows)]
 use native_plugin_lib::{declare_plugin, is_yabg3nml};
+#[cfg(windows)]
 use windows::{
     Win32::{
         Foundation::{HINSTANCE, TRUE},
-        System::{
-            Diagnostics::Debug::IsDebuggerPresent,
-            SystemServices::{DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH},
-            Threading::{CreateThread, THREAD_CREATE_RUN_IMMEDIATELY},
-        },
+        System::SystemServices::{DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH},
     },
     core::BOOL,
 };
 
-use config::Config;
+#[cfg(windows)]
+use config::{CONFIG_FILENAME, Config};
+#[cfg(windows)]
 use logging::{debug_console, setup_logging};
+#[cfg(windows)]
+use my_plugin::MyPlugin;
+#[cfg(windows)]
 use paths::get_dll_dir_filepath;
-use popup::{MessageBoxIcon, display_popup};
+#[cfg(windows)]
+use plugin::register_plugin;
+#[cfg(windows)]
+use shutdown::Detach;
+#[cfg(windows)]
 use utils::ThreadedWrapper;
 
+#[cfg(windows)]
 static MODULE: OnceLock<ThreadedWrapper<HINSTANCE>> = OnceLock::new();
 
 // Declare your plugin name and description
 // This will be accessible by anyone who uses the Native-Plugin-Lib to get the info
+#[cfg(windows)]
 declare_plugin! {
     "MyPlugin",
     "Author",
     "My Plugin Description"
 }
 
-/// All of our main plugin code goes here!
-///
-/// To log to the logfile, use the log macros: `log::debug!()`, `log::info!()`, `log::warn!()`, `log::error!()`
-/// Recommend to catch and handle potential panics instead of panicking; log instead, it's much cleaner
-///
-/// You can use tracing for logging if you prefer a much higher quality logger, but its api is also
-/// much more complex, and as such is harder to learn
-fn entry(module: HINSTANCE) {
-    // Show the hook was injected. DO NOT popup in production code! This is just for a POC
-    display_popup(
-        "Success",
-        "Plugin successfully injected",
-        MessageBoxIcon::Information,
-    );
-
-    // load a config
-    let config_path =
-        get_dll_dir_filepath(module, "my-config.toml").expect("Failed to find config path");
-    let config = Config::load(config_path).expect("Failed to load config");
-    // TODO: Do something with config
-
-    todo!("Implement libmem/memory lib hooking logic");
-}
+// The type implementing `Plugin`. All of your plugin code goes in there
+#[cfg(windows)]
+register_plugin!(MyPlugin);
 
 /// Callback which is executed after the dll is loaded. It is safe to do anything you want in this call.
 /// It is HIGHLY preferred to use Init for everything and only use `DllMain` for very very basic tasks you
@@ -79,20 +115,19 @@ fn entry(module: HINSTANCE) {
 /// So while doing anything here is safe from yabg3nml, it is not necessarily from `DllMain`. This
 /// template is already set up to run only Init in yabg3nml and fallback to running Init in `DllMain`
 /// for other ones.
+#[cfg(windows)]
 #[unsafe(no_mangle)]
 extern "C" fn Init() {
-    // If you're getting a hang on the game when you start it, it's because you compiled in debug mode,
-    // haven't attached a debugger, and this code here is still enabled!
-    //
-    // If you don't want to wait to ever attach a debugger, then comment or remove this line
-    if cfg!(debug_assertions) {
-        let is_debugger_present = || unsafe { IsDebuggerPresent().as_bool() };
-        while !is_debugger_present() {
-            // 60hz polling
-            thread::sleep(time::Duration::from_millis(16));
-        }
+    // Init may be called more than once, e.g. if a loader misreports itself, or someone calls it by hand.
+    // Everything below must only ever run once, so only the first call gets through
+    if let Err(state) = init_state::begin() {
+        warn!("Init called again while {state}, ignoring");
+        return;
     }
 
+    // times every init phase and warns about any that are taking too long
+    watchdog::start(watchdog::DEFAULT_DEADLINE);
+
     // Set up a custom panic hook so we can log all panics to logfile
     // This is also only triggered once. Safe to call it multiple times.
     panic_hook::set_hook();
@@ -111,18 +146,119 @@ extern "C" fn Init() {
             .inner();
 
         // set up our actual log file handling
+        let phase = watchdog::phase("logging");
         if cfg!(debug_assertions) {
             debug_console(LevelFilter::Trace, "Native Plugin Template Debug Console")
                 .context("debug console spawn failed")?;
         } else {
             setup_logging(module).context("failed to setup logging")?;
         }
+        drop(phase);
+
+        // load a config
+        let phase = watchdog::phase("config");
+        let config_path =
+            get_dll_dir_filepath(module, CONFIG_FILENAME).context("failed to find config path")?;
+        let config = Config::load(config_path).context("failed to load config")?;
+        watchdog::set_deadline(Duration::from_millis(config.init_phase_deadline_ms));
+        drop(phase);
+
+        // If you're getting a hang on the game when you start it, it's because you haven't attached
+        // a debugger and waiting for one is enabled. It is by default in debug builds!
+        //
+        // See the `[debugger]` section of the config, or set `MY_PLUGIN_WAIT_FOR_DEBUGGER=0`
+        //
+        // however long that takes isn't part of how long init took
+        watchdog::untimed(|| debugger::wait_for_debugger(&config.debugger));
+
+        // hands everything else over to the plugin
+        let ctx = plugin::load(module, config, plugin()).context("failed to load plugin")?;
+        let config = ctx.config();
+
+        // plugins tend to crash when the offsets they know about moved in a game update
+        let phase = watchdog::phase("game version");
+        game_version::check(plugin::supported_versions(ctx), config.unknown_game_version);
+        drop(phase);
+
+        // other mods like hooking the same functions we do
+        hooks::set_conflict_policy(config.hook_conflicts);
+
+        let phase = watchdog::phase("crash handlers");
+
+        // the panic hook only sees rust panics. if you want access violations and friends
+        // in our own code to show up in the logfile too, turn this on in the config
+        if config.exception_handler {
+            exception_handler::set_handler(module);
+        }
+
+        // dumps are a lot more useful than a log line when someone reports a crash
+        if config.minidumps
+            && let Err(e) = minidump::enable(module, config.max_minidumps)
+        {
+            warn!("failed to enable minidumps: {e}");
+        }
+
+        drop(phase);
+
+        // anything still queued for the game thread would otherwise run after we're gone
+        shutdown::register("game thread queue", |reason| {
+            if reason == Detach::FreeLibrary {
+                dispatch::GAME_THREAD.clear();
+            }
+        });
+
+        // some plugins can't do anything until the game gets to a certain point. in that case we wait
+        // on another thread; the loader may be blocked on us returning from Init
+        let conditions = plugin::ready_conditions(ctx);
+        if !conditions.is_empty() {
+            let timeout = Duration::from_millis(config.ready_timeout_ms);
+
+            // a worker, so `Uninit` stops the waiting instead of unmapping the code under it
+            worker::spawn("deferred init", move |token| {
+                // the conditions aren't UnwindSafe, but if one panics we never look at them again anyways
+                let deferred = AssertUnwindSafe(move || {
+                    deferred::wait(conditions, timeout, &token).context("plugin was never ready")?;
+                    start_plugin(ctx)
+                });
+
+                finish_init(panic::catch_unwind(deferred));
+            })?;
+
+            return Ok(Started::Deferred);
+        }
 
-        entry(module);
+        start_plugin(ctx)?;
 
-        Ok::<_, Error>(())
+        Ok::<_, Error>(Started::Now)
     });
 
+    // a deferred start finishes on its own thread
+    if !matches!(result, Ok(Ok(Started::Deferred))) {
+        finish_init(result.map(|r| r.map(|_| ())));
+    }
+}
+
+/// Whether the plugin was started during Init, or will be once it's ready
+#[cfg(windows)]
+enum Started {
+    Now,
+    Deferred,
+}
+
+#[cfg(windows)]
+fn start_plugin(ctx: &'static plugin::Context) -> Result<(), Error> {
+    // the plugin can time its own phases inside of this one with `watchdog::phase`
+    let _phase = watchdog::phase("plugin load");
+    plugin::start(ctx)
+}
+
+/// Log the outcome of init and move to the final init state
+#[cfg(windows)]
+fn finish_init(result: thread::Result<Result<(), Error>>) {
+    let success = matches!(result, Ok(Ok(_)));
+    init_state::finish(success);
+    watchdog::finish(success);
+
     match result {
         // all good
         Ok(Ok(_)) => (),
@@ -139,6 +275,17 @@ extern "C" fn Init() {
     }
 }
 
+/// Call this before `FreeLibrary` if you unload us at runtime, e.g. to hot reload the plugin
+///
+/// It waits for our worker threads to exit. That can't be done in `DllMain`, because threads need
+/// the loader lock to exit, and `DllMain` is holding it. Without this, any worker still running
+/// when the dll is unmapped crashes the game the next time it's scheduled
+#[cfg(windows)]
+#[unsafe(no_mangle)]
+extern "C" fn Uninit() {
+    worker::stop_all(worker::STOP_TIMEOUT);
+}
+
 /// Dll entry point
 ///
 /// You should NOT use `DllMain` for _anything_.
@@ -173,11 +320,12 @@ extern "C" fn Init() {
 /// <https://blog.barthe.ph/2009/07/30/no-stdlib-in-dllmai/>
 /// <https://learn.microsoft.com/en-us/windows/win32/dlls/dllmain?redirectedfrom=MSDN> (see warning section)
 /// <https://learn.microsoft.com/en-us/windows/win32/dlls/dynamic-link-library-best-practices>
+#[cfg(windows)]
 #[unsafe(no_mangle)]
 extern "system" fn DllMain(
     module: HINSTANCE,
     fdw_reason: u32,
-    _lpv_reserved: *const c_void,
+    lpv_reserved: *const c_void,
 ) -> BOOL {
     match fdw_reason {
         DLL_PROCESS_ATTACH => {
@@ -197,28 +345,26 @@ extern "system" fn DllMain(
                 // > Call CreateThread. Creating a thread can work if you do not synchronize with
                 //   other threads, but it is risky.
 
-                extern "system" fn bootstrap(_: *mut c_void) -> u32 {
-                    Init();
-                    0
-                }
-
-                // we do not use thread::spawn because we have no guarantee what will change in
-                // its implementation; calling this directly is safer
-                _ = unsafe {
-                    CreateThread(
-                        None,
-                        0,
-                        Some(bootstrap),
-                        None,
-                        THREAD_CREATE_RUN_IMMEDIATELY,
-                        None,
-                    )
-                };
+                // a worker like every other thread of ours, so `Uninit` waits for it too. it uses
+                // CreateThread directly, not thread::spawn, since we have no guarantee what will
+                // change in its implementation
+                _ = worker::spawn("bootstrap", |_| Init());
             }
         }
 
         DLL_PROCESS_DETACH => {
-            // TODO: deinit code here
+            // > lpvReserved is NULL if FreeLibrary has been called or the DLL load failed and non-NULL
+            // > if the process is terminating.
+            let reason = if lpv_reserved.is_null() {
+                Detach::FreeLibrary
+            } else {
+                Detach::ProcessExit
+            };
+
+            init_state::shutdown();
+
+            // runs everything registered with `shutdown::register` during Init, in reverse order
+            shutdown::run(reason);
         }
 
         _ => (),
@@ -1,7 +1,12 @@
-use std::fs::{File, OpenOptions};
+use std::{
+    fs::{File, OpenOptions},
+    process,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use eyre::Result;
-use log::LevelFilter;
+use log::{LevelFilter, info};
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode, WriteLogger};
 use windows::Win32::Foundation::HINSTANCE;
 
@@ -33,6 +38,8 @@ pub fn setup_logging(module: HINSTANCE) -> Result<()> {
     // enable logging
     CombinedLogger::init(vec![WriteLogger::new(level, Config::default(), file)])?;
 
+    info!("log session {}", session_id());
+
     Ok(())
 }
 
@@ -48,5 +55,24 @@ pub fn debug_console(level: LevelFilter, title: &str) -> Result<()> {
         ColorChoice::AlwaysAnsi,
     )?;
 
+    info!("log session {}", session_id());
+
     Ok(())
 }
+
+/// Unique id for this run of the game, `<unix timestamp>-<pid>`
+///
+/// Logged once when logging starts, so anything else written during this run (e.g. minidumps)
+/// can be named after it and matched back up to the right part of the logfile
+pub fn session_id() -> &'static str {
+    static SESSION: OnceLock<String> = OnceLock::new();
+
+    SESSION.get_or_init(|| {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        format!("{timestamp}-{}", process::id())
+    })
+}
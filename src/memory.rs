@@ -0,0 +1,683 @@
+#[cfg(windows)]
+use std::ffi::c_void;
+#[cfg(not(windows))]
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt as _,
+};
+use std::{
+    mem, slice,
+    sync::{Mutex, MutexGuard},
+};
+
+use eyre::{OptionExt as _, Result, bail, eyre};
+use libmem::{Address, Prot, alloc_memory, enum_modules, enum_segments, free_memory, prot_memory};
+use log::error;
+#[cfg(windows)]
+use windows::Win32::System::{
+    Diagnostics::Debug::{FlushInstructionCache, ReadProcessMemory, WriteProcessMemory},
+    Threading::GetCurrentProcess,
+};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Plain old data, that can be read from and written to memory as raw bytes: valid for any bit
+/// pattern, and without padding. Integers, floats, and arrays of them. [`Address`] is a `usize`
+///
+/// Sealed, because implementing it for the wrong type would make [`MemoryBackend::read`] unsound.
+/// Read the bytes with [`MemoryBackend::read_bytes`] for anything else
+pub trait Pod: Copy + sealed::Sealed + 'static {}
+
+macro_rules! pod {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl Pod for $ty {}
+        )*
+    };
+}
+
+pod!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+impl<T: Pod, const N: usize> sealed::Sealed for [T; N] {}
+impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A loaded module, e.g. `game.exe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub base: Address,
+    pub size: usize,
+}
+
+impl ModuleInfo {
+    pub fn end(&self) -> Address {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        (self.base..self.end()).contains(&address)
+    }
+}
+
+/// A mapped range of memory with the same protection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub base: Address,
+    pub size: usize,
+    pub prot: Prot,
+}
+
+impl Section {
+    pub fn end(&self) -> Address {
+        self.base + self.size
+    }
+
+    pub fn is_readable(&self) -> bool {
+        is_readable(self.prot)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        matches!(self.prot, Prot::X | Prot::XR | Prot::XW | Prot::XRW)
+    }
+}
+
+fn is_readable(prot: Prot) -> bool {
+    matches!(prot, Prot::R | Prot::RW | Prot::XR | Prot::XRW)
+}
+
+fn is_writable(prot: Prot) -> bool {
+    matches!(prot, Prot::W | Prot::RW | Prot::XW | Prot::XRW)
+}
+
+/// Everything we do to memory: read, write, protect, allocate, and finding modules
+///
+/// The scanner, resolver, pointer chains and patches all go through this instead of raw pointers
+/// or libmem, so a bad address is an error instead of an access violation, and so all of that logic
+/// runs just as well on [`FakeMemory`] (a few byte buffers) as on the game
+pub trait MemoryBackend {
+    /// Fill `buf` with the bytes at `address`. Fails if any of them aren't readable
+    fn read_bytes(&self, address: Address, buf: &mut [u8]) -> Result<()>;
+
+    /// Write `bytes` to `address`. Fails if any of them aren't writable; see [`MemoryBackend::write_code`]
+    fn write_bytes(&self, address: Address, bytes: &[u8]) -> Result<()>;
+
+    /// Change the protection of `address..address + size`. Returns the old protection
+    fn protect(&self, address: Address, size: usize, prot: Prot) -> Result<Prot>;
+
+    /// Allocate `size` bytes somewhere
+    fn allocate(&self, size: usize, prot: Prot) -> Result<Address>;
+
+    /// Free memory from [`MemoryBackend::allocate`]
+    fn free(&self, address: Address, size: usize) -> Result<()>;
+
+    /// Every loaded module
+    fn modules(&self) -> Result<Vec<ModuleInfo>>;
+
+    /// The mapped sections of a module, in order
+    fn sections(&self, module: &ModuleInfo) -> Result<Vec<Section>>;
+
+    /// A copy of all the bytes of a section from [`MemoryBackend::sections`]
+    ///
+    /// Always a copy, even in our own process. The game keeps writing to its memory while we look,
+    /// so a slice of it would be a data race
+    fn section_bytes(&self, section: &Section) -> Result<Vec<u8>> {
+        let mut buf = vec![0; section.size];
+        self.read_bytes(section.base, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// A copy of the whole mapped image of a module, headers and all, e.g. to parse with
+    /// [`PeImage`](crate::pe::PeImage). Fails if any of it isn't readable
+    fn module_image(&self, module: &ModuleInfo) -> Result<Vec<u8>> {
+        let mut buf = vec![0; module.size];
+        self.read_bytes(module.base, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// A loaded module by name, e.g. `game.exe`. Not case sensitive
+    fn find_module(&self, name: &str) -> Option<ModuleInfo> {
+        self.modules()
+            .ok()?
+            .into_iter()
+            .find(|m| m.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Write to memory no matter its protection (e.g. code), and put the protection back after
+    fn write_code(&self, address: Address, bytes: &[u8]) -> Result<()> {
+        let old = self.protect(address, bytes.len(), Prot::XRW)?;
+        let written = self.write_bytes(address, bytes);
+
+        if let Err(e) = self.protect(address, bytes.len(), old) {
+            // not fatal, it's just more writable than it used to be
+            error!("failed to restore protection at {address:#x}: {e}");
+        }
+
+        written
+    }
+
+    /// Read a `T` at `address`. Doesn't need to be aligned
+    fn read<T: Pod>(&self, address: Address) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let mut buf = vec![0; mem::size_of::<T>()];
+        self.read_bytes(address, &mut buf)?;
+
+        // buf is exactly size_of::<T>(), and any bytes are a valid T
+        Ok(unsafe { (buf.as_ptr() as *const T).read_unaligned() })
+    }
+
+    /// Write a `T` to `address`. Doesn't need to be aligned
+    fn write<T: Pod>(&self, address: Address, value: T) -> Result<()>
+    where
+        Self: Sized,
+    {
+        // T has no padding, so every byte of it is initialized
+        let bytes =
+            unsafe { slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>()) };
+
+        self.write_bytes(address, bytes)
+    }
+}
+
+/// So anything that owns a backend can borrow one instead, e.g. a hook over `&FakeMemory` in a test
+impl<M: MemoryBackend + ?Sized> MemoryBackend for &M {
+    fn read_bytes(&self, address: Address, buf: &mut [u8]) -> Result<()> {
+        (**self).read_bytes(address, buf)
+    }
+
+    fn write_bytes(&self, address: Address, bytes: &[u8]) -> Result<()> {
+        (**self).write_bytes(address, bytes)
+    }
+
+    fn protect(&self, address: Address, size: usize, prot: Prot) -> Result<Prot> {
+        (**self).protect(address, size, prot)
+    }
+
+    fn allocate(&self, size: usize, prot: Prot) -> Result<Address> {
+        (**self).allocate(size, prot)
+    }
+
+    fn free(&self, address: Address, size: usize) -> Result<()> {
+        (**self).free(address, size)
+    }
+
+    fn modules(&self) -> Result<Vec<ModuleInfo>> {
+        (**self).modules()
+    }
+
+    fn sections(&self, module: &ModuleInfo) -> Result<Vec<Section>> {
+        (**self).sections(module)
+    }
+
+    fn section_bytes(&self, section: &Section) -> Result<Vec<u8>> {
+        (**self).section_bytes(section)
+    }
+
+    fn module_image(&self, module: &ModuleInfo) -> Result<Vec<u8>> {
+        (**self).module_image(module)
+    }
+
+    fn find_module(&self, name: &str) -> Option<ModuleInfo> {
+        (**self).find_module(name)
+    }
+
+    fn write_code(&self, address: Address, bytes: &[u8]) -> Result<()> {
+        (**self).write_code(address, bytes)
+    }
+}
+
+/// The memory of our own process, through libmem
+///
+/// Reads and writes use `Read`/`WriteProcessMemory` (`/proc/self/mem` on linux), so unmapped
+/// or protected pages fail instead of crashing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CurrentProcess;
+
+impl MemoryBackend for CurrentProcess {
+    fn read_bytes(&self, address: Address, buf: &mut [u8]) -> Result<()> {
+        let read = read_process_memory(address, buf)
+            .map_err(|e| eyre!("failed to read {} bytes at {address:#x}: {e}", buf.len()))?;
+
+        if read != buf.len() {
+            bail!(
+                "failed to read {} bytes at {address:#x}: only {read} were readable",
+                buf.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn write_bytes(&self, address: Address, bytes: &[u8]) -> Result<()> {
+        let written = write_process_memory(address, bytes)
+            .map_err(|e| eyre!("failed to write {} bytes at {address:#x}: {e}", bytes.len()))?;
+
+        if written != bytes.len() {
+            bail!(
+                "failed to write {} bytes at {address:#x}: only {written} were writable",
+                bytes.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn protect(&self, address: Address, size: usize, prot: Prot) -> Result<Prot> {
+        unsafe { prot_memory(address, size, prot) }
+            .ok_or_eyre(format!("failed to protect {size} bytes at {address:#x}"))
+    }
+
+    fn allocate(&self, size: usize, prot: Prot) -> Result<Address> {
+        alloc_memory(size, prot).ok_or_eyre(format!("failed to allocate {size} bytes"))
+    }
+
+    fn free(&self, address: Address, size: usize) -> Result<()> {
+        unsafe { free_memory(address, size) }.ok_or_eyre(format!("failed to free {address:#x}"))
+    }
+
+    fn modules(&self) -> Result<Vec<ModuleInfo>> {
+        let modules = enum_modules().ok_or_eyre("failed to enumerate modules")?;
+
+        Ok(modules
+            .into_iter()
+            .map(|m| ModuleInfo {
+                name: m.name,
+                base: m.base,
+                size: m.size,
+            })
+            .collect())
+    }
+
+    fn sections(&self, module: &ModuleInfo) -> Result<Vec<Section>> {
+        let segments = enum_segments().ok_or_eyre("failed to enumerate memory segments")?;
+
+        Ok(segments
+            .into_iter()
+            .filter(|s| s.base >= module.base && s.end <= module.end())
+            .map(|s| Section {
+                base: s.base,
+                size: s.size,
+                prot: s.prot,
+            })
+            .collect())
+    }
+}
+
+/// Returns how many bytes were read
+#[cfg(windows)]
+fn read_process_memory(address: Address, buf: &mut [u8]) -> windows::core::Result<usize> {
+    let mut read = 0;
+
+    unsafe {
+        ReadProcessMemory(
+            GetCurrentProcess(),
+            address as *const c_void,
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            Some(&mut read),
+        )?;
+    }
+
+    Ok(read)
+}
+
+/// Returns how many bytes were written
+#[cfg(windows)]
+fn write_process_memory(address: Address, bytes: &[u8]) -> windows::core::Result<usize> {
+    let mut written = 0;
+
+    unsafe {
+        WriteProcessMemory(
+            GetCurrentProcess(),
+            address as *const c_void,
+            bytes.as_ptr() as *const c_void,
+            bytes.len(),
+            Some(&mut written),
+        )?;
+    }
+
+    // it might have been code. the cpu could still have the old one cached
+    _ = unsafe {
+        FlushInstructionCache(
+            GetCurrentProcess(),
+            Some(address as *const c_void),
+            bytes.len(),
+        )
+    };
+
+    Ok(written)
+}
+
+/// On linux, which only runs the tests. Unmapped pages fail here the same way, instead of crashing
+#[cfg(not(windows))]
+fn read_process_memory(address: Address, buf: &mut [u8]) -> io::Result<usize> {
+    File::open("/proc/self/mem")?.read_at(buf, address as u64)
+}
+
+/// Like `WriteProcessMemory`, this writes to read only pages too
+#[cfg(not(windows))]
+fn write_process_memory(address: Address, bytes: &[u8]) -> io::Result<usize> {
+    OpenOptions::new()
+        .write(true)
+        .open("/proc/self/mem")?
+        .write_at(bytes, address as u64)
+}
+
+struct Region {
+    base: Address,
+    data: Vec<u8>,
+    prot: Prot,
+}
+
+impl Region {
+    fn end(&self) -> Address {
+        self.base + self.data.len()
+    }
+}
+
+#[derive(Default)]
+struct Fake {
+    regions: Vec<Region>,
+    modules: Vec<ModuleInfo>,
+}
+
+impl Fake {
+    /// The region holding all of `address..address + len`
+    fn region(&mut self, address: Address, len: usize) -> Result<&mut Region> {
+        self.regions
+            .iter_mut()
+            .find(|r| address >= r.base && address + len <= r.end())
+            .ok_or_eyre(format!("{len} bytes at {address:#x} aren't mapped"))
+    }
+}
+
+/// A pretend process made of byte buffers, for running memory code without a game
+///
+/// Map some regions, optionally name a range of them as a module, and hand it to anything
+/// that takes a [`MemoryBackend`]. Protection is per region, and is enforced like the real thing:
+/// writing to a read-only region fails until it's protected as writable
+///
+/// ```ignore
+/// let memory = FakeMemory::new();
+/// memory.map(0x1000, vec![0x48, 0x8B, 0x05, 0x10, 0, 0, 0], Prot::XR);
+/// memory.add_module("game.exe", 0x1000, 0x1000);
+/// ```
+#[derive(Default)]
+pub struct FakeMemory {
+    fake: Mutex<Fake>,
+}
+
+impl FakeMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn fake(&self) -> MutexGuard<'_, Fake> {
+        self.fake.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Map `data` at `base`
+    pub fn map(&self, base: Address, data: Vec<u8>, prot: Prot) {
+        let mut fake = self.fake();
+        fake.regions.push(Region { base, data, prot });
+        fake.regions.sort_by_key(|r| r.base);
+    }
+
+    /// Name `base..base + size` as a module. Its sections are the regions inside of it
+    pub fn add_module(&self, name: &str, base: Address, size: usize) {
+        self.fake().modules.push(ModuleInfo {
+            name: name.to_owned(),
+            base,
+            size,
+        });
+    }
+
+    /// Copy of the bytes at `address`, no matter the protection. For checking what got written
+    pub fn peek(&self, address: Address, len: usize) -> Result<Vec<u8>> {
+        let mut fake = self.fake();
+        let region = fake.region(address, len)?;
+        let start = address - region.base;
+
+        Ok(region.data[start..start + len].to_vec())
+    }
+}
+
+impl MemoryBackend for FakeMemory {
+    fn read_bytes(&self, address: Address, buf: &mut [u8]) -> Result<()> {
+        let mut fake = self.fake();
+        let region = fake.region(address, buf.len())?;
+
+        if !is_readable(region.prot) {
+            bail!("{address:#x} isn't readable");
+        }
+
+        let start = address - region.base;
+        buf.copy_from_slice(&region.data[start..start + buf.len()]);
+
+        Ok(())
+    }
+
+    fn write_bytes(&self, address: Address, bytes: &[u8]) -> Result<()> {
+        let mut fake = self.fake();
+        let region = fake.region(address, bytes.len())?;
+
+        if !is_writable(region.prot) {
+            bail!("{address:#x} isn't writable");
+        }
+
+        let start = address - region.base;
+        region.data[start..start + bytes.len()].copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    /// Changes the protection of the whole region the range is in
+    fn protect(&self, address: Address, size: usize, prot: Prot) -> Result<Prot> {
+        let mut fake = self.fake();
+        let region = fake.region(address, size)?;
+
+        Ok(mem::replace(&mut region.prot, prot))
+    }
+
+    fn allocate(&self, size: usize, prot: Prot) -> Result<Address> {
+        let mut fake = self.fake();
+
+        // page aligned, right after everything else
+        let end = fake
+            .regions
+            .iter()
+            .map(Region::end)
+            .max()
+            .unwrap_or(0x10000);
+        let base = (end + 0xFFF) & !0xFFF;
+
+        fake.regions.push(Region {
+            base,
+            data: vec![0; size],
+            prot,
+        });
+
+        Ok(base)
+    }
+
+    fn free(&self, address: Address, _size: usize) -> Result<()> {
+        let mut fake = self.fake();
+
+        let idx = fake
+            .regions
+            .iter()
+            .position(|r| r.base == address)
+            .ok_or_eyre(format!("nothing allocated at {address:#x}"))?;
+
+        fake.regions.remove(idx);
+
+        Ok(())
+    }
+
+    fn modules(&self) -> Result<Vec<ModuleInfo>> {
+        Ok(self.fake().modules.clone())
+    }
+
+    fn sections(&self, module: &ModuleInfo) -> Result<Vec<Section>> {
+        Ok(self
+            .fake()
+            .regions
+            .iter()
+            .filter(|r| r.base >= module.base && r.end() <= module.end())
+            .map(|r| Section {
+                base: r.base,
+                size: r.data.len(),
+                prot: r.prot,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protection_is_enforced() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![0xC3; 0x10], Prot::XR);
+        memory.map(0x2000, vec![0; 0x10], Prot::W);
+
+        assert_eq!(memory.read::<u8>(0x1000).unwrap(), 0xC3);
+        assert!(memory.write_bytes(0x1000, &[0x90]).is_err());
+
+        // write only, like a guard page we can still fill
+        memory.write_bytes(0x2000, &[1, 2]).unwrap();
+        assert!(memory.read::<u16>(0x2000).is_err());
+        assert_eq!(memory.peek(0x2000, 2).unwrap(), [1, 2]);
+
+        assert_eq!(memory.protect(0x1000, 1, Prot::XRW).unwrap(), Prot::XR);
+        memory.write_bytes(0x1000, &[0x90]).unwrap();
+        assert_eq!(memory.read::<u8>(0x1000).unwrap(), 0x90);
+    }
+
+    #[test]
+    fn unmapped() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![0; 0x10], Prot::RW);
+
+        assert!(memory.read::<u8>(0xFFF).is_err());
+        assert!(memory.read::<u8>(0x1010).is_err());
+
+        // runs off the end of the region
+        assert!(memory.read::<u32>(0x100E).is_err());
+        assert!(memory.write::<u32>(0x100E, 0).is_err());
+        assert!(memory.protect(0x100E, 4, Prot::R).is_err());
+
+        assert_eq!(memory.read::<u16>(0x100E).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_write_unaligned() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![0; 0x20], Prot::RW);
+
+        memory.write::<u64>(0x1003, 0x1122_3344_5566_7788).unwrap();
+        memory.write::<f32>(0x1011, 1.5).unwrap();
+        memory.write::<[u16; 2]>(0x1018, [0xAABB, 0xCCDD]).unwrap();
+
+        assert_eq!(memory.read::<u64>(0x1003).unwrap(), 0x1122_3344_5566_7788);
+        assert_eq!(memory.read::<u8>(0x1003).unwrap(), 0x88);
+        assert_eq!(memory.read::<f32>(0x1011).unwrap(), 1.5);
+        assert_eq!(memory.peek(0x1018, 4).unwrap(), [0xBB, 0xAA, 0xDD, 0xCC]);
+    }
+
+    #[test]
+    fn write_code_restores_protection() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![0x74, 0x05], Prot::XR);
+
+        memory.write_code(0x1000, &[0x90, 0x90]).unwrap();
+
+        assert_eq!(memory.peek(0x1000, 2).unwrap(), [0x90, 0x90]);
+        assert!(memory.write_bytes(0x1000, &[0]).is_err());
+
+        // still fails on memory that isn't there
+        assert!(memory.write_code(0x2000, &[0x90]).is_err());
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![0; 0x10], Prot::RW);
+
+        let first = memory.allocate(0x10, Prot::RW).unwrap();
+        let second = memory.allocate(0x2000, Prot::R).unwrap();
+
+        assert_eq!(first, 0x2000);
+        assert_eq!(second, 0x3000);
+
+        memory.write::<u64>(first, 5).unwrap();
+        assert_eq!(memory.read::<u64>(first).unwrap(), 5);
+        assert!(memory.write::<u64>(second, 5).is_err());
+
+        memory.free(first, 0x10).unwrap();
+        assert!(memory.read::<u64>(first).is_err());
+
+        // only by base, and only once
+        assert!(memory.free(second + 0x10, 0x10).is_err());
+        assert!(memory.free(first, 0x10).is_err());
+    }
+
+    #[test]
+    fn modules_and_sections() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![b'M', b'Z'], Prot::R);
+        memory.map(0x2000, vec![0xC3; 0x10], Prot::XR);
+        memory.map(0x3000, vec![0; 0x10], Prot::RW);
+
+        // some other module
+        memory.map(0x9000, vec![0; 0x10], Prot::R);
+
+        memory.add_module("game.exe", 0x1000, 0x3000);
+
+        let game = memory.find_module("Game.EXE").unwrap();
+        assert_eq!(game.end(), 0x4000);
+        assert!(game.contains(0x3FFF));
+        assert!(!game.contains(0x4000));
+        assert!(memory.find_module("other.dll").is_none());
+
+        let sections = memory.sections(&game).unwrap();
+        assert_eq!(
+            sections.iter().map(|s| s.base).collect::<Vec<_>>(),
+            [0x1000, 0x2000, 0x3000]
+        );
+        assert!(sections[1].is_executable() && sections[1].is_readable());
+        assert!(!sections[2].is_executable());
+
+        assert_eq!(memory.section_bytes(&sections[1]).unwrap(), [0xC3; 0x10]);
+
+        // there are holes between the sections
+        assert!(memory.module_image(&game).is_err());
+
+        memory.protect(0x2000, 1, Prot::X).unwrap();
+        assert!(memory.section_bytes(&sections[1]).is_err());
+    }
+
+    #[test]
+    fn through_a_reference() {
+        let memory = FakeMemory::new();
+        memory.map(0x1000, vec![0; 8], Prot::RW);
+
+        fn write_through(memory: impl MemoryBackend) {
+            memory.write::<u32>(0x1004, 7).unwrap();
+        }
+
+        write_through(&memory);
+        assert_eq!(memory.read::<u32>(0x1004).unwrap(), 7);
+    }
+}
@@ -0,0 +1,574 @@
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::AtomicUsize,
+};
+
+use eyre::{Result, eyre};
+use libmem::{Address, Prot};
+use log::error;
+
+use crate::{
+    hooks::{self, HookHandle},
+    memory::{CurrentProcess, MemoryBackend},
+};
+
+/// One xmm register
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Xmm {
+    pub low: u64,
+    pub high: u64,
+}
+
+impl Xmm {
+    /// The scalar float in the low lane, e.g. a float argument in `xmm1`
+    pub fn f32(&self) -> f32 {
+        f32::from_bits(self.low as u32)
+    }
+
+    /// Set the low lane, leaving the rest alone
+    pub fn set_f32(&mut self, value: f32) {
+        self.low = (self.low & !0xFFFF_FFFF) | value.to_bits() as u64;
+    }
+
+    pub fn f64(&self) -> f64 {
+        f64::from_bits(self.low)
+    }
+
+    pub fn set_f64(&mut self, value: f64) {
+        self.low = value.to_bits();
+    }
+}
+
+/// The registers at the hooked instruction
+///
+/// Everything except `rsp` is written back when the callback returns, so changing a register
+/// here changes it for the rest of the function
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    pub xmm: [Xmm; 16],
+    pub rflags: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    /// Stack pointer at the hooked instruction. Read only; changing it does nothing
+    pub rsp: u64,
+}
+
+/// Machine code for a mid-function hook, see [`generate_stub`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stub {
+    pub code: Vec<u8>,
+    /// Offset of the pointer to the cell holding where to continue, see
+    /// [`HookHandle::original_cell`]. The stub spins while that's 0
+    pub cell_slot: usize,
+    /// Offset of the loop that reads the cell
+    pub spin: usize,
+}
+
+/// Generate the stub that goes between the hooked instruction and the callback
+///
+/// It saves every general purpose and xmm register into a [`Context`] on the stack, calls
+/// `callback(&mut Context, data)` with the stack aligned and shadow space reserved, loads the
+/// registers back, and continues at whatever the cell that [`Stub::cell_slot`] points at holds.
+///
+/// That's the hook's original: the trampoline while it's enabled, and the instruction itself once
+/// it isn't, however it got toggled. It's read after the callback, so a thread that was in the callback
+/// while the hook got disabled doesn't run into a freed trampoline. The slot is only written once,
+/// but it has to be aligned for that, which is why this needs `base`, where the stub is going to be.
+/// Nothing else depends on it, and the size only depends on its alignment
+pub fn generate_stub(base: Address, callback: Address, data: Address, cell: Address) -> Stub {
+    let mut code = Vec::with_capacity(512);
+
+    // pushed first so it ends up last in Context. `push rsp` pushes the value from before the push
+    code.push(0x54);
+
+    // push rax, rcx, rdx, rbx, rbp, rsi, rdi
+    code.extend_from_slice(&[0x50, 0x51, 0x52, 0x53, 0x55, 0x56, 0x57]);
+
+    // push r8 .. r15
+    for reg in 0..8 {
+        code.extend_from_slice(&[0x41, 0x50 + reg]);
+    }
+
+    // pushfq
+    code.push(0x9C);
+
+    // sub rsp, 256
+    code.extend_from_slice(&[0x48, 0x81, 0xEC, 0x00, 0x01, 0x00, 0x00]);
+
+    // movdqu [rsp + n * 16], xmmN. unaligned, the stack can be anything in the middle of a function
+    for reg in 0..16 {
+        xmm_move(&mut code, 0x7F, reg);
+    }
+
+    // mov rbx, rsp. rbx is callee saved, so it survives the call
+    code.extend_from_slice(&[0x48, 0x89, 0xE3]);
+
+    // and rsp, -16 / sub rsp, 32. the call needs an aligned stack and shadow space
+    code.extend_from_slice(&[0x48, 0x83, 0xE4, 0xF0]);
+    code.extend_from_slice(&[0x48, 0x83, 0xEC, 0x20]);
+
+    // mov rcx, rbx
+    code.extend_from_slice(&[0x48, 0x89, 0xD9]);
+
+    // mov rdx, data
+    code.extend_from_slice(&[0x48, 0xBA]);
+    code.extend_from_slice(&(data as u64).to_le_bytes());
+
+    // mov rax, callback / call rax
+    code.extend_from_slice(&[0x48, 0xB8]);
+    code.extend_from_slice(&(callback as u64).to_le_bytes());
+    code.extend_from_slice(&[0xFF, 0xD0]);
+
+    // spin: mov rax, [cell_slot] / mov rax, [rax]. 0 while the hook is being toggled
+    let spin = code.len();
+    code.extend_from_slice(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]);
+    code.extend_from_slice(&[0x48, 0x8B, 0x00]);
+
+    // test rax, rax / jnz +4 / pause / jmp spin
+    code.extend_from_slice(&[0x48, 0x85, 0xC0, 0x75, 0x04, 0xF3, 0x90, 0xEB]);
+    code.push((spin as isize - (code.len() + 1) as isize) as u8);
+
+    // mov [rbx + rsp], rax. the saved rsp is skipped on the way out anyways, so that's where we return to
+    let rsp = mem::offset_of!(Context, rsp) as u32;
+    code.extend_from_slice(&[0x48, 0x89, 0x83]);
+    code.extend_from_slice(&rsp.to_le_bytes());
+
+    // mov rsp, rbx
+    code.extend_from_slice(&[0x48, 0x89, 0xDC]);
+
+    // movdqu xmmN, [rsp + n * 16]
+    for reg in 0..16 {
+        xmm_move(&mut code, 0x6F, reg);
+    }
+
+    // add rsp, 256
+    code.extend_from_slice(&[0x48, 0x81, 0xC4, 0x00, 0x01, 0x00, 0x00]);
+
+    // popfq
+    code.push(0x9D);
+
+    // pop r15 .. r8
+    for reg in (0..8).rev() {
+        code.extend_from_slice(&[0x41, 0x58 + reg]);
+    }
+
+    // pop rdi, rsi, rbp, rbx, rdx, rcx, rax
+    code.extend_from_slice(&[0x5F, 0x5E, 0x5D, 0x5B, 0x5A, 0x59, 0x58]);
+
+    // ret. pops the address from the cell, which leaves rsp where it was at the hooked instruction
+    code.push(0xC3);
+
+    // aligned, so it's written in one go while other threads might be reading it
+    while !(base + code.len()).is_multiple_of(8) {
+        code.push(0xCC);
+    }
+
+    let cell_slot = code.len();
+    code.extend_from_slice(&(cell as u64).to_le_bytes());
+
+    // relative to the end of the load
+    let disp = (cell_slot - (spin + 7)) as i32;
+    code[spin + 3..spin + 7].copy_from_slice(&disp.to_le_bytes());
+
+    Stub {
+        code,
+        cell_slot,
+        spin,
+    }
+}
+
+/// `movdqu` between xmm`reg` and `[rsp + reg * 16]`. `0x7F` stores, `0x6F` loads
+fn xmm_move(code: &mut Vec<u8>, opcode: u8, reg: u8) {
+    code.push(0xF3);
+
+    // REX.R for xmm8 and up
+    if reg >= 8 {
+        code.push(0x44);
+    }
+
+    // modrm: disp32 with a SIB byte, then SIB: base rsp, no index
+    let modrm = 0x84 | ((reg & 7) << 3);
+    code.extend_from_slice(&[0x0F, opcode, modrm, 0x24]);
+    code.extend_from_slice(&(reg as u32 * 16).to_le_bytes());
+}
+
+type Callback = Box<dyn Fn(&mut Context) + Send + Sync>;
+
+struct Inner {
+    name: String,
+    callback: Callback,
+}
+
+/// Called by the stub, with the windows calling convention on linux too, where the tests run
+extern "win64" fn dispatch(ctx: &mut Context, inner: &Inner) {
+    // unwinding into the stub would be the end of the game
+    let result = panic::catch_unwind(AssertUnwindSafe(|| (inner.callback)(ctx)));
+
+    if let Err(e) = result {
+        // the panic hook already logged it. dropping the payload could panic again
+        error!("mid hook {} panicked", inner.name);
+        mem::forget(e);
+    }
+}
+
+/// What the stub waits on until the hook's own cell is known
+static INSTALLING: AtomicUsize = AtomicUsize::new(0);
+
+/// A hook in the middle of a function, that can read and change the registers there
+///
+/// Unhooks when dropped! Like [`hooks::install`], everything still hooked is unhooked on unload,
+/// and it can be enabled and disabled by name too. The stub and callback are never freed, since a
+/// thread can still be in them after unhooking
+///
+/// ```ignore
+/// // grab the pointer that's in rcx halfway through the function
+/// let hook = unsafe {
+///     mid_hook::install("grab world", address, |ctx| {
+///         WORLD.store(ctx.rcx as *mut World, Ordering::Release);
+///     })?
+/// };
+/// ```
+pub struct MidHook {
+    handle: HookHandle,
+}
+
+/// Call `callback` with the registers whenever the instruction at `address` is about to run
+///
+/// This is a normal hook (see [`hooks::install`]) whose detour is a stub that saves the registers,
+/// calls `callback`, and restores them. The instructions it overwrites are run from the
+/// trampoline afterwards, as usual. The game version check applies as well, but a jump at `address`
+/// isn't taken for someone else's hook
+///
+/// # Safety
+/// `address` must be the start of an instruction, and the instructions libmem needs to overwrite
+/// at it can't be jumped into from elsewhere in the function
+pub unsafe fn install(
+    name: &str,
+    address: Address,
+    callback: impl Fn(&mut Context) + Send + Sync + 'static,
+) -> Result<MidHook> {
+    let memory = CurrentProcess;
+
+    // the stub points at this for as long as it exists, which is forever once it's hooked
+    let inner: &'static Inner = Box::leak(Box::new(Inner {
+        name: name.to_owned(),
+        callback: Box::new(callback),
+    }));
+
+    // allocations are page aligned, and the size doesn't depend on anything else
+    let stub_len = generate_stub(0, 0, 0, 0).code.len();
+    let stub = memory.allocate(stub_len, Prot::XRW)?;
+
+    let dispatch: extern "win64" fn(&mut Context, &Inner) = dispatch;
+    let generated = generate_stub(
+        stub,
+        dispatch as Address,
+        inner as *const Inner as Address,
+        &INSTALLING as *const AtomicUsize as Address,
+    );
+
+    let written = memory
+        .write_bytes(stub, &generated.code)
+        .and_then(|_| memory.protect(stub, generated.code.len(), Prot::XR));
+
+    if let Err(e) = written {
+        // nothing ever jumped to it, so these are still ours
+        _ = memory.free(stub, stub_len);
+        drop(unsafe { Box::from_raw(inner as *const Inner as *mut Inner) });
+        return Err(eyre!("mid hook {name}: {e}"));
+    }
+
+    // a jump in the middle of a function is just code, not someone else's hook. if this fails,
+    // the stub and Inner stay leaked, we can't know if a thread got in before it was unhooked
+    let handle = unsafe { hooks::install_with_conflict_check(name, address, stub, false) }
+        .inspect_err(|_| point_at(stub + generated.cell_slot, address))?;
+
+    let cell = handle.original_cell() as *const AtomicUsize as Address;
+    memory.write_code(stub + generated.cell_slot, &cell.to_le_bytes())?;
+
+    Ok(MidHook { handle })
+}
+
+/// Let anyone waiting in the stub at `cell_slot` continue at `address`, for good
+fn point_at(cell_slot: Address, address: Address) {
+    let cell: &'static AtomicUsize = Box::leak(Box::new(AtomicUsize::new(address)));
+    let cell = cell as *const AtomicUsize as Address;
+
+    if let Err(e) = CurrentProcess.write_code(cell_slot, &cell.to_le_bytes()) {
+        error!("{e}");
+    }
+}
+
+impl MidHook {
+    pub fn name(&self) -> &str {
+        self.handle.name()
+    }
+
+    /// The hooked instruction
+    pub fn target(&self) -> Address {
+        self.handle.target()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.handle.is_enabled()
+    }
+
+    /// Hook the instruction again after it was disabled
+    pub fn enable(&self) -> Result<()> {
+        self.handle.enable()
+    }
+
+    /// Unhook the instruction, but keep the hook around to enable again later
+    pub fn disable(&self) -> Result<()> {
+        self.handle.disable()
+    }
+
+    /// Keep the hook installed until the plugin unloads, instead of until this is dropped
+    pub fn keep(self) {
+        self.handle.keep();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROLOGUE: &[u8] = &[
+        0x54, 0x50, 0x51, 0x52, 0x53, 0x55, 0x56, 0x57, 0x41, 0x50, 0x41, 0x51, 0x41, 0x52, 0x41,
+        0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57, 0x9C, 0x48, 0x81, 0xEC, 0x00, 0x01,
+        0x00, 0x00,
+    ];
+
+    /// From `add rsp, 256` up to the `ret`
+    const EPILOGUE: &[u8] = &[
+        0x48, 0x81, 0xC4, 0x00, 0x01, 0x00, 0x00, 0x9D, 0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41,
+        0x5C, 0x41, 0x5B, 0x41, 0x5A, 0x41, 0x59, 0x41, 0x58, 0x5F, 0x5E, 0x5D, 0x5B, 0x5A, 0x59,
+        0x58, 0xC3,
+    ];
+
+    /// From the `test rax, rax` after the loads up to the `mov rsp, rbx`
+    const SPIN: &[u8] = &[
+        0x48, 0x85, 0xC0, 0x75, 0x04, 0xF3, 0x90, 0xEB, 0xED, 0x48, 0x89, 0x83, 0x80, 0x01, 0x00,
+        0x00, 0x48, 0x89, 0xDC,
+    ];
+
+    fn find(code: &[u8], bytes: &[u8]) -> Vec<usize> {
+        code.windows(bytes.len())
+            .enumerate()
+            .filter(|(_, window)| *window == bytes)
+            .map(|(at, _)| at)
+            .collect()
+    }
+
+    #[test]
+    fn prologue_and_epilogue() {
+        let stub = generate_stub(0x1000, 0x1111_2222_3333_4444, 0x5555_6666_7777_8888, 0);
+
+        assert!(stub.code.starts_with(PROLOGUE));
+        assert_eq!(find(&stub.code, EPILOGUE).len(), 1);
+
+        // mov rdx, data / mov rax, callback / call rax
+        let mut call = vec![0x48, 0xBA];
+        call.extend_from_slice(&0x5555_6666_7777_8888u64.to_le_bytes());
+        call.extend_from_slice(&[0x48, 0xB8]);
+        call.extend_from_slice(&0x1111_2222_3333_4444u64.to_le_bytes());
+        call.extend_from_slice(&[0xFF, 0xD0]);
+        assert_eq!(find(&stub.code, &call).len(), 1);
+
+        // right after the call: load the cell, and wait for it to be set
+        assert_eq!(find(&stub.code, &call)[0] + call.len(), stub.spin);
+        assert_eq!(stub.code[stub.spin..stub.spin + 3], [0x48, 0x8B, 0x05]);
+        assert_eq!(stub.code[stub.spin + 7..stub.spin + 10], [0x48, 0x8B, 0x00]);
+        assert_eq!(find(&stub.code, SPIN), [stub.spin + 10]);
+
+        // the jmp goes back to the load
+        let jmp = stub.spin + 17;
+        assert_eq!(stub.code[jmp], 0xEB);
+        assert_eq!(
+            (jmp + 2).wrapping_add_signed(stub.code[jmp + 1] as i8 as isize),
+            stub.spin
+        );
+
+        // it writes where to continue over the saved rsp, which is what's left for the ret
+        assert_eq!(mem::offset_of!(Context, rsp), 0x180);
+    }
+
+    #[test]
+    fn xmm_moves() {
+        let encoded = |opcode, reg| {
+            let mut code = Vec::new();
+            xmm_move(&mut code, opcode, reg);
+            code
+        };
+
+        assert_eq!(
+            encoded(0x7F, 0),
+            [0xF3, 0x0F, 0x7F, 0x84, 0x24, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encoded(0x7F, 7),
+            [0xF3, 0x0F, 0x7F, 0xBC, 0x24, 0x70, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encoded(0x7F, 8),
+            [0xF3, 0x44, 0x0F, 0x7F, 0x84, 0x24, 0x80, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encoded(0x7F, 15),
+            [0xF3, 0x44, 0x0F, 0x7F, 0xBC, 0x24, 0xF0, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encoded(0x6F, 15),
+            [0xF3, 0x44, 0x0F, 0x6F, 0xBC, 0x24, 0xF0, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn pushes_match_context() {
+        let stub = generate_stub(0, 0, 0, 0);
+
+        // decode the pushes, in the order they happen
+        let mut pushed = Vec::new();
+        let mut code = &stub.code[..];
+        loop {
+            let (reg, len) = match code {
+                [0x9C, ..] => ("rflags", 1),
+                [0x41, op @ 0x50..=0x57, ..] => (
+                    ["r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"][*op as usize - 0x50],
+                    2,
+                ),
+                [op @ 0x50..=0x57, ..] => (
+                    ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi"][*op as usize - 0x50],
+                    1,
+                ),
+                _ => break,
+            };
+
+            pushed.push(reg);
+            code = &code[len..];
+        }
+
+        // then room for the xmm registers, right below the last push
+        assert_eq!(code[..7], [0x48, 0x81, 0xEC, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(mem::offset_of!(Context, xmm), 0);
+        assert_eq!(mem::size_of::<[Xmm; 16]>(), 256);
+
+        assert_eq!(pushed.len(), 17);
+        assert_eq!(mem::size_of::<Context>(), 256 + 17 * 8);
+
+        for (i, reg) in pushed.iter().enumerate() {
+            let offset = match *reg {
+                "rflags" => mem::offset_of!(Context, rflags),
+                "r15" => mem::offset_of!(Context, r15),
+                "r14" => mem::offset_of!(Context, r14),
+                "r13" => mem::offset_of!(Context, r13),
+                "r12" => mem::offset_of!(Context, r12),
+                "r11" => mem::offset_of!(Context, r11),
+                "r10" => mem::offset_of!(Context, r10),
+                "r9" => mem::offset_of!(Context, r9),
+                "r8" => mem::offset_of!(Context, r8),
+                "rdi" => mem::offset_of!(Context, rdi),
+                "rsi" => mem::offset_of!(Context, rsi),
+                "rbp" => mem::offset_of!(Context, rbp),
+                "rbx" => mem::offset_of!(Context, rbx),
+                "rdx" => mem::offset_of!(Context, rdx),
+                "rcx" => mem::offset_of!(Context, rcx),
+                "rax" => mem::offset_of!(Context, rax),
+                "rsp" => mem::offset_of!(Context, rsp),
+                _ => unreachable!(),
+            };
+
+            // the first push ends up highest
+            assert_eq!(offset, 256 + (16 - i) * 8, "{reg}");
+        }
+    }
+
+    #[test]
+    fn cell_slot() {
+        let page = generate_stub(0, 0, 0, 0);
+
+        for base in [0, 1, 3, 7, 0x1000, 0x7FF6_1234_5677] {
+            let stub = generate_stub(base, 0, 0, 0x1122_3344_5566_7788);
+
+            assert_eq!((base + stub.cell_slot) % 8, 0, "{base:#x}");
+            assert_eq!(stub.code.len(), stub.cell_slot + 8);
+            assert_eq!(
+                stub.code[stub.cell_slot..],
+                0x1122_3344_5566_7788u64.to_le_bytes()
+            );
+
+            // only the padding and the load over it depend on the base, and there's never more than on a page
+            let load = stub.spin + 3..stub.spin + 7;
+            assert_eq!(stub.code[..load.start], page.code[..load.start]);
+            let end = find(&stub.code, EPILOGUE)[0] + EPILOGUE.len();
+            assert_eq!(stub.code[load.end..end], page.code[load.end..end]);
+            assert!(stub.code[end..stub.cell_slot].iter().all(|b| *b == 0xCC));
+            assert!(stub.code.len() <= page.code.len() + 7);
+
+            // rip relative, from the end of the load
+            let disp = i32::from_le_bytes(stub.code[load.clone()].try_into().unwrap());
+            assert_eq!(load.end.wrapping_add_signed(disp as isize), stub.cell_slot);
+        }
+    }
+
+    /// `mov rax, rcx`, some room for the hook, `ret`
+    fn double_me() -> Address {
+        let memory = CurrentProcess;
+
+        let mut code = vec![0x48, 0x89, 0xC8];
+        code.extend_from_slice(&[0x90; 16]);
+        code.push(0xC3);
+
+        let address = memory.allocate(code.len(), Prot::RW).unwrap();
+        memory.write_bytes(address, &code).unwrap();
+        memory.protect(address, code.len(), Prot::XR).unwrap();
+
+        address
+    }
+
+    #[test]
+    fn toggled_by_name() {
+        let address = double_me();
+        let f: extern "win64" fn(u64) -> u64 = unsafe { mem::transmute(address) };
+
+        assert_eq!(f(21), 21);
+
+        let hook = unsafe { install("mid hook by name", address, |ctx| ctx.rcx *= 2).unwrap() };
+        assert_eq!(f(21), 42);
+
+        // the stub has to follow, the trampoline it would've continued to is gone
+        hooks::disable("mid hook by name").unwrap();
+        assert!(!hook.is_enabled());
+        assert_eq!(f(21), 21);
+
+        hooks::enable("mid hook by name").unwrap();
+        assert!(hook.is_enabled());
+        assert_eq!(f(21), 42);
+
+        hook.disable().unwrap();
+        assert_eq!(f(21), 21);
+
+        hook.enable().unwrap();
+        assert_eq!(f(21), 42);
+
+        drop(hook);
+        assert_eq!(f(21), 21);
+        assert!(!hooks::is_enabled("mid hook by name"));
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+#[cfg(windows)]
+use std::{fs::File, os::windows::io::AsRawHandle, sync::OnceLock};
+
+#[cfg(windows)]
+use eyre::Result;
+use log::warn;
+#[cfg(windows)]
+use log::{error, info};
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{FALSE, HANDLE, HINSTANCE},
+    System::{
+        Diagnostics::Debug::{
+            EXCEPTION_POINTERS, MINIDUMP_EXCEPTION_INFORMATION, MiniDumpWithDataSegs,
+            MiniDumpWithIndirectlyReferencedMemory, MiniDumpWithThreadInfo, MiniDumpWriteDump,
+        },
+        Threading::{GetCurrentProcess, GetCurrentProcessId, GetCurrentThreadId},
+    },
+};
+
+#[cfg(windows)]
+use crate::{logging::session_id, paths::get_dll_logs_dir};
+
+/// Prefix of every dump we write, so retention never touches anyone else's files
+const PREFIX: &str = "my-plugin-";
+
+#[cfg(windows)]
+struct Dumps {
+    dir: PathBuf,
+    max: usize,
+}
+
+#[cfg(windows)]
+static DUMPS: OnceLock<Dumps> = OnceLock::new();
+
+/// Enable writing minidumps to `<dll_dir>\logs\` whenever the panic hook or exception handler fires.
+/// Dumps are named after the log session, so `my-plugin-<session>.dmp` belongs to the log lines
+/// tagged with the same session.
+///
+/// Only the newest `max` dumps are kept around, older ones are deleted. These files are big!
+///
+/// Is safe to call multiple times since subsequent calls are noops
+#[cfg(windows)]
+pub fn enable(module: HINSTANCE, max: usize) -> Result<()> {
+    if DUMPS.get().is_some() {
+        return Ok(());
+    }
+
+    let dir = get_dll_logs_dir(module)?;
+    enforce_retention(&dir, max);
+
+    _ = DUMPS.set(Dumps { dir, max });
+
+    Ok(())
+}
+
+/// Write a minidump for the current process, if minidumps are enabled
+///
+/// Pass the exception pointers if you have them (from an exception handler),
+/// then the dump will open right at the faulting instruction
+#[cfg(windows)]
+pub fn write_dump(exception: Option<*mut EXCEPTION_POINTERS>) {
+    let Some(dumps) = DUMPS.get() else {
+        return;
+    };
+
+    let path = dump_path(&dumps.dir, session_id());
+
+    match write_dump_to(&path, exception) {
+        Ok(_) => info!("wrote minidump to {}", path.display()),
+        Err(e) => error!("failed to write minidump to {}: {e}", path.display()),
+    }
+
+    enforce_retention(&dumps.dir, dumps.max);
+}
+
+#[cfg(windows)]
+fn write_dump_to(path: &Path, exception: Option<*mut EXCEPTION_POINTERS>) -> Result<()> {
+    let file = File::create(path)?;
+
+    let info = exception.map(|pointers| MINIDUMP_EXCEPTION_INFORMATION {
+        ThreadId: unsafe { GetCurrentThreadId() },
+        ExceptionPointers: pointers,
+        ClientPointers: FALSE,
+    });
+
+    unsafe {
+        MiniDumpWriteDump(
+            GetCurrentProcess(),
+            GetCurrentProcessId(),
+            HANDLE(file.as_raw_handle()),
+            MiniDumpWithDataSegs | MiniDumpWithIndirectlyReferencedMemory | MiniDumpWithThreadInfo,
+            info.as_ref().map(|i| i as *const _),
+            None,
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// `<dir>\my-plugin-<session>.dmp`, or `my-plugin-<session>-<n>.dmp` if the session already has one
+fn dump_path(dir: &Path, session: &str) -> PathBuf {
+    let mut path = dir.join(format!("{PREFIX}{session}.dmp"));
+
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{PREFIX}{session}-{n}.dmp"));
+        n += 1;
+    }
+
+    path
+}
+
+/// Delete the oldest dumps until only `max` are left
+fn enforce_retention(dir: &Path, max: usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut dumps = entries
+        .filter_map(Result::ok)
+        .filter(|e| {
+            let name = e.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with(PREFIX) && name.ends_with(".dmp")
+        })
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect::<Vec<_>>();
+
+    if dumps.len() <= max {
+        return;
+    }
+
+    // oldest first
+    dumps.sort_by_key(|(modified, _)| *modified);
+
+    let excess = dumps.len() - max;
+    for (_, path) in dumps.into_iter().take(excess) {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("failed to remove old minidump {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+
+    /// A fresh, empty directory for one test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minidump-{name}-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn numbered_when_taken() {
+        let dir = temp_dir("numbered");
+
+        for expected in [
+            "my-plugin-session.dmp",
+            "my-plugin-session-1.dmp",
+            "my-plugin-session-2.dmp",
+        ] {
+            let path = dump_path(&dir, "session");
+            assert_eq!(path, dir.join(expected));
+
+            File::create(path).unwrap();
+        }
+
+        // another session starts over
+        assert_eq!(dump_path(&dir, "other"), dir.join("my-plugin-other.dmp"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        let dir = temp_dir("retention");
+        let epoch = SystemTime::now() - Duration::from_secs(3600);
+
+        // oldest first. only ours count, and the foreign ones are even older
+        let files = [
+            "someone-else.dmp",
+            "my-plugin-notes.txt",
+            "my-plugin-a.dmp",
+            "my-plugin-b-1.dmp",
+            "my-plugin-c.dmp",
+            "my-plugin-d.dmp",
+        ];
+
+        for (i, name) in files.iter().enumerate() {
+            let file = File::create(dir.join(name)).unwrap();
+            file.set_modified(epoch + Duration::from_secs(i as u64 * 60))
+                .unwrap();
+        }
+
+        enforce_retention(&dir, 4);
+        assert_eq!(names(&dir).len(), files.len());
+
+        enforce_retention(&dir, 2);
+        assert_eq!(
+            names(&dir),
+            [
+                "my-plugin-c.dmp",
+                "my-plugin-d.dmp",
+                "my-plugin-notes.txt",
+                "someone-else.dmp"
+            ]
+        );
+
+        enforce_retention(&dir, 0);
+        assert_eq!(names(&dir), ["my-plugin-notes.txt", "someone-else.dmp"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_dir() {
+        // nothing to clean up, and nothing to fail
+        enforce_retention(Path::new("/definitely/not/a/dir"), 0);
+    }
+}
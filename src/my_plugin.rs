@@ -0,0 +1,41 @@
+use eyre::Result;
+// this imports all of libmem's functions so you can use them
+// alternatively, you can import the specific ones you want to use
+// instead of a glob import
+#[allow(unused_imports)]
+use libmem::*;
+use log::info;
+
+use crate::{
+    plugin::{Context, Plugin},
+    popup::{MessageBoxIcon, display_popup},
+};
+
+/// All of our main plugin code goes here!
+///
+/// Recommend to catch and handle potential panics instead of panicking; log instead, it's much cleaner
+///
+/// You can use tracing for logging if you prefer a much higher quality logger, but its api is also
+/// much more complex, and as such is harder to learn
+#[derive(Default)]
+pub struct MyPlugin;
+
+impl Plugin for MyPlugin {
+    fn on_load(&self, ctx: &Context) -> Result<()> {
+        // Show the hook was injected. DO NOT popup in production code! This is just for a POC
+        display_popup(
+            "Success",
+            "Plugin successfully injected",
+            MessageBoxIcon::Information,
+        );
+
+        // the config is already loaded from `<dll_dir>\my-config.toml`
+        let _config = ctx.config();
+        // TODO: Do something with config
+
+        // TODO: Implement libmem/memory lib hooking logic, e.g. with `hooks::install` or `typed_hook::hook!`
+        info!("nothing to hook yet");
+
+        Ok(())
+    }
+}
@@ -1,7 +1,13 @@
-use std::{panic, sync::Once};
+use std::{
+    collections::HashSet,
+    panic,
+    sync::{Mutex, Once},
+};
 
 use log::error;
 
+use crate::minidump;
+
 #[cfg(debug_assertions)]
 use crate::backtrace::CaptureBacktrace;
 
@@ -29,6 +35,22 @@ pub fn set_hook() {
 
             // Dump panic info to logfile
             error!("{message}");
+
+            // plenty of panics are caught and survived, e.g. in a detour that runs every frame.
+            // one dump per place that panics is all anyone needs, and it keeps those from pushing
+            // the one dump that matters out of the retention limit
+            if first_at(info.location().map(ToString::to_string)) {
+                // noop unless minidumps were enabled
+                minidump::write_dump(None);
+            }
         }));
     });
 }
+
+/// Whether this is the first panic at `location` this session
+fn first_at(location: Option<String>) -> bool {
+    static SEEN: Mutex<Option<HashSet<Option<String>>>> = Mutex::new(None);
+
+    let mut seen = SEEN.lock().unwrap_or_else(|e| e.into_inner());
+    seen.get_or_insert_default().insert(location)
+}
@@ -0,0 +1,546 @@
+use std::{
+    fmt::Write as _,
+    mem,
+    sync::{Arc, Mutex, Once},
+};
+
+use eyre::{OptionExt as _, Result, bail, eyre};
+use libmem::Address;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    exception_handler, game_version,
+    memory::{CurrentProcess, MemoryBackend},
+    pattern::Pattern,
+    pointer_chain::PointerChain,
+    shutdown::{self, Detach},
+    signatures::Signatures,
+};
+
+/// The game's patches
+static PATCHES: PatchRegistry = PatchRegistry::new(CurrentProcess);
+
+/// One `[[patches]]` entry in the config
+///
+/// ```toml
+/// [[patches]]
+/// name = "skip intro"
+/// enabled = true
+/// # where to patch. either a signature from my-signatures.toml, or an address
+/// signature = "IntroCheck"
+/// # address = "game.exe+0x1234"
+/// # added to the address
+/// offset = 2
+/// # what has to be there before patching. `??` matches anything
+/// original = "74 ??"
+/// patch = "90 90"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchConfig {
+    pub name: String,
+    /// Turned off patches are still checked and registered, so they can be turned on at runtime
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<PointerChain>,
+    #[serde(default)]
+    pub offset: isize,
+    pub original: String,
+    pub patch: String,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+struct Patch {
+    name: String,
+    address: Address,
+    /// What was there before we touched anything
+    original: Vec<u8>,
+    patched: Vec<u8>,
+    enabled: Mutex<bool>,
+}
+
+impl Patch {
+    fn set_enabled(&self, memory: &impl MemoryBackend, enable: bool) -> Result<()> {
+        let mut enabled = self.enabled.lock().unwrap_or_else(|e| e.into_inner());
+        if *enabled == enable {
+            return Ok(());
+        }
+
+        let bytes = if enable {
+            &self.patched
+        } else {
+            &self.original
+        };
+
+        memory
+            .write_code(self.address, bytes)
+            .map_err(|e| eyre!("patch {}: {e}", self.name))?;
+
+        *enabled = enable;
+
+        // a crash in our bytes is most likely our fault
+        let range = self.address..self.address + bytes.len();
+        if enable {
+            exception_handler::watch_region(range);
+        } else {
+            exception_handler::unwatch_region(&range);
+        }
+
+        debug!(
+            "{} patch {} at {:#x}",
+            if enable { "enabled" } else { "disabled" },
+            self.name,
+            self.address
+        );
+
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        *self.enabled.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A set of byte patches, applied through a [`MemoryBackend`]
+///
+/// You normally want the global one behind [`install`], [`enable`], [`disable`] etc, which patches
+/// the game and is restored on unload. A separate registry over `FakeMemory` can patch a byte buffer
+pub struct PatchRegistry<M: MemoryBackend = CurrentProcess> {
+    memory: M,
+    /// In install order
+    patches: Mutex<Vec<Arc<Patch>>>,
+}
+
+impl<M: MemoryBackend> PatchRegistry<M> {
+    pub const fn new(memory: M) -> Self {
+        Self {
+            memory,
+            patches: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// See [`install`]
+    ///
+    /// # Safety
+    /// See [`install`]
+    pub unsafe fn install(
+        &self,
+        name: &str,
+        address: Address,
+        expected: &Pattern,
+        bytes: &[u8],
+        enable: bool,
+    ) -> Result<()> {
+        if expected.len() != bytes.len() {
+            bail!(
+                "patch {name}: expected {} original bytes, but the patch is {} bytes",
+                expected.len(),
+                bytes.len()
+            );
+        }
+
+        let mut patches = self.patches.lock().unwrap_or_else(|e| e.into_inner());
+
+        if patches.iter().any(|p| p.name == name) {
+            bail!("a patch named {name} is already installed");
+        }
+
+        if let Some(other) = patches
+            .iter()
+            .find(|p| address < p.address + p.original.len() && p.address < address + bytes.len())
+        {
+            bail!("patch {name} at {address:#x} overlaps patch {}", other.name);
+        }
+
+        let mut original = vec![0; bytes.len()];
+        self.memory
+            .read_bytes(address, &mut original)
+            .map_err(|e| eyre!("patch {name}: {e}"))?;
+
+        if !expected.matches_at(&original, 0) {
+            bail!(
+                "patch {name}: expected `{expected}` at {address:#x} but found `{}`. did the game update?",
+                hex(&original)
+            );
+        }
+
+        let patch = Arc::new(Patch {
+            name: name.to_owned(),
+            address,
+            original,
+            patched: bytes.to_vec(),
+            enabled: Mutex::new(false),
+        });
+
+        if enable {
+            patch.set_enabled(&self.memory, true)?;
+        }
+
+        info!(
+            "installed patch {name} at {address:#x} ({})",
+            if enable { "enabled" } else { "disabled" }
+        );
+
+        patches.push(patch);
+
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<Patch>> {
+        let patches = self.patches.lock().unwrap_or_else(|e| e.into_inner());
+
+        patches
+            .iter()
+            .find(|p| p.name == name)
+            .cloned()
+            .ok_or_eyre(format!("no patch named {name}"))
+    }
+
+    pub fn enable(&self, name: &str) -> Result<()> {
+        self.find(name)?.set_enabled(&self.memory, true)
+    }
+
+    pub fn disable(&self, name: &str) -> Result<()> {
+        self.find(name)?.set_enabled(&self.memory, false)
+    }
+
+    pub fn toggle(&self, name: &str) -> Result<bool> {
+        let patch = self.find(name)?;
+        let enable = !patch.is_enabled();
+        patch.set_enabled(&self.memory, enable)?;
+
+        Ok(enable)
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.find(name).is_ok_and(|p| p.is_enabled())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let patches = self.patches.lock().unwrap_or_else(|e| e.into_inner());
+        patches.iter().map(|p| p.name.clone()).collect()
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let patch = self.find(name)?;
+        patch.set_enabled(&self.memory, false)?;
+
+        let mut patches = self.patches.lock().unwrap_or_else(|e| e.into_inner());
+        patches.retain(|p| !Arc::ptr_eq(p, &patch));
+
+        Ok(())
+    }
+
+    pub fn restore_all(&self) {
+        let patches = match self.patches.lock() {
+            Ok(mut patches) => mem::take(&mut *patches),
+            Err(_) => return,
+        };
+
+        for patch in patches.into_iter().rev() {
+            if let Err(e) = patch.set_enabled(&self.memory, false) {
+                error!("{e}");
+            }
+        }
+    }
+}
+
+/// Register a byte patch at `address` and turn it on if `enable` is set
+///
+/// The bytes at `address` have to match `expected` first. If they don't, the game most likely
+/// updated and the patch would write garbage over some other code, so nothing is written.
+/// The bytes that were really there are kept, to put back when the patch is disabled or removed,
+/// and on unload.
+///
+/// Fails if the game version check refused this version of the game
+///
+/// ```ignore
+/// // jz -> nop nop, so the intro is never skipped over
+/// let expected = Pattern::parse("74 ??")?;
+/// unsafe { patches::install("skip intro", address + 2, &expected, &[0x90, 0x90], true)? };
+///
+/// // later, e.g. from a hotkey
+/// patches::toggle("skip intro")?;
+/// ```
+///
+/// # Safety
+/// `address` must be where `bytes` are meant to go, and nothing may be executing those bytes
+/// while they're written
+pub unsafe fn install(
+    name: &str,
+    address: Address,
+    expected: &Pattern,
+    bytes: &[u8],
+    enable: bool,
+) -> Result<()> {
+    game_version::ensure_allowed(&format!("patch {name}"))?;
+    register_teardown();
+
+    unsafe { PATCHES.install(name, address, expected, bytes, enable) }
+}
+
+/// Install every patch from the config. `signatures` is needed for the ones that use `signature = `
+///
+/// A patch that fails doesn't stop the others; it's logged and skipped
+///
+/// ```ignore
+/// let signatures = Signatures::load(ctx.module())?;
+/// patches::load(&ctx.config().patches, Some(&signatures));
+/// ```
+pub fn load(config: &[PatchConfig], signatures: Option<&Signatures>) {
+    for patch in config {
+        if let Err(e) = load_one(patch, signatures) {
+            error!("{e}");
+        }
+    }
+}
+
+fn load_one(config: &PatchConfig, signatures: Option<&Signatures>) -> Result<()> {
+    let name = &config.name;
+
+    let address = match (&config.signature, &config.address) {
+        (Some(signature), None) => signatures
+            .ok_or_eyre(format!(
+                "patch {name}: no signatures to find {signature} in"
+            ))?
+            .get(signature)?,
+
+        (None, Some(address)) => address.resolve(&CurrentProcess)?,
+
+        _ => bail!("patch {name}: needs exactly one of `signature` or `address`"),
+    };
+
+    let address = address.wrapping_add_signed(config.offset);
+    let expected = Pattern::parse(&config.original)?;
+    let bytes = parse_bytes(&config.patch)?;
+
+    // there's no telling what the game is doing. but the config is loaded early on,
+    // which is the best time to patch anything
+    unsafe { install(name, address, &expected, &bytes, config.enabled) }
+}
+
+/// `90 90 EB`
+fn parse_bytes(s: &str) -> Result<Vec<u8>> {
+    s.split_whitespace()
+        .map(|byte| {
+            u8::from_str_radix(byte, 16).map_err(|_| eyre!("invalid byte `{byte}` in `{s}`"))
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for (idx, byte) in bytes.iter().enumerate() {
+        if idx > 0 {
+            s.push(' ');
+        }
+
+        _ = write!(s, "{byte:02X}");
+    }
+
+    s
+}
+
+/// Write the patched bytes
+pub fn enable(name: &str) -> Result<()> {
+    PATCHES.enable(name)
+}
+
+/// Put the original bytes back, but keep the patch around to enable again later
+pub fn disable(name: &str) -> Result<()> {
+    PATCHES.disable(name)
+}
+
+/// Flip a patch on or off. Returns whether it's on now
+pub fn toggle(name: &str) -> Result<bool> {
+    PATCHES.toggle(name)
+}
+
+pub fn is_enabled(name: &str) -> bool {
+    PATCHES.is_enabled(name)
+}
+
+/// Names of every installed patch, in install order
+pub fn names() -> Vec<String> {
+    PATCHES.names()
+}
+
+/// Put the original bytes back and forget about the patch
+pub fn remove(name: &str) -> Result<()> {
+    PATCHES.remove(name)
+}
+
+/// Restore the original bytes of every patch, in reverse install order
+pub fn restore_all() {
+    PATCHES.restore_all();
+}
+
+fn register_teardown() {
+    static TEARDOWN: Once = Once::new();
+
+    TEARDOWN.call_once(|| {
+        shutdown::register("patches", |reason| {
+            // the process is going away, nobody will notice
+            if reason == Detach::ProcessExit {
+                return;
+            }
+
+            // the game keeps running without us, leave it how we found it
+            restore_all();
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    const CODE: Address = 0x1000;
+
+    /// `jz +5; ret; int3..` at [`CODE`], read only like real code
+    fn registry() -> PatchRegistry<FakeMemory> {
+        let memory = FakeMemory::new();
+        memory.map(CODE, vec![0x74, 0x05, 0xC3, 0xCC, 0xCC, 0xCC], Prot::XR);
+
+        PatchRegistry::new(memory)
+    }
+
+    fn code(registry: &PatchRegistry<FakeMemory>) -> Vec<u8> {
+        registry.memory.peek(CODE, 6).unwrap()
+    }
+
+    fn install(
+        registry: &PatchRegistry<FakeMemory>,
+        name: &str,
+        address: Address,
+        expected: &str,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let expected = Pattern::parse(expected).unwrap();
+        unsafe { registry.install(name, address, &expected, bytes, true) }
+    }
+
+    #[test]
+    fn apply_and_restore() {
+        let registry = registry();
+
+        install(&registry, "nop jz", CODE, "74 ??", &[0x90, 0x90]).unwrap();
+
+        assert!(registry.is_enabled("nop jz"));
+        assert_eq!(code(&registry), [0x90, 0x90, 0xC3, 0xCC, 0xCC, 0xCC]);
+
+        // put the protection back
+        assert!(registry.memory.write_bytes(CODE, &[0]).is_err());
+
+        registry.restore_all();
+
+        assert!(registry.names().is_empty());
+        assert_eq!(code(&registry), [0x74, 0x05, 0xC3, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn installed_disabled() {
+        let registry = registry();
+        let expected = Pattern::parse("C3").unwrap();
+
+        unsafe { registry.install("ret", CODE + 2, &expected, &[0xCC], false) }.unwrap();
+
+        assert!(!registry.is_enabled("ret"));
+        assert_eq!(registry.names(), ["ret"]);
+        assert_eq!(code(&registry)[2], 0xC3);
+
+        registry.enable("ret").unwrap();
+        assert_eq!(code(&registry)[2], 0xCC);
+    }
+
+    #[test]
+    fn original_mismatch() {
+        let registry = registry();
+
+        let err = install(&registry, "wrong", CODE, "75 ??", &[0x90, 0x90]).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "patch wrong: expected `75 ??` at 0x1000 but found `74 05`. did the game update?"
+        );
+        assert!(registry.names().is_empty());
+        assert_eq!(code(&registry)[..2], [0x74, 0x05]);
+    }
+
+    #[test]
+    fn rejected_patches() {
+        let registry = registry();
+
+        install(&registry, "nop jz", CODE, "74 ??", &[0x90, 0x90]).unwrap();
+
+        // size mismatch
+        assert!(install(&registry, "short", CODE + 3, "CC CC", &[0x90]).is_err());
+
+        // same name
+        assert!(install(&registry, "nop jz", CODE + 3, "CC", &[0x90]).is_err());
+
+        // overlaps the first one
+        assert!(install(&registry, "overlap", CODE + 1, "?? C3", &[0x90, 0x90]).is_err());
+
+        // not mapped
+        assert!(install(&registry, "unmapped", 0x9000, "??", &[0x90]).is_err());
+
+        // right after it is fine
+        install(&registry, "ret", CODE + 2, "C3", &[0xCC]).unwrap();
+
+        assert_eq!(registry.names(), ["nop jz", "ret"]);
+    }
+
+    #[test]
+    fn toggle() {
+        let registry = registry();
+
+        install(&registry, "nop jz", CODE, "74 ??", &[0x90, 0x90]).unwrap();
+
+        assert!(!registry.toggle("nop jz").unwrap());
+        assert_eq!(code(&registry)[..2], [0x74, 0x05]);
+
+        // disabling twice does nothing
+        registry.disable("nop jz").unwrap();
+        assert_eq!(code(&registry)[..2], [0x74, 0x05]);
+
+        assert!(registry.toggle("nop jz").unwrap());
+        assert_eq!(code(&registry)[..2], [0x90, 0x90]);
+
+        assert!(registry.toggle("missing").is_err());
+        assert!(!registry.is_enabled("missing"));
+    }
+
+    #[test]
+    fn remove() {
+        let registry = registry();
+
+        install(&registry, "nop jz", CODE, "74 ??", &[0x90, 0x90]).unwrap();
+        registry.remove("nop jz").unwrap();
+
+        assert!(registry.names().is_empty());
+        assert_eq!(code(&registry)[..2], [0x74, 0x05]);
+
+        // can go right back in
+        install(&registry, "nop jz", CODE, "74 ??", &[0x90, 0x90]).unwrap();
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse_bytes("90 90 eb").unwrap(), [0x90, 0x90, 0xEB]);
+        assert!(parse_bytes("90 9G").is_err());
+        assert!(parse_bytes("90 ??").is_err());
+
+        assert_eq!(hex(&[0x74, 0x05, 0xFF]), "74 05 FF");
+        assert_eq!(hex(&[]), "");
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    fmt::{self, Display},
+    iter,
+    str::FromStr,
+};
+
+use eyre::{OptionExt as _, Result, bail, eyre};
+use libmem::Address;
+use memchr::memmem;
+
+use crate::memory::MemoryBackend;
+
+/// A byte signature with wildcards, e.g. `48 8B ?? ?? 89`
+///
+/// Parses IDA-style signatures (`?` and `??` are wildcards) with [`Pattern::parse`],
+/// and code-style ones (`"\x48\x8B\x00\x00\x89"` + `"xx??x"`) with [`Pattern::from_code`].
+///
+/// Scanning works on any byte slice. The longest run of non-wildcard bytes is searched for
+/// first with a SIMD accelerated substring search, and only those hits get checked fully
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    /// `None` is a wildcard
+    bytes: Vec<Option<u8>>,
+    /// The longest run of non-wildcard bytes, and its offset into the pattern
+    anchor: Vec<u8>,
+    anchor_offset: usize,
+}
+
+impl Pattern {
+    /// Parse an IDA-style signature, e.g. `48 8B ?? ? 89`
+    pub fn parse(signature: &str) -> Result<Self> {
+        let bytes = signature
+            .split_whitespace()
+            .map(|token| match token {
+                "?" | "??" => Ok(None),
+                _ if token.len() == 2 => u8::from_str_radix(token, 16)
+                    .map(Some)
+                    .map_err(|_| eyre!("invalid byte `{token}` in pattern `{signature}`")),
+                _ => Err(eyre!("invalid byte `{token}` in pattern `{signature}`")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::new(bytes)
+    }
+
+    /// Build a pattern from code-style bytes and mask. `x` in the mask must match, `?` is a wildcard
+    pub fn from_code(bytes: &[u8], mask: &str) -> Result<Self> {
+        if bytes.len() != mask.len() {
+            bail!(
+                "pattern has {} bytes but mask `{mask}` has {}",
+                bytes.len(),
+                mask.len()
+            );
+        }
+
+        let bytes = bytes
+            .iter()
+            .zip(mask.chars())
+            .map(|(&byte, m)| match m {
+                'x' => Ok(Some(byte)),
+                '?' => Ok(None),
+                _ => Err(eyre!("invalid character `{m}` in mask `{mask}`")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::new(bytes)
+    }
+
+    fn new(bytes: Vec<Option<u8>>) -> Result<Self> {
+        if bytes.is_empty() {
+            bail!("pattern is empty");
+        }
+
+        let (mut anchor_offset, mut anchor_len) = (0, 0);
+        let mut run_start = 0;
+        for (idx, byte) in bytes.iter().enumerate() {
+            if byte.is_none() {
+                run_start = idx + 1;
+            } else if idx + 1 - run_start > anchor_len {
+                (anchor_offset, anchor_len) = (run_start, idx + 1 - run_start);
+            }
+        }
+
+        let anchor = bytes[anchor_offset..anchor_offset + anchor_len]
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+
+        Ok(Self {
+            bytes,
+            anchor,
+            anchor_offset,
+        })
+    }
+
+    /// Length in bytes
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether the pattern matches `data` starting at `pos`
+    pub fn matches_at(&self, data: &[u8], pos: usize) -> bool {
+        let Some(window) = data.get(pos..pos + self.len()) else {
+            return false;
+        };
+
+        self.bytes
+            .iter()
+            .zip(window)
+            .all(|(p, b)| p.is_none_or(|p| p == *b))
+    }
+
+    /// Offset of the first match in `data`
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        self.find_iter(data).next()
+    }
+
+    /// Offsets of every match in `data`, in order. Matches may overlap
+    pub fn find_iter<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let anchor_offset = self.anchor_offset;
+
+        // all wildcards matches everywhere
+        let candidates: Box<dyn Iterator<Item = usize> + 'a> = if self.anchor.is_empty() {
+            Box::new(0..(data.len() + 1).saturating_sub(self.len()))
+        } else {
+            // not `memmem::find_iter`, it skips over overlapping hits
+            let finder = memmem::Finder::new(&self.anchor);
+            let mut from = 0;
+
+            let hits = iter::from_fn(move || {
+                let hit = from + finder.find(data.get(from..)?)?;
+                from = hit + 1;
+                Some(hit)
+            });
+
+            Box::new(hits.filter_map(move |hit| hit.checked_sub(anchor_offset)))
+        };
+
+        candidates.filter(move |&pos| self.matches_at(data, pos))
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|b| match b {
+                Some(b) => format!("{b:02X}"),
+                None => "??".to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        f.write_str(&bytes)
+    }
+}
+
+/// Scan the executable parts (i.e. `.text`) of a loaded module for `pattern`
+///
+/// Returns the address of the first match
+pub fn scan_module(
+    memory: &impl MemoryBackend,
+    module: &str,
+    pattern: &Pattern,
+) -> Result<Option<Address>> {
+    let module = memory
+        .find_module(module)
+        .ok_or_eyre(format!("module {module} is not loaded"))?;
+
+    // only readable + executable memory, we can't read execute-only pages
+    let code = memory
+        .sections(&module)?
+        .into_iter()
+        .filter(|s| s.is_executable() && s.is_readable());
+
+    for section in code {
+        let data = memory.section_bytes(&section)?;
+
+        if let Some(offset) = pattern.find(&data) {
+            return Ok(Some(section.base + offset));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    #[test]
+    fn parse_ida_style() {
+        let pattern = Pattern::parse("48 8b ?? ? 89").unwrap();
+
+        assert_eq!(pattern.len(), 5);
+        assert_eq!(
+            pattern.bytes,
+            [Some(0x48), Some(0x8B), None, None, Some(0x89)]
+        );
+        assert_eq!(pattern.to_string(), "48 8B ?? ?? 89");
+
+        // extra whitespace doesn't matter
+        assert_eq!(Pattern::parse("  48\t8B ?? ??  89 ").unwrap(), pattern);
+        assert_eq!("48 8B ?? ?? 89".parse::<Pattern>().unwrap(), pattern);
+    }
+
+    #[test]
+    fn parse_code_style() {
+        let pattern = Pattern::from_code(b"\x48\x8B\x00\x00\x89", "xx??x").unwrap();
+
+        assert_eq!(pattern, Pattern::parse("48 8B ?? ?? 89").unwrap());
+    }
+
+    #[test]
+    fn bad_patterns() {
+        for bad in ["", "   ", "4G", "488B", "8", "??? 48", "0x48", "48 8B ?x"] {
+            assert!(Pattern::parse(bad).is_err(), "`{bad}` parsed");
+        }
+
+        assert!(Pattern::from_code(b"\x48\x8B", "x").is_err());
+        assert!(Pattern::from_code(b"\x48\x8B", "x.").is_err());
+        assert!(Pattern::from_code(b"", "").is_err());
+    }
+
+    #[test]
+    fn anchor_is_longest_run() {
+        let pattern = Pattern::parse("48 ?? 8B 05 11 ?? 22 33").unwrap();
+
+        assert_eq!(pattern.anchor, [0x8B, 0x05, 0x11]);
+        assert_eq!(pattern.anchor_offset, 2);
+    }
+
+    #[test]
+    fn wildcards_match_anything() {
+        let pattern = Pattern::parse("E8 ?? ?? ?? ?? 90").unwrap();
+
+        assert_eq!(pattern.find(&[0xCC, 0xE8, 1, 2, 3, 4, 0x90]), Some(1));
+        assert_eq!(pattern.find(&[0xE8, 0xFF, 0xFF, 0xFF, 0xFF, 0x90]), Some(0));
+        assert_eq!(pattern.find(&[0xE8, 1, 2, 3, 4, 0x91]), None);
+    }
+
+    #[test]
+    fn matches_at_the_edges() {
+        let pattern = Pattern::parse("AA ?? BB").unwrap();
+        let data = [0xAA, 0x00, 0xBB, 0x11, 0x22, 0xAA, 0x33, 0xBB];
+
+        assert_eq!(pattern.find_iter(&data).collect::<Vec<_>>(), [0, 5]);
+
+        assert!(pattern.matches_at(&data, 0));
+        assert!(pattern.matches_at(&data, 5));
+
+        // would run off the end
+        assert!(!pattern.matches_at(&data, 6));
+        assert!(!pattern.matches_at(&data, 100));
+
+        // cut off right before the last byte
+        assert_eq!(pattern.find(&data[..7]), Some(0));
+        assert_eq!(pattern.find(&data[1..7]), None);
+    }
+
+    #[test]
+    fn anchor_in_the_middle() {
+        // the anchor hit at 0 is too early for a match to start before it
+        let pattern = Pattern::parse("?? ?? 8B 05").unwrap();
+        let data = [0x8B, 0x05, 0x00, 0x00, 0x8B, 0x05];
+
+        assert_eq!(pattern.find_iter(&data).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn overlapping_matches() {
+        let pattern = Pattern::parse("AA AA").unwrap();
+
+        assert_eq!(
+            pattern.find_iter(&[0xAA, 0xAA, 0xAA]).collect::<Vec<_>>(),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn all_wildcards() {
+        let pattern = Pattern::parse("?? ??").unwrap();
+
+        assert_eq!(pattern.find_iter(&[1, 2, 3]).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(pattern.find(&[1, 2]), Some(0));
+        assert_eq!(pattern.find(&[1]), None);
+        assert_eq!(pattern.find(&[]), None);
+    }
+
+    #[test]
+    fn scan_module_code() {
+        let memory = FakeMemory::new();
+
+        // the same bytes in data don't count, even though it comes first in the module
+        memory.map(0x1000, vec![0x48, 0x8B, 0x05, 0x10], Prot::R);
+
+        let mut code = vec![0xCC; 0x100];
+        code[0xFC..].copy_from_slice(&[0x48, 0x8B, 0x05, 0x10]);
+        memory.map(0x1100, code, Prot::XR);
+
+        memory.add_module("game.exe", 0x1000, 0x200);
+
+        let pattern = Pattern::parse("48 8B 05 ??").unwrap();
+        assert_eq!(
+            scan_module(&memory, "GAME.EXE", &pattern).unwrap(),
+            Some(0x11FC)
+        );
+
+        // and when it's only in data, it isn't found at all
+        memory.map(0x3000, vec![0x48, 0x8B, 0x05, 0x10], Prot::RW);
+        memory.map(0x3100, vec![0xCC; 0x100], Prot::XR);
+        memory.add_module("data.dll", 0x3000, 0x200);
+        assert_eq!(scan_module(&memory, "data.dll", &pattern).unwrap(), None);
+
+        let missing = Pattern::parse("48 8B 0D").unwrap();
+        assert_eq!(scan_module(&memory, "game.exe", &missing).unwrap(), None);
+
+        assert!(scan_module(&memory, "other.dll", &pattern).is_err());
+    }
+}
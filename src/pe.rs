@@ -0,0 +1,1430 @@
+use std::fmt::{self, Display};
+
+use eyre::{OptionExt as _, Result, bail, eyre};
+
+const DIR_EXPORT: usize = 0;
+const DIR_IMPORT: usize = 1;
+const DIR_RESOURCE: usize = 2;
+const DIR_DEBUG: usize = 6;
+
+const SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const SCN_MEM_READ: u32 = 0x4000_0000;
+const SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+const DEBUG_TYPE_CODEVIEW: u32 = 2;
+const RT_VERSION: u32 = 16;
+
+/// How the image is laid out in the bytes we're given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Loaded by the windows loader; every RVA is an offset into the bytes
+    Mapped,
+    /// Straight from the file on disk; RVAs have to be translated through the section table
+    File,
+}
+
+/// A section header, e.g. `.text`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionHeader {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+}
+
+impl SectionHeader {
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & SCN_MEM_EXECUTE != 0
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & SCN_MEM_READ != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & SCN_MEM_WRITE != 0
+    }
+
+    pub fn contains_rva(&self, rva: u32) -> bool {
+        let size = self.virtual_size.max(self.raw_size);
+        rva >= self.virtual_address && rva - self.virtual_address < size
+    }
+}
+
+/// An exported function or variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    /// Exports can be by ordinal only
+    pub name: Option<String>,
+    pub ordinal: u16,
+    pub rva: u32,
+    /// `OTHER.dll.Function` if this export is forwarded to another dll. `rva` is meaningless then
+    pub forwarder: Option<String>,
+}
+
+/// Everything imported from one dll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// e.g. `KERNEL32.dll`. Case is whatever the linker wrote
+    pub dll: String,
+    pub functions: Vec<ImportedFunction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedFunction {
+    /// `None` if imported by ordinal
+    pub name: Option<String>,
+    pub ordinal: Option<u16>,
+    /// Where the loader writes the function's address, i.e. its slot in the import address table
+    pub iat_rva: u32,
+}
+
+/// The CodeView (`RSDS`) debug entry, which is what links a binary to its PDB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeView {
+    pub guid: [u8; 16],
+    pub age: u32,
+    /// Path of the PDB at link time
+    pub pdb_path: String,
+}
+
+impl CodeView {
+    /// The id symbol servers file the PDB under, i.e. the GUID without dashes followed by the age
+    pub fn symbol_id(&self) -> String {
+        format!("{}{:X}", self.guid_string().replace('-', ""), self.age)
+    }
+
+    /// `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX`
+    pub fn guid_string(&self) -> String {
+        let g = &self.guid;
+        let data1 = u32::from_le_bytes([g[0], g[1], g[2], g[3]]);
+        let data2 = u16::from_le_bytes([g[4], g[5]]);
+        let data3 = u16::from_le_bytes([g[6], g[7]]);
+
+        let data4 = g[8..]
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<String>();
+
+        format!(
+            "{data1:08X}-{data2:04X}-{data3:04X}-{}-{}",
+            &data4[..4],
+            &data4[4..]
+        )
+    }
+}
+
+impl Display for CodeView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.pdb_path, self.symbol_id())
+    }
+}
+
+/// The interesting parts of the version resource
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// From `VS_FIXEDFILEINFO`: major, minor, build, revision
+    pub file_version: Option<[u16; 4]>,
+    pub product_version: Option<[u16; 4]>,
+    /// Everything in the `StringFileInfo` tables, e.g. `("ProductName", "Baldur's Gate 3")`
+    pub strings: Vec<(String, String)>,
+}
+
+impl VersionInfo {
+    /// `1.2.3.4`
+    pub fn file_version_string(&self) -> Option<String> {
+        self.file_version
+            .map(|[a, b, c, d]| format!("{a}.{b}.{c}.{d}"))
+    }
+
+    /// A value from the string table, e.g. `FileDescription`
+    pub fn string(&self, key: &str) -> Option<&str> {
+        self.strings
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A PE image (exe or dll), parsed from a byte slice
+///
+/// Works on a module mapped in memory and on a file read from disk alike; tell it which with
+/// [`Layout`]. Nothing here touches memory outside of the slice, so a broken or hostile image
+/// is an error, not a crash
+///
+/// ```ignore
+/// let data = fs::read("game.exe")?;
+/// let pe = PeImage::parse(&data, Layout::File)?;
+///
+/// for import in pe.imports()? {
+///     info!("{} functions from {}", import.functions.len(), import.dll);
+/// }
+/// ```
+pub struct PeImage<'a> {
+    data: &'a [u8],
+    layout: Layout,
+    is_64: bool,
+    timestamp: u32,
+    entry_point: u32,
+    image_base: u64,
+    size_of_image: u32,
+    size_of_headers: u32,
+    data_dirs: Vec<(u32, u32)>,
+    sections: Vec<SectionHeader>,
+}
+
+impl<'a> PeImage<'a> {
+    pub fn parse(data: &'a [u8], layout: Layout) -> Result<Self> {
+        if data.get(..2) != Some(b"MZ") {
+            bail!("not a PE image: no MZ signature");
+        }
+
+        let nt = u32_at(data, 0x3C)? as usize;
+        if data.get(nt..nt + 4) != Some(b"PE\0\0") {
+            bail!("not a PE image: no PE signature at {nt:#x}");
+        }
+
+        let file_header = nt + 4;
+        let section_count = u16_at(data, file_header + 2)? as usize;
+        let timestamp = u32_at(data, file_header + 4)?;
+        let optional_size = u16_at(data, file_header + 16)? as usize;
+
+        let optional = file_header + 20;
+        let is_64 = match u16_at(data, optional)? {
+            0x10B => false,
+            0x20B => true,
+            magic => bail!("unknown optional header magic {magic:#x}"),
+        };
+
+        let entry_point = u32_at(data, optional + 16)?;
+        let image_base = if is_64 {
+            u64_at(data, optional + 24)?
+        } else {
+            u32_at(data, optional + 28)? as u64
+        };
+        let size_of_image = u32_at(data, optional + 56)?;
+        let size_of_headers = u32_at(data, optional + 60)?;
+
+        let (dir_count, dirs) = if is_64 {
+            (optional + 108, optional + 112)
+        } else {
+            (optional + 92, optional + 96)
+        };
+
+        // there are never more than 16, anything else is garbage
+        let dir_count = (u32_at(data, dir_count)? as usize).min(16);
+        let data_dirs = (0..dir_count)
+            .map(|idx| {
+                let dir = dirs + idx * 8;
+                Ok((u32_at(data, dir)?, u32_at(data, dir + 4)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let section_table = optional + optional_size;
+        let sections = (0..section_count)
+            .map(|idx| {
+                let header = section_table + idx * 40;
+                let name = bytes_at(data, header, 8)?;
+                let name = String::from_utf8_lossy(name)
+                    .trim_end_matches('\0')
+                    .to_owned();
+
+                Ok(SectionHeader {
+                    name,
+                    virtual_size: u32_at(data, header + 8)?,
+                    virtual_address: u32_at(data, header + 12)?,
+                    raw_size: u32_at(data, header + 16)?,
+                    raw_offset: u32_at(data, header + 20)?,
+                    characteristics: u32_at(data, header + 36)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            data,
+            layout,
+            is_64,
+            timestamp,
+            entry_point,
+            image_base,
+            size_of_image,
+            size_of_headers,
+            data_dirs,
+            sections,
+        })
+    }
+
+    /// PE32+, i.e. a 64 bit image
+    pub fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    /// Link time, seconds since the unix epoch. Some toolchains put a hash here instead
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    pub fn entry_point(&self) -> u32 {
+        self.entry_point
+    }
+
+    /// Where the image would like to be loaded
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    /// Size of the image once mapped
+    pub fn size_of_image(&self) -> u32 {
+        self.size_of_image
+    }
+
+    pub fn sections(&self) -> &[SectionHeader] {
+        &self.sections
+    }
+
+    pub fn section(&self, name: &str) -> Option<&SectionHeader> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// Offset into our bytes of `rva`
+    pub fn rva_to_offset(&self, rva: u32) -> Result<usize> {
+        if self.layout == Layout::Mapped || rva < self.size_of_headers {
+            return Ok(rva as usize);
+        }
+
+        let section = self
+            .sections
+            .iter()
+            .find(|s| s.contains_rva(rva))
+            .ok_or_eyre(format!("rva {rva:#x} isn't in any section"))?;
+
+        let offset = rva - section.virtual_address;
+        if offset >= section.raw_size {
+            bail!(
+                "rva {rva:#x} is in the uninitialized part of {}",
+                section.name
+            );
+        }
+
+        Ok(section.raw_offset as usize + offset as usize)
+    }
+
+    fn dir(&self, idx: usize) -> Option<(u32, u32)> {
+        self.data_dirs
+            .get(idx)
+            .copied()
+            .filter(|(rva, size)| *rva != 0 && *size != 0)
+    }
+
+    fn cstr_at_rva(&self, rva: u32) -> Result<String> {
+        cstr_at(self.data, self.rva_to_offset(rva)?)
+    }
+
+    // tables are read by offset from their start, rather than by rva. everything in them is in the
+    // same section, and a garbage rva can't overflow on the way
+
+    /// Everything the image exports, in address table order
+    pub fn exports(&self) -> Result<Vec<Export>> {
+        let Some((dir_rva, dir_size)) = self.dir(DIR_EXPORT) else {
+            return Ok(Vec::new());
+        };
+
+        let dir = self.rva_to_offset(dir_rva)?;
+        let base = u32_at(self.data, dir + 16)?;
+        let function_count = u32_at(self.data, dir + 20)? as usize;
+        let name_count = u32_at(self.data, dir + 24)? as usize;
+        let functions = self.rva_to_offset(u32_at(self.data, dir + 28)?)?;
+
+        // index into the address table -> name
+        let mut exported_names = vec![None; function_count.min(self.data.len() / 4)];
+        if name_count > 0 {
+            let names = self.rva_to_offset(u32_at(self.data, dir + 32)?)?;
+            let ordinals = self.rva_to_offset(u32_at(self.data, dir + 36)?)?;
+
+            for idx in 0..name_count {
+                let name = u32_at(self.data, names + idx * 4)?;
+                let function = u16_at(self.data, ordinals + idx * 2)? as usize;
+
+                if let Some(slot) = exported_names.get_mut(function) {
+                    *slot = Some(self.cstr_at_rva(name)?);
+                }
+            }
+        }
+
+        let mut exports = Vec::new();
+        for (idx, name) in exported_names.into_iter().enumerate() {
+            let rva = u32_at(self.data, functions + idx * 4)?;
+            if rva == 0 {
+                continue;
+            }
+
+            // an address inside the export directory is a string naming where it's forwarded to
+            let forwarder = (rva.wrapping_sub(dir_rva) < dir_size)
+                .then(|| self.cstr_at_rva(rva))
+                .transpose()?;
+
+            exports.push(Export {
+                name,
+                ordinal: base.wrapping_add(idx as u32) as u16,
+                rva,
+                forwarder,
+            });
+        }
+
+        Ok(exports)
+    }
+
+    /// An export by name
+    pub fn export(&self, name: &str) -> Result<Option<Export>> {
+        Ok(self
+            .exports()?
+            .into_iter()
+            .find(|e| e.name.as_deref() == Some(name)))
+    }
+
+    /// Everything the image imports, per dll
+    ///
+    /// On a mapped image, names come from the import lookup table, since the loader already
+    /// overwrote the address table with addresses. Images without a lookup table only have ordinals
+    /// and names on disk
+    pub fn imports(&self) -> Result<Vec<Import>> {
+        let Some((dir_rva, _)) = self.dir(DIR_IMPORT) else {
+            return Ok(Vec::new());
+        };
+
+        let thunk_size = if self.is_64 { 8 } else { 4 };
+        let ordinal_flag = if self.is_64 { 1 << 63 } else { 1 << 31 };
+
+        let dir = self.rva_to_offset(dir_rva)?;
+
+        let mut imports = Vec::new();
+        for descriptor in (dir..).step_by(20) {
+            let lookup = u32_at(self.data, descriptor)?;
+            let name = u32_at(self.data, descriptor + 12)?;
+            let iat = u32_at(self.data, descriptor + 16)?;
+
+            // the table ends with an all zero descriptor
+            if name == 0 && iat == 0 {
+                break;
+            }
+
+            let dll = self.cstr_at_rva(name)?;
+
+            // without a lookup table the names are only in the address table, before it's bound
+            let names_from = match lookup {
+                0 if self.layout == Layout::Mapped => None,
+                0 => Some(iat),
+                lookup => Some(lookup),
+            };
+
+            let table = self.rva_to_offset(names_from.unwrap_or(iat))?;
+
+            let mut functions = Vec::new();
+            for idx in 0.. {
+                let thunk = thunk_at(self.data, table + idx * thunk_size, self.is_64)?;
+                if thunk == 0 {
+                    break;
+                }
+
+                let iat_rva = u32::try_from(idx * thunk_size)
+                    .ok()
+                    .and_then(|offset| iat.checked_add(offset))
+                    .ok_or_eyre(format!("import address table of {dll} is out of range"))?;
+
+                let function = match names_from {
+                    // all we know is where the slots are
+                    None => ImportedFunction {
+                        name: None,
+                        ordinal: None,
+                        iat_rva,
+                    },
+
+                    Some(_) if thunk & ordinal_flag != 0 => ImportedFunction {
+                        name: None,
+                        ordinal: Some(thunk as u16),
+                        iat_rva,
+                    },
+
+                    // IMAGE_IMPORT_BY_NAME: a hint, then the name
+                    Some(_) => {
+                        let by_name = self.rva_to_offset(thunk as u32)?;
+
+                        ImportedFunction {
+                            name: Some(cstr_at(self.data, by_name + 2)?),
+                            ordinal: None,
+                            iat_rva,
+                        }
+                    }
+                };
+
+                functions.push(function);
+            }
+
+            imports.push(Import { dll, functions });
+        }
+
+        Ok(imports)
+    }
+
+    /// The CodeView debug entry, if the image was linked with debug info
+    pub fn codeview(&self) -> Result<Option<CodeView>> {
+        let Some((dir_rva, dir_size)) = self.dir(DIR_DEBUG) else {
+            return Ok(None);
+        };
+
+        let dir = self.rva_to_offset(dir_rva)?;
+
+        for entry in (dir..dir + dir_size as usize).step_by(28) {
+            if u32_at(self.data, entry + 12)? != DEBUG_TYPE_CODEVIEW {
+                continue;
+            }
+
+            let size = u32_at(self.data, entry + 16)? as usize;
+            let offset = match self.layout {
+                Layout::Mapped => u32_at(self.data, entry + 20)? as usize,
+                Layout::File => u32_at(self.data, entry + 24)? as usize,
+            };
+
+            let data = bytes_at(self.data, offset, size)?;
+            if data.get(..4) != Some(b"RSDS") || size < 24 {
+                continue;
+            }
+
+            let mut guid = [0; 16];
+            guid.copy_from_slice(&data[4..20]);
+
+            return Ok(Some(CodeView {
+                guid,
+                age: u32_at(data, 20)?,
+                pdb_path: cstr_at(data, 24)?,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// The version resource, if there is one
+    pub fn version_info(&self) -> Result<Option<VersionInfo>> {
+        let Some((dir_rva, _)) = self.dir(DIR_RESOURCE) else {
+            return Ok(None);
+        };
+
+        let root = self.rva_to_offset(dir_rva)?;
+
+        // type -> name -> language. there's only ever one version resource worth looking at
+        let Some(names) = self.resource_entry(root, root, Some(RT_VERSION))? else {
+            return Ok(None);
+        };
+        let Some(languages) = self.resource_entry(root, names, None)? else {
+            return Ok(None);
+        };
+        let Some(data_entry) = self.resource_entry(root, languages, None)? else {
+            return Ok(None);
+        };
+
+        let rva = u32_at(self.data, data_entry)?;
+        let size = u32_at(self.data, data_entry + 4)? as usize;
+        let data = bytes_at(self.data, self.rva_to_offset(rva)?, size)?;
+
+        parse_version(data).map(Some)
+    }
+
+    /// Offset of the first entry in a resource directory (with this id, if given)
+    fn resource_entry(&self, root: usize, dir: usize, id: Option<u32>) -> Result<Option<usize>> {
+        let named = u16_at(self.data, dir + 12)? as usize;
+        let ids = u16_at(self.data, dir + 14)? as usize;
+
+        for idx in 0..named + ids {
+            let entry = dir + 16 + idx * 8;
+            let name = u32_at(self.data, entry)?;
+            let offset = u32_at(self.data, entry + 4)?;
+
+            // named entries have the top bit set, and never match an id
+            if id.is_some_and(|id| name != id) {
+                continue;
+            }
+
+            return Ok(Some(root + (offset & 0x7FFF_FFFF) as usize));
+        }
+
+        Ok(None)
+    }
+}
+
+/// One block of a `VS_VERSIONINFO` tree
+struct VersionBlock<'a> {
+    key: String,
+    value: &'a [u8],
+    children: &'a [u8],
+}
+
+/// Parse the block at the start of `data`, and return it with how many bytes it took
+fn version_block(data: &[u8]) -> Result<(VersionBlock<'_>, usize)> {
+    let len = u16_at(data, 0)? as usize;
+    let value_len = u16_at(data, 2)? as usize;
+    let is_text = u16_at(data, 4)? == 1;
+
+    let block = data
+        .get(..len)
+        .ok_or_eyre("version block is out of bounds")?;
+
+    let (key, key_end) = utf16_at(block, 6)?;
+    let value_start = align4(key_end);
+
+    let value_size = if is_text { value_len * 2 } else { value_len };
+    let value_end = (value_start + value_size).min(len);
+    let value = block.get(value_start..value_end).unwrap_or_default();
+
+    let children = block.get(align4(value_end)..).unwrap_or_default();
+
+    Ok((
+        VersionBlock {
+            key,
+            value,
+            children,
+        },
+        align4(len),
+    ))
+}
+
+/// Every block in a run of sibling blocks
+fn version_children(mut data: &[u8]) -> Result<Vec<VersionBlock<'_>>> {
+    let mut blocks = Vec::new();
+
+    while data.len() >= 6 {
+        let (block, len) = version_block(data)?;
+        if len == 0 {
+            break;
+        }
+
+        blocks.push(block);
+        data = data.get(len..).unwrap_or_default();
+    }
+
+    Ok(blocks)
+}
+
+fn parse_version(data: &[u8]) -> Result<VersionInfo> {
+    let (root, _) = version_block(data)?;
+    if root.key != "VS_VERSION_INFO" {
+        bail!("version resource starts with `{}`", root.key);
+    }
+
+    let mut info = VersionInfo::default();
+
+    // VS_FIXEDFILEINFO, starting with its signature
+    if root.value.len() >= 52 && u32_at(root.value, 0)? == 0xFEEF_04BD {
+        let version = |at| -> Result<[u16; 4]> {
+            let ms = u32_at(root.value, at)?;
+            let ls = u32_at(root.value, at + 4)?;
+            Ok([(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16])
+        };
+
+        info.file_version = Some(version(8)?);
+        info.product_version = Some(version(16)?);
+    }
+
+    for child in version_children(root.children)? {
+        if child.key != "StringFileInfo" {
+            continue;
+        }
+
+        for table in version_children(child.children)? {
+            for string in version_children(table.children)? {
+                // some linkers get the value length wrong, so read up to the terminator instead
+                let (value, _) = utf16_at(string.value, 0).unwrap_or_default();
+                info.strings.push((string.key, value));
+            }
+        }
+    }
+
+    Ok(info)
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn bytes_at(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| eyre!("{len} bytes at {offset:#x} are out of bounds"))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = bytes_at(data, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = bytes_at(data, offset, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = bytes_at(data, offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+fn thunk_at(data: &[u8], offset: usize, is_64: bool) -> Result<u64> {
+    if is_64 {
+        u64_at(data, offset)
+    } else {
+        u32_at(data, offset).map(u64::from)
+    }
+}
+
+/// A nul terminated ascii/utf8 string
+fn cstr_at(data: &[u8], offset: usize) -> Result<String> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| eyre!("string at {offset:#x} is out of bounds"))?;
+
+    let len = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| eyre!("string at {offset:#x} isn't terminated"))?;
+
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+/// A nul terminated utf16 string, and the offset right after the terminator.
+/// Running into the end of `data` counts as the end of the string
+fn utf16_at(data: &[u8], offset: usize) -> Result<(String, usize)> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| eyre!("string at {offset:#x} is out of bounds"))?;
+
+    let units = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+
+    let end = (offset + units.len() * 2 + 2).min(data.len());
+
+    Ok((String::from_utf16_lossy(&units), end))
+}
+
+/// Builds small PE32+ images for tests, in either layout
+#[cfg(test)]
+pub(crate) mod fixture {
+    use super::*;
+
+    const HEADERS: u32 = 0x400;
+    const SECTION_TABLE: usize = 0x40 + 4 + 20 + 240;
+
+    /// What an export points at
+    pub(crate) enum Exported<'a> {
+        Rva(u32),
+        /// e.g. `NTDLL.RtlAllocateHeap`
+        Forward(&'a str),
+        /// A hole in the address table
+        Missing,
+    }
+
+    pub(crate) enum Imported<'a> {
+        Name(&'a str),
+        Ordinal(u16),
+    }
+
+    struct Section {
+        name: &'static str,
+        rva: u32,
+        raw_offset: u32,
+        characteristics: u32,
+        data: Vec<u8>,
+    }
+
+    /// Sections go one after another in the order they're added, page aligned once mapped
+    ///
+    /// ```ignore
+    /// let mut pe = PeBuilder::new();
+    /// let code = pe.section(".text", SCN_MEM_EXECUTE | SCN_MEM_READ, vec![0xC3]);
+    /// pe.exports("test.dll", 1, &[(Some("Run"), Exported::Rva(code))]);
+    ///
+    /// let image = pe.build(Layout::Mapped);
+    /// ```
+    pub(crate) struct PeBuilder {
+        timestamp: u32,
+        dirs: [(u32, u32); 16],
+        sections: Vec<Section>,
+    }
+
+    impl PeBuilder {
+        pub fn new() -> Self {
+            Self {
+                timestamp: 0x66F1_A2B3,
+                dirs: [(0, 0); 16],
+                sections: Vec::new(),
+            }
+        }
+
+        /// Rva and file offset of the next section
+        fn next(&self) -> (u32, u32) {
+            self.sections.last().map_or((0x1000, HEADERS), |s| {
+                let len = s.data.len().max(1) as u32;
+                (
+                    s.rva + len.next_multiple_of(0x1000),
+                    s.raw_offset + len.next_multiple_of(0x200),
+                )
+            })
+        }
+
+        /// Add a section, and return its rva
+        pub fn section(&mut self, name: &'static str, characteristics: u32, data: Vec<u8>) -> u32 {
+            let (rva, raw_offset) = self.next();
+
+            self.sections.push(Section {
+                name,
+                rva,
+                raw_offset,
+                characteristics,
+                data,
+            });
+
+            rva
+        }
+
+        /// `.edata` with an export directory. Ordinals count up from `ordinal_base`
+        pub fn exports(
+            &mut self,
+            dll: &str,
+            ordinal_base: u32,
+            exports: &[(Option<&str>, Exported)],
+        ) -> u32 {
+            let (rva, _) = self.next();
+            let named = exports
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, (name, _))| Some((idx, (*name)?)))
+                .collect::<Vec<_>>();
+
+            let functions = 40;
+            let names = functions + exports.len() * 4;
+            let ordinals = names + named.len() * 4;
+            let mut data = vec![0; ordinals + named.len() * 2];
+
+            let name = rva + cstr(&mut data, dll);
+            put(&mut data, 12, name);
+            put(&mut data, 16, ordinal_base);
+            put(&mut data, 20, exports.len() as u32);
+            put(&mut data, 24, named.len() as u32);
+            put(&mut data, 28, rva + functions as u32);
+            put(&mut data, 32, rva + names as u32);
+            put(&mut data, 36, rva + ordinals as u32);
+
+            for (idx, (_, exported)) in exports.iter().enumerate() {
+                let target = match exported {
+                    Exported::Rva(target) => *target,
+                    Exported::Forward(to) => rva + cstr(&mut data, to),
+                    Exported::Missing => 0,
+                };
+
+                put(&mut data, functions + idx * 4, target);
+            }
+
+            for (n, (idx, name)) in named.into_iter().enumerate() {
+                let name = rva + cstr(&mut data, name);
+                put(&mut data, names + n * 4, name);
+                put(&mut data, ordinals + n * 2, idx as u16);
+            }
+
+            self.dirs[DIR_EXPORT] = (rva, data.len() as u32);
+            self.section(".edata", SCN_MEM_READ, data)
+        }
+
+        /// `.idata` with an import directory. Without a lookup table, the names are only in the
+        /// address table, like old linkers do
+        pub fn imports(&mut self, dlls: &[(&str, &[Imported])], lookup_table: bool) -> u32 {
+            let (rva, _) = self.next();
+            let mut data = vec![0; (dlls.len() + 1) * 20];
+
+            for (idx, (dll, functions)) in dlls.iter().enumerate() {
+                let descriptor = idx * 20;
+
+                let name = rva + cstr(&mut data, dll);
+                put(&mut data, descriptor + 12, name);
+
+                let mut thunks = Vec::new();
+                for function in *functions {
+                    let thunk = match function {
+                        Imported::Ordinal(ordinal) => 1 << 63 | *ordinal as u64,
+
+                        // IMAGE_IMPORT_BY_NAME, with a hint of 0
+                        Imported::Name(name) => {
+                            let hint = data.len();
+                            data.extend_from_slice(&[0, 0]);
+                            cstr(&mut data, name);
+
+                            (rva as usize + hint) as u64
+                        }
+                    };
+
+                    thunks.push(thunk);
+                }
+
+                let lookup = rva + thunk_table(&mut data, &thunks);
+                let iat = rva + thunk_table(&mut data, &thunks);
+
+                if lookup_table {
+                    put(&mut data, descriptor, lookup);
+                }
+                put(&mut data, descriptor + 16, iat);
+            }
+
+            self.dirs[DIR_IMPORT] = (rva, data.len() as u32);
+            self.section(".idata", SCN_MEM_READ | SCN_MEM_WRITE, data)
+        }
+
+        /// `.rdata` with a debug directory holding one CodeView entry
+        pub fn codeview(&mut self, guid: [u8; 16], age: u32, pdb_path: &str) -> u32 {
+            let (rva, raw_offset) = self.next();
+            let mut data = vec![0; 28];
+
+            let entry = data.len();
+            data.extend_from_slice(b"RSDS");
+            data.extend_from_slice(&guid);
+            data.extend_from_slice(&age.to_le_bytes());
+            cstr(&mut data, pdb_path);
+
+            let size = (data.len() - entry) as u32;
+            put(&mut data, 12, DEBUG_TYPE_CODEVIEW);
+            put(&mut data, 16, size);
+            put(&mut data, 20, rva + entry as u32);
+            put(&mut data, 24, raw_offset + entry as u32);
+
+            self.dirs[DIR_DEBUG] = (rva, 28);
+            self.section(".rdata", SCN_MEM_READ, data)
+        }
+
+        /// `.rsrc` with a version resource, type -> name -> language -> `VS_VERSIONINFO`
+        pub fn version(&mut self, file_version: [u16; 4], strings: &[(&str, &str)]) -> u32 {
+            let (rva, _) = self.next();
+
+            let [a, b, c, d] = file_version.map(u32::from);
+            let mut fixed = vec![0; 52];
+            put(&mut fixed, 0, 0xFEEF_04BDu32);
+            put(&mut fixed, 4, 0x1_0000u32);
+            put(&mut fixed, 8, a << 16 | b);
+            put(&mut fixed, 12, c << 16 | d);
+            put(&mut fixed, 16, a << 16 | b);
+            put(&mut fixed, 20, c << 16 | d);
+
+            let strings = strings
+                .iter()
+                .map(|(key, value)| {
+                    let value = utf16(value);
+                    block(key, &value, value.len() / 2, true, &[])
+                })
+                .collect::<Vec<_>>();
+
+            let table = block("040904B0", &[], 0, true, &strings);
+            let string_file_info = block("StringFileInfo", &[], 0, true, &[table]);
+            let version = block("VS_VERSION_INFO", &fixed, 52, false, &[string_file_info]);
+
+            // three directories with one entry each, then the data entry
+            let mut data = vec![0; 3 * 24 + 16];
+            for (level, id) in [RT_VERSION, 1, 0x409].into_iter().enumerate() {
+                let dir = level * 24;
+                let next = dir as u32 + 24;
+
+                put(&mut data, dir + 14, 1u16);
+                put(&mut data, dir + 16, id);
+                put(
+                    &mut data,
+                    dir + 20,
+                    if level < 2 { next | 1 << 31 } else { next },
+                );
+            }
+
+            put(&mut data, 72, rva + 88);
+            put(&mut data, 76, version.len() as u32);
+            data.extend_from_slice(&version);
+
+            self.dirs[DIR_RESOURCE] = (rva, data.len() as u32);
+            self.section(".rsrc", SCN_MEM_READ, data)
+        }
+
+        pub fn build(&self, layout: Layout) -> Vec<u8> {
+            let (size_of_image, file_size) = self.next();
+
+            let mut headers = vec![0; HEADERS as usize];
+            headers[..2].copy_from_slice(b"MZ");
+            put(&mut headers, 0x3C, 0x40u32);
+            headers[0x40..0x44].copy_from_slice(b"PE\0\0");
+
+            let file_header = 0x44;
+            put(&mut headers, file_header, 0x8664u16);
+            put(&mut headers, file_header + 2, self.sections.len() as u16);
+            put(&mut headers, file_header + 4, self.timestamp);
+            put(&mut headers, file_header + 16, 240u16);
+
+            let optional = file_header + 20;
+            put(&mut headers, optional, 0x20Bu16);
+            put(&mut headers, optional + 24, 0x1_4000_0000u64);
+            put(&mut headers, optional + 32, 0x1000u32);
+            put(&mut headers, optional + 36, 0x200u32);
+            put(&mut headers, optional + 56, size_of_image);
+            put(&mut headers, optional + 60, HEADERS);
+            put(&mut headers, optional + 108, 16u32);
+
+            for (idx, (rva, size)) in self.dirs.iter().enumerate() {
+                put(&mut headers, optional + 112 + idx * 8, *rva);
+                put(&mut headers, optional + 116 + idx * 8, *size);
+            }
+
+            for (idx, section) in self.sections.iter().enumerate() {
+                let header = SECTION_TABLE + idx * 40;
+                let len = section.data.len() as u32;
+
+                headers[header..header + section.name.len()]
+                    .copy_from_slice(section.name.as_bytes());
+                put(&mut headers, header + 8, len);
+                put(&mut headers, header + 12, section.rva);
+                put(&mut headers, header + 16, len.next_multiple_of(0x200));
+                put(&mut headers, header + 20, section.raw_offset);
+                put(&mut headers, header + 36, section.characteristics);
+            }
+
+            let (len, at): (u32, fn(&Section) -> u32) = match layout {
+                Layout::Mapped => (size_of_image, |s| s.rva),
+                Layout::File => (file_size, |s| s.raw_offset),
+            };
+
+            let mut image = vec![0; len as usize];
+            image[..headers.len()].copy_from_slice(&headers);
+
+            for section in &self.sections {
+                let at = at(section) as usize;
+                image[at..at + section.data.len()].copy_from_slice(&section.data);
+            }
+
+            image
+        }
+    }
+
+    /// Little endian `value` at `offset`
+    fn put<const N: usize>(data: &mut [u8], offset: usize, value: impl ToLe<N>) {
+        data[offset..offset + N].copy_from_slice(&value.to_le());
+    }
+
+    trait ToLe<const N: usize> {
+        fn to_le(self) -> [u8; N];
+    }
+
+    impl ToLe<2> for u16 {
+        fn to_le(self) -> [u8; 2] {
+            self.to_le_bytes()
+        }
+    }
+
+    impl ToLe<4> for u32 {
+        fn to_le(self) -> [u8; 4] {
+            self.to_le_bytes()
+        }
+    }
+
+    impl ToLe<8> for u64 {
+        fn to_le(self) -> [u8; 8] {
+            self.to_le_bytes()
+        }
+    }
+
+    /// Append a nul terminated string, and return where it starts
+    fn cstr(data: &mut Vec<u8>, s: &str) -> u32 {
+        let at = data.len() as u32;
+        data.extend_from_slice(s.as_bytes());
+        data.push(0);
+
+        at
+    }
+
+    /// Append an 8 byte aligned, zero terminated thunk table, and return where it starts
+    fn thunk_table(data: &mut Vec<u8>, thunks: &[u64]) -> u32 {
+        data.resize(data.len().next_multiple_of(8), 0);
+
+        let at = data.len() as u32;
+        for thunk in thunks.iter().chain([&0]) {
+            data.extend_from_slice(&thunk.to_le_bytes());
+        }
+
+        at
+    }
+
+    /// Nul terminated utf16
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    /// A `VS_VERSIONINFO` style block
+    fn block(
+        key: &str,
+        value: &[u8],
+        value_len: usize,
+        is_text: bool,
+        children: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut block = vec![0; 6];
+        put(&mut block, 2, value_len as u16);
+        put(&mut block, 4, is_text as u16);
+
+        block.extend_from_slice(&utf16(key));
+        block.resize(align4(block.len()), 0);
+        block.extend_from_slice(value);
+
+        for child in children {
+            block.resize(align4(block.len()), 0);
+            block.extend_from_slice(child);
+        }
+
+        let len = block.len() as u16;
+        put(&mut block, 0, len);
+
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fixture::{Exported, Imported, PeBuilder},
+        *,
+    };
+
+    const CODE: u32 = SCN_MEM_EXECUTE | SCN_MEM_READ;
+
+    /// One byte short of the section table in [`everything`]
+    const SECTION_TABLE_END: usize = 0x148 + 5 * 40 - 1;
+
+    const GUID: [u8; 16] = [
+        0x78, 0x56, 0x34, 0x12, 0xBC, 0x9A, 0xF0, 0xDE, 1, 2, 3, 4, 5, 6, 7, 8,
+    ];
+
+    /// A dll with a bit of everything
+    fn everything() -> PeBuilder {
+        let mut pe = PeBuilder::new();
+
+        let code = pe.section(".text", CODE, vec![0xC3; 0x20]);
+        pe.exports(
+            "test.dll",
+            5,
+            &[
+                (Some("Real"), Exported::Rva(code)),
+                (Some("Fwd"), Exported::Forward("NTDLL.RtlFoo")),
+                (None, Exported::Rva(code + 0x10)),
+                (None, Exported::Missing),
+            ],
+        );
+        pe.imports(
+            &[
+                (
+                    "KERNEL32.dll",
+                    &[Imported::Name("GetTickCount"), Imported::Name("Sleep")],
+                ),
+                ("WS2_32.dll", &[Imported::Ordinal(23)]),
+            ],
+            true,
+        );
+        pe.codeview(GUID, 3, r"C:\build\test.pdb");
+        pe.version(
+            [4, 1, 1, 5],
+            &[("ProductName", "Test Game"), ("FileDescription", "")],
+        );
+
+        pe
+    }
+
+    fn both(pe: &PeBuilder) -> [(Layout, Vec<u8>); 2] {
+        [Layout::Mapped, Layout::File].map(|layout| (layout, pe.build(layout)))
+    }
+
+    #[test]
+    fn headers() {
+        for (layout, image) in both(&everything()) {
+            let pe = PeImage::parse(&image, layout).unwrap();
+
+            assert!(pe.is_64());
+            assert_eq!(pe.timestamp(), 0x66F1_A2B3);
+            assert_eq!(pe.image_base(), 0x1_4000_0000);
+            assert_eq!(pe.size_of_image(), 0x6000);
+
+            let names = pe.sections().iter().map(|s| &s.name).collect::<Vec<_>>();
+            assert_eq!(names, [".text", ".edata", ".idata", ".rdata", ".rsrc"]);
+
+            let text = pe.section(".text").unwrap();
+            assert!(text.is_executable() && text.is_readable() && !text.is_writable());
+            assert!(pe.section(".idata").unwrap().is_writable());
+            assert!(pe.section(".bss").is_none());
+        }
+    }
+
+    #[test]
+    fn rva_to_offset() {
+        let image = everything().build(Layout::File);
+        let pe = PeImage::parse(&image, Layout::File).unwrap();
+
+        // headers are where they are
+        assert_eq!(pe.rva_to_offset(0x3C).unwrap(), 0x3C);
+        assert_eq!(pe.rva_to_offset(0x1010).unwrap(), 0x410);
+        assert_eq!(pe.rva_to_offset(0x2000).unwrap(), 0x600);
+
+        // past the raw data of .text, and between sections
+        assert!(pe.rva_to_offset(0x1300).is_err());
+        assert!(pe.rva_to_offset(0x1800).is_err());
+        assert!(pe.rva_to_offset(0x10_0000).is_err());
+
+        let mapped = PeImage::parse(&image, Layout::Mapped).unwrap();
+        assert_eq!(mapped.rva_to_offset(0x1800).unwrap(), 0x1800);
+    }
+
+    #[test]
+    fn exports() {
+        for (layout, image) in both(&everything()) {
+            let pe = PeImage::parse(&image, layout).unwrap();
+
+            let exports = pe.exports().unwrap();
+            assert_eq!(
+                exports,
+                [
+                    Export {
+                        name: Some("Real".to_owned()),
+                        ordinal: 5,
+                        rva: 0x1000,
+                        forwarder: None,
+                    },
+                    Export {
+                        name: Some("Fwd".to_owned()),
+                        ordinal: 6,
+                        rva: exports[1].rva,
+                        forwarder: Some("NTDLL.RtlFoo".to_owned()),
+                    },
+                    // by ordinal only, and the hole is skipped
+                    Export {
+                        name: None,
+                        ordinal: 7,
+                        rva: 0x1010,
+                        forwarder: None,
+                    },
+                ]
+            );
+
+            assert_eq!(pe.export("Real").unwrap().unwrap().rva, 0x1000);
+            assert!(pe.export("real").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn imports() {
+        for (layout, image) in both(&everything()) {
+            let pe = PeImage::parse(&image, layout).unwrap();
+            let imports = pe.imports().unwrap();
+
+            assert_eq!(imports.len(), 2);
+            assert_eq!(imports[0].dll, "KERNEL32.dll");
+            assert_eq!(imports[1].dll, "WS2_32.dll");
+
+            let kernel32 = &imports[0].functions;
+            assert_eq!(kernel32[0].name.as_deref(), Some("GetTickCount"));
+            assert_eq!(kernel32[1].name.as_deref(), Some("Sleep"));
+            assert_eq!(kernel32[1].iat_rva, kernel32[0].iat_rva + 8);
+
+            assert_eq!(
+                imports[1].functions[0],
+                ImportedFunction {
+                    name: None,
+                    ordinal: Some(23),
+                    iat_rva: imports[1].functions[0].iat_rva,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn imports_without_lookup_table() {
+        let mut pe = PeBuilder::new();
+        pe.imports(&[("KERNEL32.dll", &[Imported::Name("Sleep")])], false);
+
+        // on disk the names are still in the address table
+        let file = pe.build(Layout::File);
+        let imports = PeImage::parse(&file, Layout::File)
+            .unwrap()
+            .imports()
+            .unwrap();
+        assert_eq!(imports[0].functions[0].name.as_deref(), Some("Sleep"));
+
+        // once mapped it's all addresses, so there are only slots
+        let mapped = pe.build(Layout::Mapped);
+        let imports = PeImage::parse(&mapped, Layout::Mapped)
+            .unwrap()
+            .imports()
+            .unwrap();
+
+        let sleep = &imports[0].functions[0];
+        assert_eq!((&sleep.name, sleep.ordinal), (&None, None));
+        assert_eq!(sleep.iat_rva, imports_iat(&file));
+    }
+
+    /// Where the address table of the first import is, from its descriptor
+    fn imports_iat(file: &[u8]) -> u32 {
+        let pe = PeImage::parse(file, Layout::File).unwrap();
+        let (dir, _) = pe.dir(DIR_IMPORT).unwrap();
+
+        u32_at(file, pe.rva_to_offset(dir).unwrap() + 16).unwrap()
+    }
+
+    #[test]
+    fn codeview() {
+        for (layout, image) in both(&everything()) {
+            let codeview = PeImage::parse(&image, layout)
+                .unwrap()
+                .codeview()
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(codeview.guid, GUID);
+            assert_eq!(codeview.age, 3);
+            assert_eq!(
+                codeview.guid_string(),
+                "12345678-9ABC-DEF0-0102-030405060708"
+            );
+            assert_eq!(codeview.symbol_id(), "123456789ABCDEF001020304050607083");
+            assert_eq!(
+                codeview.to_string(),
+                r"C:\build\test.pdb (123456789ABCDEF001020304050607083)"
+            );
+        }
+    }
+
+    #[test]
+    fn version_info() {
+        for (layout, image) in both(&everything()) {
+            let info = PeImage::parse(&image, layout)
+                .unwrap()
+                .version_info()
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(info.file_version, Some([4, 1, 1, 5]));
+            assert_eq!(info.product_version, Some([4, 1, 1, 5]));
+            assert_eq!(info.file_version_string().as_deref(), Some("4.1.1.5"));
+
+            assert_eq!(info.string("ProductName"), Some("Test Game"));
+            assert_eq!(info.string("FileDescription"), Some(""));
+            assert_eq!(info.string("CompanyName"), None);
+        }
+    }
+
+    #[test]
+    fn nothing_to_find() {
+        let mut pe = PeBuilder::new();
+        pe.section(".text", CODE, vec![0xC3]);
+
+        for (layout, image) in both(&pe) {
+            let pe = PeImage::parse(&image, layout).unwrap();
+
+            assert!(pe.exports().unwrap().is_empty());
+            assert!(pe.imports().unwrap().is_empty());
+            assert!(pe.codeview().unwrap().is_none());
+            assert!(pe.version_info().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn not_a_pe() {
+        for garbage in [&b""[..], b"MZ", &[0; 0x100], b"PE\0\0"] {
+            assert!(PeImage::parse(garbage, Layout::File).is_err());
+        }
+
+        let image = everything().build(Layout::File);
+
+        // e_lfanew pointing nowhere
+        let mut bad = image.clone();
+        bad[0x3C..0x40].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert!(PeImage::parse(&bad, Layout::File).is_err());
+
+        // unknown optional header magic
+        let mut bad = image.clone();
+        bad[0x58..0x5A].copy_from_slice(&0x107u16.to_le_bytes());
+        assert!(PeImage::parse(&bad, Layout::File).is_err());
+
+        // more sections than there's room for
+        let mut bad = image;
+        bad[0x46..0x48].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        assert!(PeImage::parse(&bad, Layout::File).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let image = everything().build(Layout::File);
+
+        // cut off in the headers
+        for len in [0x40, 0x50, 0x100, SECTION_TABLE_END] {
+            assert!(PeImage::parse(&image[..len], Layout::File).is_err());
+        }
+
+        // the headers are fine, the tables aren't
+        let headers = PeImage::parse(&image[..0x400], Layout::File).unwrap();
+        assert!(headers.exports().is_err());
+        assert!(headers.imports().is_err());
+        assert!(headers.codeview().is_err());
+        assert!(headers.version_info().is_err());
+
+        // every other length at least doesn't panic
+        for len in 0..image.len() {
+            for layout in [Layout::File, Layout::Mapped] {
+                if let Ok(pe) = PeImage::parse(&image[..len], layout) {
+                    _ = (pe.exports(), pe.imports(), pe.codeview(), pe.version_info());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_tables() {
+        let mut image = everything().build(Layout::Mapped);
+
+        // a function count that would be a lot of memory
+        let edata = 0x2000;
+        image[edata + 20..edata + 24].copy_from_slice(&u32::MAX.to_le_bytes());
+        // an rva way outside of the image
+        image[edata + 32..edata + 36].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+
+        let pe = PeImage::parse(&image, Layout::Mapped).unwrap();
+        assert!(pe.exports().is_err());
+
+        // the rest is untouched
+        assert_eq!(pe.imports().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn corrupted_images_dont_panic() {
+        let image = everything().build(Layout::File);
+        let mut seed = 12345u64;
+
+        for round in 0..5000 {
+            let mut bad = image.clone();
+
+            for _ in 0..round % 16 + 1 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+
+                let at = (seed >> 33) as usize % bad.len();
+                bad[at] = (seed >> 20) as u8;
+            }
+
+            for layout in [Layout::File, Layout::Mapped] {
+                if let Ok(pe) = PeImage::parse(&bad, layout) {
+                    _ = (pe.exports(), pe.imports(), pe.codeview(), pe.version_info());
+                }
+            }
+        }
+    }
+}
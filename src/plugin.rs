@@ -0,0 +1,189 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use eyre::{Result, bail};
+use windows::Win32::Foundation::HINSTANCE;
+
+use crate::{
+    config::{CONFIG_FILENAME, Config},
+    deferred::Condition,
+    game_version::SupportedVersion,
+    init_state::{self, InitState},
+    paths::{get_dll_dir, get_dll_dir_filepath, get_dll_logs_dir},
+    shutdown::{self, Detach},
+    utils::ThreadedWrapper,
+};
+
+/// All of your plugin code goes in an implementation of this trait!
+///
+/// The template takes care of everything else (`DllMain`/`Init`, logging, panics, loading the config),
+/// then calls into these at the right time. Register your implementation with [`register_plugin!`]
+/// in `lib.rs`.
+///
+/// To log to the logfile, use the log macros: `log::debug!()`, `log::info!()`, `log::warn!()`, `log::error!()`
+pub trait Plugin: Send + Sync + 'static {
+    /// Conditions the game has to reach before `on_load` is called
+    ///
+    /// If there are any, `on_load` is called from a background thread once they're all met
+    /// instead of straight from `Init`. See [`Condition`]
+    fn ready_when(&self, _ctx: &Context) -> Vec<Condition> {
+        Vec::new()
+    }
+
+    /// Game builds this plugin is known to work with
+    ///
+    /// If this isn't empty and the game isn't one of them, the plugin runs unverified, or without
+    /// hooks and patches, depending on `unknown_game_version` in the config. See [`SupportedVersion`]
+    fn supported_versions(&self) -> &[SupportedVersion] {
+        &[]
+    }
+
+    /// Called once from `Init` after logging and the config are set up
+    ///
+    /// Returning an error logs it. Panics are caught and logged too, but it's much cleaner
+    /// to bubble up an error instead
+    fn on_load(&self, ctx: &Context) -> Result<()>;
+
+    /// Called after [`Context::reload_config`] swapped in a new config
+    fn on_config_changed(&self, _ctx: &Context) {}
+
+    /// Called on `DLL_PROCESS_DETACH`, after everything registered with `shutdown::register`
+    /// since `on_load` was torn down. The same rules apply here; we're under the loader lock
+    fn on_unload(&self, _ctx: &Context, _reason: Detach) {}
+}
+
+/// Everything a plugin needs to know about where it lives
+pub struct Context {
+    module: ThreadedWrapper<HINSTANCE>,
+    dll_dir: PathBuf,
+    logs_dir: PathBuf,
+    config_path: PathBuf,
+    config: RwLock<Arc<Config>>,
+    plugin: &'static dyn Plugin,
+}
+
+static CONTEXT: OnceLock<Context> = OnceLock::new();
+
+impl Context {
+    fn new(module: HINSTANCE, config: Config, plugin: &'static dyn Plugin) -> Result<Self> {
+        let config_path = get_dll_dir_filepath(module, CONFIG_FILENAME)?;
+
+        let ctx = Self {
+            module: unsafe { ThreadedWrapper::new(module) },
+            dll_dir: get_dll_dir(module)?.clone(),
+            logs_dir: get_dll_logs_dir(module)?,
+            config_path,
+            config: RwLock::new(Arc::new(config)),
+            plugin,
+        };
+
+        Ok(ctx)
+    }
+
+    /// Our dll's module handle
+    pub fn module(&self) -> HINSTANCE {
+        *self.module.inner()
+    }
+
+    /// The folder our dll is in
+    pub fn dll_dir(&self) -> &Path {
+        &self.dll_dir
+    }
+
+    /// `<dll_dir>\logs\`
+    pub fn logs_dir(&self) -> &Path {
+        &self.logs_dir
+    }
+
+    /// `<dll_dir>\my-config.toml`
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    /// The currently loaded config
+    ///
+    /// This is a snapshot; hold on to it as long as you like, a reload won't change it under you
+    pub fn config(&self) -> Arc<Config> {
+        match self.config.read() {
+            Ok(config) => config.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Where the dll is in its lifecycle
+    ///
+    /// Still `Initializing` during `on_load`. Long running work on another thread can check for
+    /// `ShuttingDown` to stop early
+    pub fn init_state(&self) -> InitState {
+        init_state::state()
+    }
+
+    /// Load the config from disk again and let the plugin know about it
+    pub fn reload_config(&self) -> Result<()> {
+        let config = Config::load(&self.config_path)?;
+
+        match self.config.write() {
+            Ok(mut c) => *c = Arc::new(config),
+            Err(e) => *e.into_inner() = Arc::new(config),
+        }
+
+        self.plugin.on_config_changed(self);
+
+        Ok(())
+    }
+}
+
+/// Set up the plugin context
+///
+/// Fails if the plugin was already loaded
+pub fn load(
+    module: HINSTANCE,
+    config: Config,
+    plugin: &'static dyn Plugin,
+) -> Result<&'static Context> {
+    let ctx = Context::new(module, config, plugin)?;
+
+    if CONTEXT.set(ctx).is_err() {
+        bail!("plugin is already loaded");
+    }
+
+    let ctx = CONTEXT.get().unwrap();
+
+    // registered before on_load, so it is torn down after everything on_load registered
+    shutdown::register("plugin", |reason| ctx.plugin.on_unload(ctx, reason));
+
+    Ok(ctx)
+}
+
+/// The conditions the plugin wants to wait on before `start`
+pub fn ready_conditions(ctx: &'static Context) -> Vec<Condition> {
+    ctx.plugin.ready_when(ctx)
+}
+
+/// The game versions the plugin supports
+pub fn supported_versions(ctx: &'static Context) -> &'static [SupportedVersion] {
+    ctx.plugin.supported_versions()
+}
+
+/// Run the plugin's `on_load`
+pub fn start(ctx: &'static Context) -> Result<()> {
+    ctx.plugin.on_load(ctx)
+}
+
+/// Register the type implementing [`Plugin`]. It must implement `Default`
+///
+/// ```ignore
+/// register_plugin!(my_plugin::MyPlugin);
+/// ```
+macro_rules! register_plugin {
+    ($plugin:ty) => {
+        fn plugin() -> &'static dyn $crate::plugin::Plugin {
+            static PLUGIN: ::std::sync::OnceLock<$plugin> = ::std::sync::OnceLock::new();
+            PLUGIN.get_or_init(<$plugin as ::std::default::Default>::default)
+        }
+    };
+}
+
+pub(crate) use register_plugin;
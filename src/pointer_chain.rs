@@ -0,0 +1,446 @@
+use std::{
+    fmt::{self, Display},
+    mem,
+    str::FromStr,
+    time::Duration,
+};
+
+use eyre::{OptionExt as _, Report, Result, bail, eyre};
+use libmem::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{CurrentProcess, MemoryBackend, Pod},
+    scheduler::{self, RunOn, TimerHandle},
+};
+
+/// Where a [`PointerChain`] starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Base {
+    /// `game.exe+0x1234`
+    Module { name: String, offset: usize },
+    /// A fixed address, e.g. `0x7ff6a0001234`
+    Address(Address),
+}
+
+/// A multi-level pointer, e.g. `game.exe+0x1234, 0x10, 0x48`
+///
+/// Same as in Cheat Engine: the pointer at the base is read, the first offset added, the pointer
+/// there is read, the next offset added, and so on. What's left at the end is the address of the value.
+///
+/// Parse one from a string (also works straight from the config), then [`PointerChain::read`] it.
+/// Every pointer on the way is read with [`MemoryBackend`], so a null or freed pointer somewhere in the
+/// chain is an error saying where, instead of a crash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PointerChain {
+    base: Base,
+    offsets: Vec<isize>,
+}
+
+impl PointerChain {
+    pub fn new(base: Base, offsets: Vec<isize>) -> Self {
+        Self { base, offsets }
+    }
+
+    pub fn base(&self) -> &Base {
+        &self.base
+    }
+
+    pub fn offsets(&self) -> &[isize] {
+        &self.offsets
+    }
+
+    /// Follow the chain to the address of the value
+    pub fn resolve(&self, memory: &impl MemoryBackend) -> Result<Address> {
+        let mut address = match &self.base {
+            Base::Module { name, offset } => memory
+                .find_module(name)
+                .ok_or_eyre(format!("{self}: base: module {name} is not loaded"))?
+                .base
+                .checked_add(*offset)
+                .ok_or_eyre(format!(
+                    "{self}: base: {name}+{offset:#x} is outside of the address space"
+                ))?,
+
+            Base::Address(address) => *address,
+        };
+
+        for (level, offset) in self.offsets.iter().enumerate() {
+            let pointer = memory
+                .read::<Address>(address)
+                .map_err(|e| eyre!("{self}: level {}: {e}", level + 1))?;
+
+            if pointer == 0 {
+                bail!(
+                    "{self}: level {}: pointer at {address:#x} is null",
+                    level + 1
+                );
+            }
+
+            address = pointer.checked_add_signed(*offset).ok_or_eyre(format!(
+                "{self}: level {}: offset from {pointer:#x} is outside of the address space",
+                level + 1
+            ))?;
+        }
+
+        Ok(address)
+    }
+
+    /// Follow the chain and read the value at the end
+    pub fn read<T: Pod>(&self, memory: &impl MemoryBackend) -> Result<T> {
+        let address = self.resolve(memory)?;
+
+        memory
+            .read::<T>(address)
+            .map_err(|e| eyre!("{self}: value: {e}"))
+    }
+
+    /// Call `callback` every time the value at the end of the chain changes, checking every `interval`
+    ///
+    /// This includes the chain breaking (e.g. the object it points into is freed) and resolving again.
+    /// The value starts out as `None`, so the first successful read is a change too.
+    /// Cancel the returned handle to stop watching
+    pub fn watch<T>(
+        &self,
+        interval: Duration,
+        run_on: RunOn,
+        mut callback: impl FnMut(Change<T>) + Send + 'static,
+    ) -> TimerHandle
+    where
+        T: Pod + PartialEq + Send,
+    {
+        let mut watcher = Watcher::new(self.clone());
+
+        scheduler::repeat(interval, run_on, move || {
+            if let Some(change) = watcher.poll(&CurrentProcess) {
+                callback(change);
+            }
+        })
+    }
+}
+
+impl FromStr for PointerChain {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',').map(str::trim);
+
+        let base = parts.next().unwrap_or_default();
+        if base.is_empty() {
+            bail!("pointer chain `{s}` has no base");
+        }
+
+        let base = if let Ok(address) = parse_number(base) {
+            Base::Address(address as Address)
+        } else if let Some((name, offset)) = base.rsplit_once('+') {
+            let offset = parse_number(offset.trim())
+                .ok()
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_eyre(format!("invalid base offset `{offset}` in `{s}`"))?;
+
+            Base::Module {
+                name: name.trim().to_owned(),
+                offset,
+            }
+        } else {
+            Base::Module {
+                name: base.to_owned(),
+                offset: 0,
+            }
+        };
+
+        let offsets = parts
+            .map(|offset| parse_number(offset).map_err(|e| eyre!("{e} in `{s}`")))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { base, offsets })
+    }
+}
+
+impl TryFrom<String> for PointerChain {
+    type Error = Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<PointerChain> for String {
+    fn from(chain: PointerChain) -> Self {
+        chain.to_string()
+    }
+}
+
+impl Display for PointerChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.base {
+            Base::Module { name, offset: 0 } => write!(f, "{name}")?,
+            Base::Module { name, offset } => write!(f, "{name}+{offset:#x}")?,
+            Base::Address(address) => write!(f, "{address:#x}")?,
+        }
+
+        for offset in &self.offsets {
+            if *offset < 0 {
+                write!(f, ", -{:#x}", offset.unsigned_abs())?;
+            } else {
+                write!(f, ", {offset:#x}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `0x10`, `-0x8` or `16`
+fn parse_number(s: &str) -> Result<isize> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+
+    let n = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => digits.parse::<usize>(),
+    }
+    .map_err(|_| eyre!("invalid number `{s}`"))?;
+
+    // addresses can have the top bit set, e.g. kernel space or sign-extended ones
+    let n = n as isize;
+
+    Ok(if negative { n.wrapping_neg() } else { n })
+}
+
+/// The value at the end of a watched [`PointerChain`] changed
+///
+/// `None` means the chain didn't resolve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change<T> {
+    pub old: Option<T>,
+    pub new: Option<T>,
+}
+
+/// Remembers the last value at the end of a chain, to tell when it changes
+///
+/// [`PointerChain::watch`] polls one of these on the scheduler. Use it directly to poll yourself,
+/// e.g. once per frame
+pub struct Watcher<T> {
+    chain: PointerChain,
+    last: Option<T>,
+}
+
+impl<T: Pod + PartialEq> Watcher<T> {
+    pub fn new(chain: PointerChain) -> Self {
+        Self { chain, last: None }
+    }
+
+    pub fn chain(&self) -> &PointerChain {
+        &self.chain
+    }
+
+    /// The value as of the last poll
+    pub fn last(&self) -> Option<T> {
+        self.last
+    }
+
+    /// Read the value again, and return the change if it's different from last time
+    pub fn poll(&mut self, memory: &impl MemoryBackend) -> Option<Change<T>> {
+        let new = self.chain.read::<T>(memory).ok();
+
+        if new == self.last {
+            return None;
+        }
+
+        let old = mem::replace(&mut self.last, new);
+
+        Some(Change { old, new })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    /// `game.exe` at 0x1000, with `game.exe+0x10 -> 0x1040`, `0x1048 -> 0x1060`, and 42 at 0x1058
+    fn game() -> FakeMemory {
+        let mut data = vec![0; 0x100];
+        data[0x10..0x18].copy_from_slice(&0x1040usize.to_le_bytes());
+        data[0x48..0x50].copy_from_slice(&0x1060usize.to_le_bytes());
+        data[0x58..0x5C].copy_from_slice(&42u32.to_le_bytes());
+
+        let memory = FakeMemory::new();
+        memory.map(0x1000, data, Prot::RW);
+        memory.add_module("game.exe", 0x1000, 0x100);
+
+        memory
+    }
+
+    fn chain(s: &str) -> PointerChain {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            chain("game.exe+0x10, 0x8, -0x8"),
+            PointerChain::new(
+                Base::Module {
+                    name: "game.exe".to_owned(),
+                    offset: 0x10
+                },
+                vec![8, -8]
+            )
+        );
+
+        assert_eq!(
+            chain(" 0x7ff6a0001234 ,16"),
+            PointerChain::new(Base::Address(0x7ff6a0001234), vec![16])
+        );
+
+        assert_eq!(
+            chain("client.dll").base(),
+            &Base::Module {
+                name: "client.dll".to_owned(),
+                offset: 0
+            }
+        );
+
+        for bad in [
+            "",
+            " , 0x10",
+            "game.exe+zz",
+            "game.exe+-0x10",
+            "game.exe, 0x1G",
+        ] {
+            assert!(bad.parse::<PointerChain>().is_err(), "`{bad}` parsed");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "game.exe+0x10, 0x8, -0x8",
+            "game.exe, 0x0",
+            "0x7ff6a0001234",
+        ] {
+            assert_eq!(chain(s).to_string(), s);
+        }
+
+        assert_eq!(chain("game.exe+16, 8").to_string(), "game.exe+0x10, 0x8");
+    }
+
+    #[test]
+    fn walk() {
+        let memory = game();
+        let chain = chain("game.exe+0x10, 0x8, -0x8");
+
+        assert_eq!(chain.resolve(&memory).unwrap(), 0x1058);
+        assert_eq!(chain.read::<u32>(&memory).unwrap(), 42);
+
+        // no offsets is just the base
+        assert_eq!(self::chain("0x1058").read::<u32>(&memory).unwrap(), 42);
+    }
+
+    #[test]
+    fn broken_chains() {
+        let memory = game();
+        let chain = chain("game.exe+0x10, 0x8, -0x8");
+
+        memory.write::<usize>(0x1048, 0).unwrap();
+        assert_eq!(
+            chain.resolve(&memory).unwrap_err().to_string(),
+            "game.exe+0x10, 0x8, -0x8: level 2: pointer at 0x1048 is null"
+        );
+
+        memory.write::<usize>(0x1048, 0xDEAD_0000).unwrap();
+        assert_eq!(
+            chain.read::<u32>(&memory).unwrap_err().to_string(),
+            "game.exe+0x10, 0x8, -0x8: value: 4 bytes at 0xdeacfff8 aren't mapped"
+        );
+
+        assert_eq!(
+            self::chain("other.dll, 0x8")
+                .resolve(&memory)
+                .unwrap_err()
+                .to_string(),
+            "other.dll, 0x8: base: module other.dll is not loaded"
+        );
+    }
+
+    #[test]
+    fn out_of_the_address_space() {
+        let memory = game();
+
+        // can't be parsed, offsets are at most isize::MAX
+        let base = PointerChain::new(
+            Base::Module {
+                name: "game.exe".to_owned(),
+                offset: usize::MAX - 0xF,
+            },
+            vec![8],
+        );
+
+        assert_eq!(
+            base.resolve(&memory).unwrap_err().to_string(),
+            "game.exe+0xfffffffffffffff0, 0x8: base: game.exe+0xfffffffffffffff0 is outside of the address space"
+        );
+
+        assert_eq!(
+            chain("game.exe+0x10, -0x2000")
+                .resolve(&memory)
+                .unwrap_err()
+                .to_string(),
+            "game.exe+0x10, -0x2000: level 1: offset from 0x1040 is outside of the address space"
+        );
+    }
+
+    #[test]
+    fn watcher() {
+        let memory = game();
+        let mut watcher = Watcher::<u32>::new(chain("game.exe+0x10, 0x8, -0x8"));
+
+        assert_eq!(
+            watcher.poll(&memory),
+            Some(Change {
+                old: None,
+                new: Some(42)
+            })
+        );
+        assert_eq!(watcher.poll(&memory), None);
+
+        memory.write::<u32>(0x1058, 7).unwrap();
+        assert_eq!(
+            watcher.poll(&memory),
+            Some(Change {
+                old: Some(42),
+                new: Some(7)
+            })
+        );
+
+        // the chain breaks, then comes back
+        memory.write::<usize>(0x1048, 0).unwrap();
+        assert_eq!(
+            watcher.poll(&memory),
+            Some(Change {
+                old: Some(7),
+                new: None
+            })
+        );
+        assert_eq!(watcher.last(), None);
+
+        memory.write::<usize>(0x1048, 0x1060).unwrap();
+        assert_eq!(
+            watcher.poll(&memory),
+            Some(Change {
+                old: None,
+                new: Some(7)
+            })
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::fmt::{self, Display};
+
+use eyre::{Result, bail, eyre};
+use libmem::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::memory::MemoryBackend;
+
+/// One step on the way from a pattern match (or any other address) to the address that's wanted
+///
+/// In the signature database these are written as e.g.
+/// `ops = ["follow", { rip_relative = { disp = 3, len = 7 } }, { add = 16 }, "deref", "non_null"]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Follow the `call rel32` (`E8`), `jmp rel32` (`E9`) or `jmp rel8` (`EB`) at the address
+    Follow,
+    /// Resolve a rip-relative operand: the rel32 `disp` bytes into the instruction is relative to
+    /// the end of the instruction, which is `len` bytes long.
+    ///
+    /// `mov rax, [rip+disp32]` (`48 8B 05 ..`) is `{ disp = 3, len = 7 }`, `lea rcx, [rip+disp32]`
+    /// (`48 8D 0D ..`) too
+    RipRelative { disp: usize, len: usize },
+    /// Add to the address. Negative to subtract
+    Add(isize),
+    /// Read the pointer at the address
+    Deref,
+    /// Fail if the address is null. e.g. a global singleton that isn't created yet
+    NonNull,
+}
+
+impl Step {
+    fn apply(&self, memory: &impl MemoryBackend, address: Address) -> Result<Address> {
+        let next = match *self {
+            Self::Follow => {
+                let opcode = memory.read::<u8>(address)?;
+                match opcode {
+                    0xE8 | 0xE9 => {
+                        let rel = memory.read::<i32>(offset(address, 1)?)?;
+                        offset(offset(address, 5)?, rel as isize)?
+                    }
+
+                    0xEB => {
+                        let rel = memory.read::<i8>(offset(address, 1)?)?;
+                        offset(offset(address, 2)?, rel as isize)?
+                    }
+
+                    _ => bail!("expected a call or jmp, found opcode {opcode:#04x}"),
+                }
+            }
+
+            Self::RipRelative { disp, len } => {
+                if disp.checked_add(4).is_none_or(|end| end > len) {
+                    bail!("disp32 at {disp} doesn't fit in a {len} byte instruction");
+                }
+
+                let at = address.checked_add(disp).ok_or_else(|| overflow(address))?;
+                let end = address.checked_add(len).ok_or_else(|| overflow(address))?;
+
+                let rel = memory.read::<i32>(at)?;
+                offset(end, rel as isize)?
+            }
+
+            Self::Add(n) => offset(address, n)?,
+
+            Self::Deref => memory.read::<Address>(address)?,
+
+            Self::NonNull => {
+                if address == 0 {
+                    bail!("address is null");
+                }
+
+                address
+            }
+        };
+
+        Ok(next)
+    }
+}
+
+/// `address + n`, failing instead of wrapping around the address space
+fn offset(address: Address, n: isize) -> Result<Address> {
+    address
+        .checked_add_signed(n)
+        .ok_or_else(|| overflow(address))
+}
+
+fn overflow(address: Address) -> eyre::Report {
+    eyre!("offset from {address:#x} is outside of the address space")
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Follow => write!(f, "follow"),
+            Self::RipRelative { disp, len } => write!(f, "rip_relative(disp {disp}, len {len})"),
+            Self::Add(n) if *n < 0 => write!(f, "add(-{:#x})", n.unsigned_abs()),
+            Self::Add(n) => write!(f, "add({n:#x})"),
+            Self::Deref => write!(f, "deref"),
+            Self::NonNull => write!(f, "non_null"),
+        }
+    }
+}
+
+/// A list of [`Step`]s, run in order
+///
+/// ```ignore
+/// // E8 ?? ?? ?? ?? -> the function being called, then the global its first instruction loads
+/// let resolver = Resolver::new()
+///     .follow()
+///     .rip_relative(3, 7)
+///     .deref()
+///     .non_null();
+///
+/// let instance = resolver.resolve(&CurrentProcess, found)?;
+/// ```
+///
+/// When a step fails, the error says which one, and at what address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resolver {
+    steps: Vec<Step>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_steps(steps: Vec<Step>) -> Self {
+        Self { steps }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// See [`Step::Follow`]
+    pub fn follow(self) -> Self {
+        self.step(Step::Follow)
+    }
+
+    /// See [`Step::RipRelative`]
+    pub fn rip_relative(self, disp: usize, len: usize) -> Self {
+        self.step(Step::RipRelative { disp, len })
+    }
+
+    /// Add `n` to the address. See [`Step::Add`]
+    pub fn offset(self, n: isize) -> Self {
+        self.step(Step::Add(n))
+    }
+
+    /// See [`Step::Deref`]
+    pub fn deref(self) -> Self {
+        self.step(Step::Deref)
+    }
+
+    /// See [`Step::NonNull`]
+    pub fn non_null(self) -> Self {
+        self.step(Step::NonNull)
+    }
+
+    /// Run every step, starting at `address`
+    pub fn resolve(&self, memory: &impl MemoryBackend, address: Address) -> Result<Address> {
+        self.steps
+            .iter()
+            .enumerate()
+            .try_fold(address, |address, (idx, step)| {
+                step.apply(memory, address)
+                    .map_err(|e| eyre!("step {} ({step}) at {address:#x}: {e}", idx + 1))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libmem::Prot;
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    const BASE: Address = 0x1000;
+
+    /// `code` at [`BASE`], with room after it
+    fn memory(code: &[u8]) -> FakeMemory {
+        let mut data = vec![0xCC; 0x200];
+        data[..code.len()].copy_from_slice(code);
+
+        let memory = FakeMemory::new();
+        memory.map(BASE, data, Prot::XR);
+        memory
+    }
+
+    #[test]
+    fn follow_call_and_jmp() {
+        // call +0x10, jmp -0x20, jmp short -2
+        let memory = memory(&[]);
+        memory.map(0x3000, vec![0xE8, 0x10, 0, 0, 0], Prot::XR);
+        memory.map(0x3100, vec![0xE9, 0xE0, 0xFF, 0xFF, 0xFF], Prot::XR);
+        memory.map(0x3200, vec![0xEB, 0xFE], Prot::XR);
+
+        let follow = Resolver::new().follow();
+
+        assert_eq!(follow.resolve(&memory, 0x3000).unwrap(), 0x3015);
+        assert_eq!(follow.resolve(&memory, 0x3100).unwrap(), 0x30E5);
+        assert_eq!(follow.resolve(&memory, 0x3200).unwrap(), 0x3200);
+
+        let err = follow.resolve(&memory, BASE).unwrap_err();
+        assert!(err.to_string().contains("found opcode 0xcc"), "{err}");
+    }
+
+    #[test]
+    fn rip_relative() {
+        // mov rax, [rip+0x20] / lea rcx, [rip-0x10]
+        let memory = memory(&[
+            0x48, 0x8B, 0x05, 0x20, 0, 0, 0, //
+            0x48, 0x8D, 0x0D, 0xF0, 0xFF, 0xFF, 0xFF,
+        ]);
+
+        let rip_relative = Resolver::new().rip_relative(3, 7);
+
+        assert_eq!(
+            rip_relative.resolve(&memory, BASE).unwrap(),
+            BASE + 7 + 0x20
+        );
+        assert_eq!(
+            rip_relative.resolve(&memory, BASE + 7).unwrap(),
+            BASE + 14 - 0x10
+        );
+
+        // the disp32 would run past the end of the instruction
+        assert!(
+            Resolver::new()
+                .rip_relative(4, 7)
+                .resolve(&memory, BASE)
+                .is_err()
+        );
+        assert!(
+            Resolver::new()
+                .rip_relative(usize::MAX, 7)
+                .resolve(&memory, BASE)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        let memory = memory(&[]);
+
+        assert_eq!(
+            Resolver::new().offset(0x10).resolve(&memory, BASE).unwrap(),
+            BASE + 0x10
+        );
+        assert_eq!(
+            Resolver::new()
+                .offset(-0x10)
+                .resolve(&memory, BASE)
+                .unwrap(),
+            BASE - 0x10
+        );
+
+        // doesn't touch memory
+        assert_eq!(Resolver::new().offset(1).resolve(&memory, 0).unwrap(), 1);
+
+        assert!(Resolver::new().offset(-1).resolve(&memory, 0).is_err());
+        assert!(
+            Resolver::new()
+                .offset(1)
+                .resolve(&memory, usize::MAX)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn jump_out_of_the_address_space() {
+        // jmp -0x100, from right above 0
+        let memory = FakeMemory::new();
+        memory.map(0x10, vec![0xE9, 0x00, 0xFF, 0xFF, 0xFF], Prot::XR);
+
+        let err = Resolver::new().follow().resolve(&memory, 0x10).unwrap_err();
+        assert!(
+            err.to_string().contains("outside of the address space"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn call_to_global() {
+        // call the function, whose first instruction loads the global, which points at the instance
+        let mut code = vec![0xE8, 0x0B, 0, 0, 0];
+        code.resize(0x10, 0xCC);
+        code.extend_from_slice(&[0x48, 0x8B, 0x05, 0x29, 0, 0, 0]);
+        code.resize(0x40, 0xCC);
+        code.extend_from_slice(&0x5000usize.to_le_bytes());
+
+        let memory = memory(&code);
+        let resolver = Resolver::new()
+            .follow()
+            .rip_relative(3, 7)
+            .deref()
+            .non_null();
+
+        assert_eq!(resolver.resolve(&memory, BASE).unwrap(), 0x5000);
+
+        // the instance isn't created yet
+        memory
+            .write_code(BASE + 0x40, &0usize.to_le_bytes())
+            .unwrap();
+
+        let err = resolver.resolve(&memory, BASE).unwrap_err();
+        assert_eq!(err.to_string(), "step 4 (non_null) at 0x0: address is null");
+    }
+}
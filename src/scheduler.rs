@@ -0,0 +1,496 @@
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, LazyLock, Mutex, Once,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::{dispatch::DispatchQueue, worker};
+
+/// Longest the scheduler thread sleeps before checking if it was cancelled
+const MAX_PARK: Duration = Duration::from_millis(100);
+
+/// The scheduler used by [`once`] and [`repeat`]. Runs on its own worker thread
+static SCHEDULER: LazyLock<Scheduler> = LazyLock::new(|| Scheduler::new(SystemClock::new()));
+
+/// Monotonic clock the scheduler runs on
+///
+/// Time is the duration since some fixed point; which one doesn't matter
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// The real clock
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when told to, so scheduling can be driven by hand
+#[derive(Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        if let Ok(mut now) = self.now.lock() {
+            *now += by;
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.lock().map(|n| *n).unwrap_or_default()
+    }
+}
+
+impl<C: Clock> Clock for Arc<C> {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+/// Where a timer's callback runs
+#[derive(Copy, Clone)]
+pub enum RunOn {
+    /// Right on the thread ticking the scheduler. Keep these short, they delay every other timer
+    Scheduler,
+    /// Dispatched to a queue, e.g. `dispatch::GAME_THREAD`, and run whenever that is drained
+    Queue(&'static DispatchQueue),
+}
+
+/// Handle to a scheduled timer. Dropping it does NOT cancel the timer
+#[derive(Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    /// Stop the timer. A callback that's already running (or dispatched) isn't interrupted
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+type Repeating = Arc<Mutex<Box<dyn FnMut() + Send>>>;
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    Repeat { interval: Duration, f: Repeating },
+}
+
+struct Timer {
+    id: u64,
+    due: Duration,
+    run_on: RunOn,
+    cancelled: Arc<AtomicBool>,
+    callback: Callback,
+}
+
+/// One-shot and repeating timers
+///
+/// Nothing runs by itself; something has to call [`Scheduler::tick`]. The global scheduler
+/// behind [`once`] and [`repeat`] does that from a worker thread
+pub struct Scheduler<C: Clock = SystemClock> {
+    clock: C,
+    timers: Mutex<Vec<Timer>>,
+    next_id: AtomicU64,
+    wake: Condvar,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            timers: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Run `f` once, `delay` from now
+    pub fn once(
+        &self,
+        delay: Duration,
+        run_on: RunOn,
+        f: impl FnOnce() + Send + 'static,
+    ) -> TimerHandle {
+        self.add(delay, run_on, Callback::Once(Box::new(f)))
+    }
+
+    /// Run `f` every `interval`, starting `interval` from now
+    ///
+    /// If the scheduler falls behind, missed runs are skipped rather than run back to back
+    pub fn repeat(
+        &self,
+        interval: Duration,
+        run_on: RunOn,
+        f: impl FnMut() + Send + 'static,
+    ) -> TimerHandle {
+        let callback = Callback::Repeat {
+            interval,
+            f: Arc::new(Mutex::new(Box::new(f))),
+        };
+
+        self.add(interval, run_on, callback)
+    }
+
+    fn add(&self, delay: Duration, run_on: RunOn, callback: Callback) -> TimerHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let timer = Timer {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            due: self.clock.now() + delay,
+            run_on,
+            cancelled: cancelled.clone(),
+            callback,
+        };
+
+        if let Ok(mut timers) = self.timers.lock() {
+            timers.push(timer);
+        }
+
+        // it may be due before whatever the ticking thread is currently sleeping on
+        self.wake.notify_all();
+
+        TimerHandle { cancelled }
+    }
+
+    /// Number of timers that are still scheduled
+    pub fn len(&self) -> usize {
+        self.timers.lock().map(|t| t.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run every timer that is due, in the order they were due
+    ///
+    /// Returns how long until the next timer is due, or `None` if there are none left
+    pub fn tick(&self) -> Option<Duration> {
+        let now = self.clock.now();
+
+        let mut due = {
+            let Ok(mut timers) = self.timers.lock() else {
+                return None;
+            };
+
+            timers.retain(|t| !t.cancelled.load(Ordering::Acquire));
+
+            let (due, pending) = mem::take(&mut *timers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|t| t.due <= now);
+
+            *timers = pending;
+            due
+        };
+
+        due.sort_by_key(|t| (t.due, t.id));
+
+        // callbacks run without the lock held, so they can schedule (or cancel) timers themselves
+        let mut rescheduled = Vec::new();
+        for mut timer in due {
+            // an earlier callback in this tick may have cancelled it
+            if timer.cancelled.load(Ordering::Acquire) {
+                continue;
+            }
+
+            match timer.callback {
+                Callback::Once(f) => run(timer.run_on, f),
+
+                Callback::Repeat { interval, ref f } => {
+                    let f = f.clone();
+                    let cancelled = timer.cancelled.clone();
+                    run(
+                        timer.run_on,
+                        Box::new(move || {
+                            // may have been cancelled while sitting in a queue
+                            if cancelled.load(Ordering::Acquire) {
+                                return;
+                            }
+
+                            if let Ok(mut f) = f.lock() {
+                                f();
+                            }
+                        }),
+                    );
+
+                    timer.due += interval;
+                    if timer.due <= now {
+                        timer.due = now + interval;
+                    }
+
+                    rescheduled.push(timer);
+                }
+            }
+        }
+
+        let Ok(mut timers) = self.timers.lock() else {
+            return None;
+        };
+
+        timers.extend(rescheduled);
+
+        let now = self.clock.now();
+        timers.iter().map(|t| t.due.saturating_sub(now)).min()
+    }
+
+    /// Sleep until `timeout` passes, a timer is added, or the next timer is due, whichever is first
+    ///
+    /// Doesn't sleep at all if a timer is already due. That also covers one added between the last
+    /// [`Scheduler::tick`] and this, whose wakeup would otherwise be missed
+    pub fn park(&self, timeout: Duration) {
+        let Ok(timers) = self.timers.lock() else {
+            return;
+        };
+
+        let now = self.clock.now();
+        let next = timers
+            .iter()
+            .filter(|t| !t.cancelled.load(Ordering::Acquire))
+            .map(|t| t.due.saturating_sub(now))
+            .min();
+
+        let timeout = match next {
+            Some(Duration::ZERO) => return,
+            Some(next) => next.min(timeout),
+            None => timeout,
+        };
+
+        _ = self.wake.wait_timeout(timers, timeout);
+    }
+}
+
+fn run(run_on: RunOn, f: Box<dyn FnOnce() + Send>) {
+    match run_on {
+        RunOn::Scheduler => {
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                // same as in Init; the panic hook already logged it
+                mem::forget(e);
+            }
+        }
+
+        RunOn::Queue(queue) => queue.dispatch(f),
+    }
+}
+
+/// Make sure the global scheduler's thread is running
+fn ensure_started() {
+    static START: Once = Once::new();
+
+    START.call_once(|| {
+        let result = worker::spawn("scheduler", |token| {
+            while !token.is_cancelled() {
+                let next = SCHEDULER.tick().unwrap_or(MAX_PARK);
+                SCHEDULER.park(next.min(MAX_PARK));
+            }
+        });
+
+        if let Err(e) = result {
+            error!("failed to start scheduler thread: {e}");
+        }
+    });
+}
+
+/// Run `f` once, `delay` from now, on the global scheduler
+///
+/// Use this instead of a `thread::sleep` loop
+pub fn once(delay: Duration, run_on: RunOn, f: impl FnOnce() + Send + 'static) -> TimerHandle {
+    ensure_started();
+    SCHEDULER.once(delay, run_on, f)
+}
+
+/// Run `f` every `interval` on the global scheduler
+pub fn repeat(interval: Duration, run_on: RunOn, f: impl FnMut() + Send + 'static) -> TimerHandle {
+    ensure_started();
+    SCHEDULER.repeat(interval, run_on, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn scheduler() -> (Arc<ManualClock>, Scheduler<Arc<ManualClock>>) {
+        let clock = Arc::new(ManualClock::new());
+        (clock.clone(), Scheduler::new(clock))
+    }
+
+    fn counter() -> (Arc<AtomicU64>, impl FnMut() + Send + 'static) {
+        let count = Arc::new(AtomicU64::new(0));
+        let f = {
+            let count = count.clone();
+            move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+
+        (count, f)
+    }
+
+    #[test]
+    fn once_fires_when_due() {
+        let (clock, scheduler) = scheduler();
+        let (count, f) = counter();
+
+        scheduler.once(10 * MS, RunOn::Scheduler, f);
+
+        assert_eq!(scheduler.tick(), Some(10 * MS));
+
+        clock.advance(9 * MS);
+        assert_eq!(scheduler.tick(), Some(MS));
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        clock.advance(MS);
+        assert_eq!(scheduler.tick(), None);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn repeat_fires_every_interval() {
+        let (clock, scheduler) = scheduler();
+        let (count, f) = counter();
+
+        scheduler.repeat(10 * MS, RunOn::Scheduler, f);
+
+        for n in 1..=3 {
+            clock.advance(10 * MS);
+            assert_eq!(scheduler.tick(), Some(10 * MS));
+            assert_eq!(count.load(Ordering::Relaxed), n);
+        }
+
+        clock.advance(5 * MS);
+        assert_eq!(scheduler.tick(), Some(5 * MS));
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn repeat_skips_missed_runs() {
+        let (clock, scheduler) = scheduler();
+        let (count, f) = counter();
+
+        scheduler.repeat(10 * MS, RunOn::Scheduler, f);
+
+        // 3.5 intervals late. runs once, and the next one is a full interval away
+        clock.advance(35 * MS);
+        assert_eq!(scheduler.tick(), Some(10 * MS));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        clock.advance(10 * MS);
+        scheduler.tick();
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn fires_in_due_order() {
+        let (clock, scheduler) = scheduler();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for (name, delay) in [("late", 20), ("early", 10), ("also early", 10)] {
+            let fired = fired.clone();
+            scheduler.once(delay * MS, RunOn::Scheduler, move || {
+                fired.lock().unwrap().push(name)
+            });
+        }
+
+        clock.advance(20 * MS);
+        scheduler.tick();
+
+        assert_eq!(*fired.lock().unwrap(), ["early", "also early", "late"]);
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let (clock, scheduler) = scheduler();
+        let (count, f) = counter();
+
+        let handle = scheduler.repeat(10 * MS, RunOn::Scheduler, f);
+
+        clock.advance(10 * MS);
+        scheduler.tick();
+
+        handle.cancel();
+        assert!(handle.is_cancelled());
+
+        clock.advance(10 * MS);
+        assert_eq!(scheduler.tick(), None);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn runs_on_queue() {
+        static QUEUE: DispatchQueue = DispatchQueue::new();
+
+        let (clock, scheduler) = scheduler();
+        let (count, f) = counter();
+
+        let handle = scheduler.repeat(10 * MS, RunOn::Queue(&QUEUE), f);
+
+        clock.advance(10 * MS);
+        scheduler.tick();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert_eq!(QUEUE.len(), 1);
+
+        QUEUE.drain(Duration::from_secs(1));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // cancelled while waiting in the queue
+        clock.advance(10 * MS);
+        scheduler.tick();
+        handle.cancel();
+
+        QUEUE.drain(Duration::from_secs(1));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn park_returns_when_due() {
+        let (clock, scheduler) = scheduler();
+
+        scheduler.once(10 * MS, RunOn::Scheduler, || ());
+        clock.advance(10 * MS);
+
+        // would otherwise sleep the whole timeout
+        let start = Instant::now();
+        scheduler.park(Duration::from_secs(30));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
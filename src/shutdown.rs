@@ -0,0 +1,61 @@
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::Mutex,
+};
+
+use log::{debug, error};
+
+/// Why we're being detached
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Detach {
+    /// Someone called `FreeLibrary` on us. The process keeps running, so everything we touched
+    /// (hooks, patches, handlers pointing into our dll) MUST be put back, or the game will
+    /// jump into unmapped memory the next time it calls one of them
+    FreeLibrary,
+    /// The process is exiting. All other threads are already gone, possibly in the middle of
+    /// holding a lock. Only do the bare minimum here, like flushing files; the OS frees the rest
+    ProcessExit,
+}
+
+type Teardown = Box<dyn FnOnce(Detach) + Send>;
+
+static TEARDOWNS: Mutex<Vec<(&'static str, Teardown)>> = Mutex::new(Vec::new());
+
+/// Register a teardown action which runs on `DLL_PROCESS_DETACH`
+///
+/// Teardowns run in reverse order of registration, so something registered during init
+/// is torn down after anything that was built on top of it.
+///
+/// These run inside `DllMain` under the loader lock! Do NOT join threads, wait on anything
+/// another thread may need to finish, or load/free libraries in here. Signal and move on.
+pub fn register(name: &'static str, teardown: impl FnOnce(Detach) + Send + 'static) {
+    let Ok(mut teardowns) = TEARDOWNS.lock() else {
+        error!("shutdown registry is poisoned, {name} will not be torn down");
+        return;
+    };
+
+    teardowns.push((name, Box::new(teardown)));
+}
+
+/// Run all registered teardowns in reverse order. Only meant to be called from `DllMain`
+pub fn run(reason: Detach) {
+    // on process exit another thread may have died while holding this lock, so never block on it
+    let teardowns = match TEARDOWNS.try_lock() {
+        Ok(mut teardowns) => mem::take(&mut *teardowns),
+        Err(_) => return,
+    };
+
+    for (name, teardown) in teardowns.into_iter().rev() {
+        debug!("tearing down {name} ({reason:?})");
+
+        // a panic here would unwind straight into the loader
+        let result = panic::catch_unwind(AssertUnwindSafe(|| teardown(reason)));
+        if let Err(e) = result {
+            // same as in Init; the panic hook already logged it
+            mem::forget(e);
+        }
+    }
+
+    log::logger().flush();
+}
@@ -0,0 +1,502 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+    time::Instant,
+};
+
+use eyre::{OptionExt as _, Report, Result, bail};
+use libmem::Address;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+#[cfg(windows)]
+use windows::Win32::Foundation::HINSTANCE;
+
+#[cfg(windows)]
+use crate::paths::get_dll_dir_filepath;
+use crate::{
+    game_version,
+    memory::{CurrentProcess, MemoryBackend},
+    pattern::{Pattern, scan_module},
+    resolver::{Resolver, Step},
+};
+
+/// Name of the signature database, next to the dll
+pub const SIGNATURES_FILENAME: &str = "my-signatures.toml";
+/// Name of the scan results cache, next to the dll
+const CACHE_FILENAME: &str = "my-signatures.cache.toml";
+
+/// Written when there's no signature database yet, so there's something to go off of
+const EXAMPLE: &str = r#"# Every signature is scanned for once, and where it matched is cached per game version
+# in my-signatures.cache.toml. Delete that file to force a rescan.
+#
+# [[signature]]
+# name = "GameUpdate"
+# # which module to scan. leave it out for the game's exe
+# module = "game.exe"
+# pattern = "E8 ?? ?? ?? ?? 48 8B 5C 24 ?? 48 83 C4 20"
+# # added to the match before the ops run
+# offset = 0
+# # how to get from the match to the address we want, in order:
+# #   "follow"                                follow the call/jmp at the address
+# #   { rip_relative = { disp = 3, len = 7 } }  resolve e.g. `mov rax, [rip+disp32]`
+# #   { add = 16 }                            add to the address
+# #   "deref"                                 read the pointer at the address
+# #   "non_null"                              fail if the address is null
+# ops = ["follow"]
+"#;
+
+/// One entry of the signature database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    /// Module to scan. The game's exe if there is none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// IDA-style pattern, e.g. `48 8B ?? ?? 89`
+    pub pattern: String,
+    /// Added to the match before the ops run
+    #[serde(default)]
+    pub offset: isize,
+    /// How to get from the match to the address we want, in order. See [`Step`]
+    #[serde(default)]
+    pub ops: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+struct Database {
+    #[serde(default, rename = "signature")]
+    signatures: Vec<Signature>,
+}
+
+/// The cache, keyed by the game's exe. If it changed, the whole cache is thrown away
+#[derive(Serialize, Deserialize)]
+struct Cache {
+    game: String,
+    #[serde(default)]
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+/// Only the match is cached. The ops run again on every load, since what they deref can be
+/// somewhere else every launch
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Where the pattern matched, relative to the module base
+    match_rva: usize,
+    /// The definition it was found from. If that changes, the entry is stale
+    signature: Signature,
+}
+
+/// Addresses from the signature database, resolved for the running game
+///
+/// ```ignore
+/// let signatures = Signatures::load(ctx.module())?;
+/// let update = signatures.get("GameUpdate")?;
+/// ```
+pub struct Signatures {
+    resolved: HashMap<String, Address>,
+    failed: HashMap<String, Report>,
+}
+
+impl Signatures {
+    /// Resolve every signature in `<dll_dir>\my-signatures.toml`, using the cache where it's still valid
+    ///
+    /// A signature that doesn't resolve doesn't fail the others. It's logged, and [`Signatures::get`]
+    /// returns why it failed
+    #[cfg(windows)]
+    pub fn load(module: HINSTANCE) -> Result<Self> {
+        let path = get_dll_dir_filepath(module, SIGNATURES_FILENAME)?;
+        let cache_path = get_dll_dir_filepath(module, CACHE_FILENAME)?;
+
+        Self::load_from(&path, &cache_path)
+    }
+
+    /// Same as [`Signatures::load`], with your own paths
+    pub fn load_from(path: &Path, cache_path: &Path) -> Result<Self> {
+        // the hash of the whole exe catches every update, even ones that don't bump any version
+        // number. that's a bit of reading on launch, but it's a lot faster than scanning
+        let game = game_version::current()?;
+        let game_id = format!("{:016x}", game.hash()?);
+
+        Self::load_with(&CurrentProcess, game.name(), game_id, path, cache_path)
+    }
+
+    /// Resolve the signatures in `memory`. `exe` is the module to scan when a signature doesn't
+    /// say, and the cache is only used if it was made for the same `game_id`
+    fn load_with(
+        memory: &impl MemoryBackend,
+        exe: &str,
+        game_id: String,
+        path: &Path,
+        cache_path: &Path,
+    ) -> Result<Self> {
+        let start = Instant::now();
+
+        if !path.exists() {
+            fs::write(path, EXAMPLE)?;
+        }
+
+        let data = fs::read_to_string(path)?;
+        let database = toml::from_str::<Database>(&data)?;
+
+        let mut cache = load_cache(cache_path, game_id);
+        let mut dirty = false;
+
+        let mut signatures = Self {
+            resolved: HashMap::new(),
+            failed: HashMap::new(),
+        };
+
+        let mut cached = 0;
+        for signature in database.signatures {
+            let name = signature.name.clone();
+            let module = signature.module.as_deref().unwrap_or(exe);
+
+            let found = match cache
+                .entries
+                .get(&name)
+                .and_then(|entry| from_cache(memory, entry, &signature, module))
+            {
+                Some(found) => {
+                    cached += 1;
+                    Ok(found)
+                }
+
+                None => scan(memory, &signature, module).map(|(found, entry)| {
+                    cache.entries.insert(name.clone(), entry);
+                    dirty = true;
+                    found
+                }),
+            };
+
+            match found.and_then(|found| run_ops(memory, &signature, found)) {
+                Ok(address) => {
+                    debug!("signature {name} at {address:#x}");
+                    signatures.resolved.insert(name, address);
+                }
+
+                Err(e) => {
+                    error!("signature {name} (`{}`): {e}", signature.pattern);
+
+                    // scanned again next time
+                    if cache.entries.remove(&name).is_some() {
+                        dirty = true;
+                    }
+
+                    signatures.failed.insert(name, e);
+                }
+            }
+        }
+
+        if dirty && let Err(e) = save_cache(cache_path, &cache) {
+            warn!("failed to save signature cache: {e}");
+        }
+
+        info!(
+            "resolved {} signatures ({cached} cached, {} failed) in {}ms",
+            signatures.resolved.len(),
+            signatures.failed.len(),
+            start.elapsed().as_millis()
+        );
+
+        Ok(signatures)
+    }
+
+    /// Address of the signature by this name
+    pub fn get(&self, name: &str) -> Result<Address> {
+        if let Some(address) = self.resolved.get(name) {
+            return Ok(*address);
+        }
+
+        match self.failed.get(name) {
+            Some(e) => bail!("signature {name} failed to resolve: {e}"),
+            None => bail!("no signature named {name} in {SIGNATURES_FILENAME}"),
+        }
+    }
+
+    /// Names and addresses of every signature that resolved
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Address)> {
+        self.resolved
+            .iter()
+            .map(|(name, addr)| (name.as_str(), *addr))
+    }
+}
+
+/// Where the cache says the pattern matched, if it was made from the same definition and the
+/// pattern still matches there
+fn from_cache(
+    memory: &impl MemoryBackend,
+    entry: &CacheEntry,
+    signature: &Signature,
+    module: &str,
+) -> Option<Address> {
+    if entry.signature != *signature {
+        return None;
+    }
+
+    let pattern = Pattern::parse(&signature.pattern).ok()?;
+    let module = memory.find_module(module)?;
+
+    if entry.match_rva + pattern.len() > module.size {
+        return None;
+    }
+
+    let found = module.base + entry.match_rva;
+
+    let mut data = vec![0; pattern.len()];
+    memory.read_bytes(found, &mut data).ok()?;
+
+    pattern.matches_at(&data, 0).then_some(found)
+}
+
+/// Scan for a signature. Returns the match, and the cache entry for it
+fn scan(
+    memory: &impl MemoryBackend,
+    signature: &Signature,
+    module_name: &str,
+) -> Result<(Address, CacheEntry)> {
+    let pattern = Pattern::parse(&signature.pattern)?;
+    let module = memory
+        .find_module(module_name)
+        .ok_or_eyre(format!("module {module_name} is not loaded"))?;
+
+    let found = scan_module(memory, module_name, &pattern)?
+        .ok_or_eyre(format!("pattern not found in {module_name}"))?;
+
+    let entry = CacheEntry {
+        match_rva: found - module.base,
+        signature: signature.clone(),
+    };
+
+    Ok((found, entry))
+}
+
+/// Get from the match to the address we want
+fn run_ops(memory: &impl MemoryBackend, signature: &Signature, found: Address) -> Result<Address> {
+    Resolver::from_steps(signature.ops.clone())
+        .resolve(memory, found.wrapping_add_signed(signature.offset))
+}
+
+/// Load the cache, or start a new one if it's missing, broken, or from another game version
+fn load_cache(path: &Path, game: String) -> Cache {
+    let fresh = |game| Cache {
+        game,
+        entries: BTreeMap::new(),
+    };
+
+    let Ok(data) = fs::read_to_string(path) else {
+        return fresh(game);
+    };
+
+    match toml::from_str::<Cache>(&data) {
+        Ok(cache) if cache.game == game => cache,
+
+        Ok(_) => {
+            info!("game changed since the signatures were cached, rescanning");
+            fresh(game)
+        }
+
+        Err(e) => {
+            warn!("signature cache is broken, rescanning: {e}");
+            fresh(game)
+        }
+    }
+}
+
+fn save_cache(path: &Path, cache: &Cache) -> Result<()> {
+    let serialized = toml::to_string_pretty(cache)?;
+    fs::write(path, serialized)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use libmem::Prot;
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    const WORLD: &str = r#"
+[[signature]]
+name = "World"
+pattern = "48 8B 05 ?? ?? ?? ??"
+ops = [{ rip_relative = { disp = 3, len = 7 } }, "deref", "non_null"]
+"#;
+
+    /// Where the `mov rax, [rip+disp32]` of the world global is
+    const MATCH: Address = 0x10100;
+    /// The global it loads, which points at the world
+    const GLOBAL: Address = 0x10800;
+
+    /// A game with the world global, and a database with one signature for it
+    fn game(test: &str, database: &str) -> (FakeMemory, PathBuf) {
+        let memory = FakeMemory::new();
+
+        let mut code = vec![0xCC; 0x200];
+        code[0x100..0x107].copy_from_slice(&mov_rax(MATCH, GLOBAL));
+        memory.map(0x10000, code, Prot::XR);
+
+        let mut data = 0x50000u64.to_le_bytes().to_vec();
+        data.extend_from_slice(&0x70000u64.to_le_bytes());
+        memory.map(GLOBAL, data, Prot::RW);
+
+        memory.add_module("game.exe", 0x10000, 0x1000);
+
+        let dir = std::env::temp_dir().join(format!("signatures-{test}-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(SIGNATURES_FILENAME), database).unwrap();
+
+        (memory, dir)
+    }
+
+    /// `mov rax, [rip+disp32]` at `at`, loading `global`
+    fn mov_rax(at: Address, global: Address) -> [u8; 7] {
+        let disp = (global - (at + 7)) as i32;
+
+        let mut code = [0x48, 0x8B, 0x05, 0, 0, 0, 0];
+        code[3..].copy_from_slice(&disp.to_le_bytes());
+        code
+    }
+
+    /// The same instruction, earlier in the code and loading the pointer after the global. A scan
+    /// finds this one first, a cache hit never looks at it
+    fn decoy(memory: &FakeMemory) {
+        memory
+            .write_code(0x10000, &mov_rax(0x10000, GLOBAL + 8))
+            .unwrap();
+    }
+
+    fn load(memory: &FakeMemory, dir: &Path, game_id: &str) -> Signatures {
+        Signatures::load_with(
+            memory,
+            "game.exe",
+            game_id.to_owned(),
+            &dir.join(SIGNATURES_FILENAME),
+            &dir.join(CACHE_FILENAME),
+        )
+        .unwrap()
+    }
+
+    fn cached(dir: &Path, game_id: &str) -> BTreeMap<String, usize> {
+        load_cache(&dir.join(CACHE_FILENAME), game_id.to_owned())
+            .entries
+            .into_iter()
+            .map(|(name, entry)| (name, entry.match_rva))
+            .collect()
+    }
+
+    #[test]
+    fn cache_hit_runs_the_ops_again() {
+        let (memory, dir) = game("hit", WORLD);
+
+        assert_eq!(load(&memory, &dir, "a").get("World").unwrap(), 0x50000);
+        assert_eq!(cached(&dir, "a"), [("World".to_owned(), 0x100)].into());
+
+        // the world got created somewhere else this time. the match is cached, the deref isn't
+        decoy(&memory);
+        memory.write(GLOBAL, 0x60000u64).unwrap();
+
+        assert_eq!(load(&memory, &dir, "a").get("World").unwrap(), 0x60000);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_when_the_pattern_moved() {
+        let (memory, dir) = game("moved", WORLD);
+        load(&memory, &dir, "a");
+
+        // same game id, but the cached match isn't there anymore
+        memory.write_code(MATCH, &[0xCC; 7]).unwrap();
+        decoy(&memory);
+
+        assert_eq!(load(&memory, &dir, "a").get("World").unwrap(), 0x70000);
+        assert_eq!(cached(&dir, "a"), [("World".to_owned(), 0)].into());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn new_game_id_rescans() {
+        let (memory, dir) = game("game-id", WORLD);
+        load(&memory, &dir, "a");
+
+        decoy(&memory);
+
+        assert_eq!(load(&memory, &dir, "b").get("World").unwrap(), 0x70000);
+        assert_eq!(cached(&dir, "b"), [("World".to_owned(), 0)].into());
+
+        // the old game's cache is gone
+        assert!(cached(&dir, "a").is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn changed_definition_rescans() {
+        let (memory, dir) = game("definition", WORLD);
+        load(&memory, &dir, "a");
+
+        decoy(&memory);
+
+        // no deref anymore, so it's the global itself
+        let changed = WORLD.replace(r#", "deref", "non_null""#, "");
+        fs::write(dir.join(SIGNATURES_FILENAME), changed).unwrap();
+
+        assert_eq!(load(&memory, &dir, "a").get("World").unwrap(), GLOBAL + 8);
+        assert_eq!(cached(&dir, "a"), [("World".to_owned(), 0)].into());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failures() {
+        let database = format!(
+            r#"{WORLD}
+[[signature]]
+name = "Missing"
+pattern = "0F 0B"
+
+[[signature]]
+name = "Elsewhere"
+module = "other.dll"
+pattern = "48 8B 05"
+"#
+        );
+
+        let (memory, dir) = game("failures", &database);
+
+        let signatures = load(&memory, &dir, "a");
+        assert_eq!(signatures.get("World").unwrap(), 0x50000);
+        assert_eq!(signatures.iter().count(), 1);
+
+        let err = signatures.get("Missing").unwrap_err().to_string();
+        assert!(
+            err.contains("failed to resolve") && err.contains("not found"),
+            "{err}"
+        );
+
+        let err = signatures.get("Elsewhere").unwrap_err().to_string();
+        assert!(err.contains("other.dll is not loaded"), "{err}");
+
+        let err = signatures.get("Nope").unwrap_err().to_string();
+        assert!(err.contains("no signature named Nope"), "{err}");
+
+        // failures aren't cached
+        assert_eq!(cached(&dir, "a"), [("World".to_owned(), 0x100)].into());
+
+        // a cached match whose ops fail now is dropped, and scanned for again next time
+        memory.write(GLOBAL, 0u64).unwrap();
+
+        let signatures = load(&memory, &dir, "a");
+        let err = signatures.get("World").unwrap_err().to_string();
+        assert!(err.contains("failed to resolve"), "{err}");
+        assert!(cached(&dir, "a").is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    marker::PhantomData,
+    mem, ptr,
+    sync::{
+        Mutex, MutexGuard,
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+    },
+};
+
+use eyre::{Result, bail};
+use libmem::Address;
+
+use crate::hooks::{self, HookHandle};
+
+/// Storage for a hook with a known function signature. You normally don't use this directly,
+/// [`hook!`] declares one for you along with a typed `original(..)`
+///
+/// `F` is the hooked function's pointer type, e.g. `unsafe extern "C" fn(i32) -> i32`
+pub struct TypedHook<F: Copy> {
+    name: &'static str,
+    handle: Mutex<Option<HookHandle>>,
+    /// [`HookHandle::original_cell`] of the last install, null if it was never installed.
+    /// Detours call `original` all the time, so it's read from here without locking
+    original: AtomicPtr<AtomicUsize>,
+    _f: PhantomData<F>,
+}
+
+impl<F: Copy> TypedHook<F> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            handle: Mutex::new(None),
+            original: AtomicPtr::new(ptr::null_mut()),
+            _f: PhantomData,
+        }
+    }
+
+    fn handle(&self) -> MutexGuard<'_, Option<HookHandle>> {
+        self.handle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Detour `target` to `detour`
+    ///
+    /// # Safety
+    /// `target` must be the start of a function whose signature and calling convention is exactly `F`
+    pub unsafe fn install(&self, target: Address, detour: F) -> Result<()> {
+        const {
+            assert!(
+                mem::size_of::<F>() == mem::size_of::<Address>(),
+                "F must be a function pointer"
+            );
+        }
+
+        let mut handle = self.handle();
+        if handle.is_some() {
+            bail!("hook {} is already installed", self.name);
+        }
+
+        let detour = unsafe { mem::transmute_copy::<F, Address>(&detour) };
+        let installed = unsafe { hooks::install(self.name, target, detour)? };
+
+        let cell = installed.original_cell() as *const AtomicUsize;
+        self.original.store(cell.cast_mut(), Ordering::Release);
+        *handle = Some(installed);
+
+        Ok(())
+    }
+
+    /// The original function, callable through the trampoline
+    ///
+    /// While the hook is disabled, or after it was uninstalled, this is the function itself.
+    /// Only fails if the hook was never installed
+    pub fn original(&self) -> Result<F> {
+        // the cell is leaked, it's valid forever
+        let Some(cell) = (unsafe { self.original.load(Ordering::Acquire).as_ref() }) else {
+            bail!("hook {} was never installed", self.name);
+        };
+
+        let address = hooks::original(cell);
+
+        // install() made sure the address is of type F
+        Ok(unsafe { mem::transmute_copy::<Address, F>(&address) })
+    }
+
+    pub fn is_installed(&self) -> bool {
+        self.handle().is_some()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.handle().as_ref().is_some_and(HookHandle::is_enabled)
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        match &*self.handle() {
+            Some(handle) => handle.enable(),
+            None => bail!("hook {} is not installed", self.name),
+        }
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        match &*self.handle() {
+            Some(handle) => handle.disable(),
+            None => bail!("hook {} is not installed", self.name),
+        }
+    }
+
+    /// Unhook and forget about the hook, so it can be installed again
+    pub fn uninstall(&self) {
+        // dropping the handle unhooks it
+        self.handle().take();
+    }
+}
+
+/// Declare a hook with its exact signature and calling convention
+///
+/// This generates a type with the detour, and typed `install`/`original` functions, so nobody
+/// has to transmute trampolines by hand:
+///
+/// ```ignore
+/// hook! {
+///     /// Called every frame
+///     pub GameUpdate: extern "C" fn(this: *mut c_void, dt: f32) -> u64 {
+///         // runs instead of the game's function
+///         GameUpdate::original(this, dt)
+///     }
+/// }
+///
+/// // in on_load
+/// unsafe { GameUpdate::install(address)? };
+/// ```
+///
+/// `original` panics if the hook was never installed, which can't happen from inside of the detour.
+///
+/// A panic in the detour is caught and logged, and the original function is called instead, so the
+/// game never sees it. That's why the arguments have to be `Copy`, which they always are in an
+/// `extern` function anyways
+// only the plugin (windows only) declares hooks
+#[cfg_attr(not(windows), allow(unused_macros))]
+macro_rules! hook {
+    (
+        $(#[$meta:meta])*
+        $vis:vis $name:ident: extern $abi:literal fn($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?
+        $body:block
+    ) => {
+        $(#[$meta])*
+        $vis struct $name;
+
+        #[allow(dead_code)]
+        impl $name {
+            fn hook() -> &'static $crate::typed_hook::TypedHook<unsafe extern $abi fn($($ty),*) $(-> $ret)?> {
+                static HOOK: $crate::typed_hook::TypedHook<unsafe extern $abi fn($($ty),*) $(-> $ret)?> =
+                    $crate::typed_hook::TypedHook::new(stringify!($name));
+
+                &HOOK
+            }
+
+            extern $abi fn detour($($arg: $ty),*) $(-> $ret)? {
+                // unwinding out of an extern fn aborts, which takes the game with it
+                match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| $body)) {
+                    Ok(result) => result,
+
+                    Err(e) => {
+                        // the panic hook already logged it. dropping the payload could panic again
+                        ::std::mem::forget(e);
+                        ::log::error!("hook {} panicked, calling the original instead", stringify!($name));
+
+                        Self::original($($arg),*)
+                    }
+                }
+            }
+
+            /// Detour `target` to this hook
+            ///
+            /// # Safety
+            /// `target` must be the start of a function with exactly this hook's signature and calling convention
+            $vis unsafe fn install(target: ::libmem::Address) -> ::eyre::Result<()> {
+                unsafe { Self::hook().install(target, Self::detour) }
+            }
+
+            /// Call the original function
+            $vis fn original($($arg: $ty),*) $(-> $ret)? {
+                let original = match Self::hook().original() {
+                    Ok(original) => original,
+
+                    // can't happen in the detour, so this isn't unwinding through the game
+                    Err(e) => {
+                        ::log::error!("{e}");
+                        panic!("{e}");
+                    }
+                };
+
+                // install() is where the caller promised the signature is correct
+                unsafe { original($($arg),*) }
+            }
+
+            $vis fn enable() -> ::eyre::Result<()> {
+                Self::hook().enable()
+            }
+
+            $vis fn disable() -> ::eyre::Result<()> {
+                Self::hook().disable()
+            }
+
+            $vis fn is_enabled() -> bool {
+                Self::hook().is_enabled()
+            }
+
+            $vis fn uninstall() {
+                Self::hook().uninstall()
+            }
+        }
+    };
+}
+
+#[cfg_attr(not(windows), allow(unused_imports))]
+pub(crate) use hook;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    super::hook! {
+        Double: extern "C" fn(x: i32) -> i32 {
+            if x < 0 {
+                panic!("can't double a negative number");
+            }
+
+            Double::original(x) * 2
+        }
+    }
+
+    extern "C" fn identity(x: i32) -> i32 {
+        x
+    }
+
+    /// What `install` leaves behind, without hooking anything
+    fn pretend_installed<F: Copy>(hook: &TypedHook<F>, original: Address) {
+        let cell = Box::leak(Box::new(AtomicUsize::new(original)));
+        hook.original.store(cell, Ordering::Release);
+    }
+
+    #[test]
+    fn original_before_install() {
+        let hook = TypedHook::<extern "C" fn()>::new("never installed");
+
+        assert!(!hook.is_installed());
+        assert!(hook.original().is_err());
+    }
+
+    #[test]
+    fn detour_calls_original() {
+        let identity: extern "C" fn(i32) -> i32 = identity;
+        pretend_installed(Double::hook(), identity as Address);
+
+        assert_eq!(Double::detour(21), 42);
+
+        // the panic is caught, and the original runs instead
+        assert_eq!(Double::detour(-21), -21);
+    }
+}
@@ -0,0 +1,582 @@
+use std::{
+    mem,
+    sync::{Mutex, Once},
+};
+
+use eyre::{Result, bail, eyre};
+use libmem::{Address, Prot};
+use log::{debug, error, info, warn};
+
+use crate::{
+    game_version,
+    memory::{CurrentProcess, MemoryBackend},
+    shutdown::{self, Detach},
+};
+
+const PTR: usize = mem::size_of::<Address>();
+
+/// A pointer we swapped in the game, to put back on unload if whoever swapped it is still around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Swap {
+    address: Address,
+    ours: Address,
+    original: Address,
+    /// Vtables are in read only memory, objects aren't
+    code: bool,
+}
+
+impl Swap {
+    /// Put the original pointer back. `false` if it was left alone, because the object doesn't
+    /// point at our copy anymore
+    fn restore(&self, memory: &impl MemoryBackend) -> Result<bool> {
+        let current = memory.read::<Address>(self.address)?;
+
+        if current != self.ours {
+            // the object was freed, and whatever lives there now isn't ours to write to
+            if !self.code {
+                warn!(
+                    "object at {:#x} doesn't use our vtable anymore ({current:#x}), leaving it alone",
+                    self.address
+                );
+
+                return Ok(false);
+            }
+
+            // someone else hooked the slot after us. putting ours back cuts them off,
+            // but leaving it would have calls go to wherever we point after we're gone
+            warn!(
+                "vtable slot at {:#x} was changed to {current:#x} by someone else, overwriting it",
+                self.address
+            );
+        }
+
+        let bytes = self.original.to_le_bytes();
+        if self.code {
+            memory.write_code(self.address, &bytes)?;
+        } else {
+            memory.write_bytes(self.address, &bytes)?;
+        }
+
+        Ok(true)
+    }
+}
+
+/// Every swap in the game that's still in place, in install order
+static SWAPS: Mutex<Vec<Swap>> = Mutex::new(Vec::new());
+
+fn track(swap: Swap) {
+    register_teardown();
+
+    let mut swaps = SWAPS.lock().unwrap_or_else(|e| e.into_inner());
+    swaps.push(swap);
+}
+
+/// Stop tracking a swap. `false` if it was already restored (or skipped) on unload
+fn untrack(swap: &Swap) -> bool {
+    let mut swaps = SWAPS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let Some(idx) = swaps.iter().position(|s| s == swap) else {
+        return false;
+    };
+
+    swaps.remove(idx);
+
+    true
+}
+
+/// Hook of one slot of a vtable, patched in place
+///
+/// Every object of the class (and of subclasses that don't override it) goes through the hook.
+/// Puts the original pointer back when dropped!
+///
+/// ```ignore
+/// // hook IGameSystem::Update, the 5th virtual function
+/// let hook = unsafe { VmtHook::install(vtable, 4, update_detour as Address)? };
+///
+/// // in the detour
+/// let original: extern "C" fn(*mut c_void, f32) = unsafe { mem::transmute(hook.original()) };
+/// ```
+pub struct VmtHook<M: MemoryBackend = CurrentProcess> {
+    memory: M,
+    index: usize,
+    swap: Swap,
+    tracked: bool,
+}
+
+impl VmtHook {
+    /// Point slot `index` of the game's `vtable` at `detour`
+    ///
+    /// Fails if the game version check refused this version of the game
+    ///
+    /// # Safety
+    /// `vtable` must be a vtable with at least `index + 1` entries, and `detour` a function with the
+    /// exact same signature and calling convention (`this` first) as the one in the slot
+    pub unsafe fn install(vtable: Address, index: usize, detour: Address) -> Result<Self> {
+        game_version::ensure_allowed(&format!("vtable hook {vtable:#x}[{index}]"))?;
+
+        let mut hook = unsafe { Self::install_with(CurrentProcess, vtable, index, detour)? };
+
+        track(hook.swap);
+        hook.tracked = true;
+
+        Ok(hook)
+    }
+}
+
+impl<M: MemoryBackend> VmtHook<M> {
+    /// [`VmtHook::install`] through any [`MemoryBackend`], e.g. `FakeMemory`. Isn't restored on
+    /// unload, only when dropped
+    ///
+    /// # Safety
+    /// See [`VmtHook::install`]
+    pub unsafe fn install_with(
+        memory: M,
+        vtable: Address,
+        index: usize,
+        detour: Address,
+    ) -> Result<Self> {
+        let slot = vtable + index * PTR;
+
+        let original = memory
+            .read::<Address>(slot)
+            .map_err(|e| eyre!("vtable hook {vtable:#x}[{index}]: {e}"))?;
+
+        // vtables are in .rdata
+        memory
+            .write_code(slot, &detour.to_le_bytes())
+            .map_err(|e| eyre!("vtable hook {vtable:#x}[{index}]: {e}"))?;
+
+        info!("hooked vtable {vtable:#x}[{index}] (original {original:#x})");
+
+        Ok(Self {
+            memory,
+            index,
+            swap: Swap {
+                address: slot,
+                ours: detour,
+                original,
+                code: true,
+            },
+            tracked: false,
+        })
+    }
+
+    /// The function that was in the slot, to call through to
+    pub fn original(&self) -> Address {
+        self.swap.original
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Address of the vtable slot we patched
+    pub fn slot(&self) -> Address {
+        self.swap.address
+    }
+}
+
+impl VmtHook {
+    /// Keep the hook installed until the plugin unloads, instead of until this is dropped
+    pub fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl<M: MemoryBackend> Drop for VmtHook<M> {
+    fn drop(&mut self) {
+        // already put back on unload
+        if self.tracked && !untrack(&self.swap) {
+            return;
+        }
+
+        match self.swap.restore(&self.memory) {
+            Ok(_) => debug!("unhooked vtable slot {:#x}", self.swap.address),
+            Err(e) => error!("failed to unhook vtable slot {:#x}: {e}", self.swap.address),
+        }
+    }
+}
+
+/// A private copy of an object's vtable, so hooks only see calls on that one object
+///
+/// The object's vtable pointer is pointed at the copy, and hooks go into the copy. Every other
+/// object of the class keeps using the real vtable. Puts the object's vtable pointer back when
+/// dropped; make sure that happens before the game frees the object! If the object doesn't point
+/// at the copy anymore by then, it's left alone
+///
+/// ```ignore
+/// let mut shadow = unsafe { ShadowVmt::install(object, 40)? };
+/// let original = unsafe { shadow.hook(4, update_detour as Address)? };
+/// ```
+pub struct ShadowVmt<M: MemoryBackend = CurrentProcess> {
+    memory: M,
+    object: Address,
+    /// The real vtable
+    vtable: Address,
+    /// Our allocation. Starts with the entry before the vtable, which MSVC points at the RTTI
+    copy: Address,
+    len: usize,
+    swap: Swap,
+    tracked: bool,
+}
+
+impl ShadowVmt {
+    /// Give the game's `object` its own copy of the first `len` entries of its vtable
+    ///
+    /// Fails if the game version check refused this version of the game
+    ///
+    /// # Safety
+    /// `object` must be a live object with a vtable pointer at its start, and its vtable must have at least
+    /// `len` entries. Calls to virtual functions past `len` read garbage
+    pub unsafe fn install(object: Address, len: usize) -> Result<Self> {
+        game_version::ensure_allowed(&format!("shadow vtable for {object:#x}"))?;
+
+        let mut shadow = unsafe { Self::install_with(CurrentProcess, object, len)? };
+
+        track(shadow.swap);
+        shadow.tracked = true;
+
+        Ok(shadow)
+    }
+}
+
+impl<M: MemoryBackend> ShadowVmt<M> {
+    /// [`ShadowVmt::install`] through any [`MemoryBackend`], e.g. `FakeMemory`. Isn't restored on
+    /// unload, only when dropped
+    ///
+    /// # Safety
+    /// See [`ShadowVmt::install`]
+    pub unsafe fn install_with(memory: M, object: Address, len: usize) -> Result<Self> {
+        if len == 0 {
+            bail!("shadow vtable for {object:#x}: can't copy an empty vtable");
+        }
+
+        let vtable = memory
+            .read::<Address>(object)
+            .map_err(|e| eyre!("shadow vtable for {object:#x}: {e}"))?;
+
+        let mut entries = vec![0; (len + 1) * PTR];
+
+        // the RTTI pointer isn't there on every compiler, and dynamic_cast is the only one who cares
+        if memory
+            .read_bytes(vtable.wrapping_sub(PTR), &mut entries[..PTR])
+            .is_err()
+        {
+            debug!("no entry before vtable {vtable:#x}, leaving it empty in the copy");
+        }
+
+        memory
+            .read_bytes(vtable, &mut entries[PTR..])
+            .map_err(|e| eyre!("shadow vtable for {object:#x}: {e}"))?;
+
+        let copy = memory.allocate(entries.len(), Prot::R)?;
+
+        let copied = memory
+            .write_code(copy, &entries)
+            .and_then(|_| memory.write_bytes(object, &(copy + PTR).to_le_bytes()));
+
+        if let Err(e) = copied {
+            _ = memory.free(copy, entries.len());
+            bail!("shadow vtable for {object:#x}: {e}");
+        }
+
+        info!("shadowed vtable {vtable:#x} of {object:#x} with {len} entries");
+
+        Ok(Self {
+            memory,
+            object,
+            vtable,
+            copy,
+            len,
+            swap: Swap {
+                address: object,
+                ours: copy + PTR,
+                original: vtable,
+                code: false,
+            },
+            tracked: false,
+        })
+    }
+
+    fn slot(&self, index: usize) -> Result<Address> {
+        if index >= self.len {
+            bail!(
+                "shadow vtable for {:#x}: index {index} is past the {} copied entries",
+                self.object,
+                self.len
+            );
+        }
+
+        Ok(self.copy + (index + 1) * PTR)
+    }
+
+    /// Point slot `index` of the copy at `detour`. Returns the real vtable's function, to call through to
+    ///
+    /// # Safety
+    /// `detour` must be a function with the exact same signature and calling convention (`this` first)
+    /// as the one in the slot
+    pub unsafe fn hook(&mut self, index: usize, detour: Address) -> Result<Address> {
+        let slot = self.slot(index)?;
+        self.memory.write_code(slot, &detour.to_le_bytes())?;
+
+        debug!(
+            "hooked slot {index} of shadow vtable for {:#x}",
+            self.object
+        );
+
+        self.original(index)
+    }
+
+    /// Put the real vtable's function back in slot `index` of the copy
+    pub fn unhook(&mut self, index: usize) -> Result<()> {
+        let slot = self.slot(index)?;
+        let original = self.original(index)?;
+
+        self.memory.write_code(slot, &original.to_le_bytes())
+    }
+
+    /// The function in slot `index` of the real vtable
+    pub fn original(&self, index: usize) -> Result<Address> {
+        self.slot(index)?;
+        self.memory.read::<Address>(self.vtable + index * PTR)
+    }
+
+    pub fn object(&self) -> Address {
+        self.object
+    }
+
+    /// The object's real vtable
+    pub fn vtable(&self) -> Address {
+        self.vtable
+    }
+
+    /// The copy the object uses now
+    pub fn shadow(&self) -> Address {
+        self.copy + PTR
+    }
+}
+
+impl<M: MemoryBackend> Drop for ShadowVmt<M> {
+    fn drop(&mut self) {
+        // untracked means unhook_all already dealt with the object. if that had failed, it would
+        // still be tracked
+        let restore = !self.tracked || untrack(&self.swap);
+
+        if restore && let Err(e) = self.swap.restore(&self.memory) {
+            // the object might still be using the copy, so it has to stay around
+            error!(
+                "failed to restore the vtable of {:#x}, leaking its copy: {e}",
+                self.object
+            );
+            return;
+        }
+
+        if let Err(e) = self.memory.free(self.copy, (self.len + 1) * PTR) {
+            error!("failed to free shadow vtable of {:#x}: {e}", self.object);
+        }
+
+        debug!("removed shadow vtable of {:#x}", self.object);
+    }
+}
+
+/// Put back every vtable slot and object still hooked, in reverse install order
+///
+/// The ones that fail stay tracked, so their hook doesn't free anything they might still point at
+pub fn unhook_all() {
+    let mut swaps = SWAPS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut failed = Vec::new();
+    for swap in mem::take(&mut *swaps).into_iter().rev() {
+        if let Err(e) = swap.restore(&CurrentProcess) {
+            error!("failed to restore pointer at {:#x}: {e}", swap.address);
+            failed.push(swap);
+        }
+    }
+
+    failed.reverse();
+    *swaps = failed;
+}
+
+fn register_teardown() {
+    static TEARDOWN: Once = Once::new();
+
+    TEARDOWN.call_once(|| {
+        shutdown::register("vtable hooks", |reason| {
+            // the process is going away, nobody will make virtual calls anymore
+            if reason == Detach::ProcessExit {
+                return;
+            }
+
+            // the detours live in our dll. shadow vtables are allocated separately and stay valid,
+            // but they point at our detours too
+            unhook_all();
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    const VTABLE: Address = 0x5008;
+    const OBJECTS: Address = 0x9000;
+
+    /// A read only vtable with an RTTI pointer and 4 functions, and two objects using it
+    fn memory() -> FakeMemory {
+        let vtable = [0xAAAA, 0x100, 0x200, 0x300, 0x400usize];
+        let objects = [VTABLE, VTABLE];
+
+        let memory = FakeMemory::new();
+        memory.map(VTABLE - PTR, bytes(&vtable), Prot::R);
+        memory.map(OBJECTS, bytes(&objects), Prot::RW);
+
+        memory
+    }
+
+    fn bytes(pointers: &[usize]) -> Vec<u8> {
+        pointers.iter().flat_map(|p| p.to_le_bytes()).collect()
+    }
+
+    fn pointer(memory: &FakeMemory, address: Address) -> Address {
+        memory.read::<Address>(address).unwrap()
+    }
+
+    #[test]
+    fn in_place() {
+        let memory = memory();
+
+        {
+            let hook = unsafe { VmtHook::install_with(&memory, VTABLE, 2, 0xBEEF) }.unwrap();
+
+            assert_eq!(hook.original(), 0x300);
+            assert_eq!(hook.slot(), VTABLE + 16);
+            assert_eq!(pointer(&memory, VTABLE + 16), 0xBEEF);
+
+            // still read only
+            assert!(memory.write_bytes(VTABLE, &[0]).is_err());
+        }
+
+        assert_eq!(pointer(&memory, VTABLE + 16), 0x300);
+
+        // past the end of the vtable
+        assert!(unsafe { VmtHook::install_with(&memory, VTABLE, 4, 0xBEEF) }.is_err());
+    }
+
+    #[test]
+    fn in_place_hooked_again() {
+        let memory = memory();
+        let hook = unsafe { VmtHook::install_with(&memory, VTABLE, 0, 0xBEEF) }.unwrap();
+
+        // someone else hooks it after us. we still put the original back, our detour is going away
+        memory
+            .write_code(VTABLE, &0xF00Dusize.to_le_bytes())
+            .unwrap();
+        drop(hook);
+
+        assert_eq!(pointer(&memory, VTABLE), 0x100);
+    }
+
+    #[test]
+    fn shadow() {
+        let memory = memory();
+
+        let copy = {
+            let mut shadow = unsafe { ShadowVmt::install_with(&memory, OBJECTS, 4) }.unwrap();
+            let copy = shadow.shadow();
+
+            assert_eq!(shadow.vtable(), VTABLE);
+            assert_eq!(pointer(&memory, OBJECTS), copy);
+            assert_eq!(pointer(&memory, copy - PTR), 0xAAAA);
+
+            // only the one object
+            assert_eq!(pointer(&memory, OBJECTS + PTR), VTABLE);
+
+            assert_eq!(unsafe { shadow.hook(1, 0xF00D) }.unwrap(), 0x200);
+            assert_eq!(pointer(&memory, copy + PTR), 0xF00D);
+            assert_eq!(pointer(&memory, VTABLE + PTR), 0x200);
+
+            // the copy is read only, like the real one
+            assert!(memory.write_bytes(copy, &[0]).is_err());
+
+            assert!(unsafe { shadow.hook(4, 0xF00D) }.is_err());
+            assert!(shadow.original(4).is_err());
+
+            shadow.unhook(1).unwrap();
+            assert_eq!(pointer(&memory, copy + PTR), 0x200);
+
+            unsafe { shadow.hook(3, 0xF00D) }.unwrap();
+            assert_eq!(shadow.original(3).unwrap(), 0x400);
+
+            copy
+        };
+
+        assert_eq!(pointer(&memory, OBJECTS), VTABLE);
+
+        // and the copy is gone
+        assert!(memory.peek(copy, PTR).is_err());
+    }
+
+    #[test]
+    fn shadow_object_reused() {
+        let memory = memory();
+        let shadow = unsafe { ShadowVmt::install_with(&memory, OBJECTS, 4) }.unwrap();
+        let copy = shadow.shadow();
+
+        // the game freed the object, and something else lives there now
+        memory.write::<Address>(OBJECTS, 0x7777).unwrap();
+        drop(shadow);
+
+        assert_eq!(pointer(&memory, OBJECTS), 0x7777);
+        assert!(memory.peek(copy, PTR).is_err());
+    }
+
+    #[test]
+    fn shadow_bad_objects() {
+        let memory = memory();
+
+        assert!(unsafe { ShadowVmt::install_with(&memory, 0x1, 4) }.is_err());
+        assert!(unsafe { ShadowVmt::install_with(&memory, OBJECTS, 0) }.is_err());
+
+        // longer than the vtable
+        assert!(unsafe { ShadowVmt::install_with(&memory, OBJECTS, 5) }.is_err());
+        assert_eq!(pointer(&memory, OBJECTS), VTABLE);
+    }
+
+    #[test]
+    fn restore() {
+        let memory = memory();
+
+        let object = Swap {
+            address: OBJECTS,
+            ours: 0x6008,
+            original: VTABLE,
+            code: false,
+        };
+
+        // not pointing at our copy, left alone
+        assert!(!object.restore(&memory).unwrap());
+        assert_eq!(pointer(&memory, OBJECTS), VTABLE);
+
+        memory.write::<Address>(OBJECTS, 0x6008).unwrap();
+        assert!(object.restore(&memory).unwrap());
+        assert_eq!(pointer(&memory, OBJECTS), VTABLE);
+
+        let slot = Swap {
+            address: VTABLE,
+            ours: 0xBEEF,
+            original: 0x111,
+            code: true,
+        };
+
+        // overwritten no matter what's there
+        assert!(slot.restore(&memory).unwrap());
+        assert_eq!(pointer(&memory, VTABLE), 0x111);
+
+        let unmapped = Swap {
+            address: 0x1,
+            ..slot
+        };
+        assert!(unmapped.restore(&memory).is_err());
+    }
+}
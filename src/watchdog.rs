@@ -0,0 +1,209 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::worker;
+
+/// How often the watchdog checks on the running phases
+const POLL: Duration = Duration::from_millis(100);
+
+/// Deadline used until the config is loaded
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(5);
+
+struct Running {
+    name: &'static str,
+    start: Instant,
+    warned: bool,
+}
+
+struct Watchdog {
+    start: Instant,
+    /// Time spent in [`untimed`], which doesn't count towards the total
+    excluded: Duration,
+    deadline: Duration,
+    running: Vec<Running>,
+    done: bool,
+}
+
+static WATCHDOG: Mutex<Option<Watchdog>> = Mutex::new(None);
+
+/// Start timing init, and spawn the watchdog thread
+///
+/// The watchdog warns about any phase still running after `deadline`, so if something
+/// blocks forever (e.g. waiting on a pattern that never appears) it at least shows up in the log
+pub fn start(deadline: Duration) {
+    let Ok(mut watchdog) = WATCHDOG.lock() else {
+        return;
+    };
+
+    if watchdog.is_some() {
+        return;
+    }
+
+    *watchdog = Some(Watchdog {
+        start: Instant::now(),
+        excluded: Duration::ZERO,
+        deadline,
+        running: Vec::new(),
+        done: false,
+    });
+
+    // everything else is waiting on init, so this isn't worth failing it over
+    let spawned = worker::spawn("watchdog", |token| {
+        while !token.wait(POLL) {
+            let Ok(mut watchdog) = WATCHDOG.lock() else {
+                return;
+            };
+
+            let Some(watchdog) = watchdog.as_mut() else {
+                return;
+            };
+
+            if watchdog.done {
+                return;
+            }
+
+            let deadline = watchdog.deadline;
+            for phase in watchdog.running.iter_mut().filter(|p| !p.warned) {
+                let elapsed = phase.start.elapsed();
+                if elapsed > deadline {
+                    phase.warned = true;
+                    warn!(
+                        "init phase `{}` is still running after {}ms (deadline {}ms)",
+                        phase.name,
+                        elapsed.as_millis(),
+                        deadline.as_millis()
+                    );
+                }
+            }
+        }
+    });
+
+    if let Err(e) = spawned {
+        warn!("failed to start the init watchdog: {e}");
+    }
+}
+
+/// Change the deadline, e.g. once the config has been loaded
+pub fn set_deadline(deadline: Duration) {
+    if let Ok(mut watchdog) = WATCHDOG.lock()
+        && let Some(watchdog) = watchdog.as_mut()
+    {
+        watchdog.deadline = deadline;
+    }
+}
+
+/// Time an init phase until the returned guard is dropped
+///
+/// ```ignore
+/// let _phase = watchdog::phase("pattern scans");
+/// // scan..
+/// ```
+///
+/// Phases can be nested. Use this in your plugin's `on_load` too!
+pub fn phase(name: &'static str) -> Phase {
+    let start = Instant::now();
+
+    if let Ok(mut watchdog) = WATCHDOG.lock()
+        && let Some(watchdog) = watchdog.as_mut()
+        && !watchdog.done
+    {
+        watchdog.running.push(Running {
+            name,
+            start,
+            warned: false,
+        });
+    }
+
+    Phase { name, start }
+}
+
+/// Run `f` without counting the time it takes towards init, e.g. waiting for a debugger to attach
+///
+/// Meant for between phases. A phase still running around it will count the time anyways
+pub fn untimed<R>(f: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+
+    if let Ok(mut watchdog) = WATCHDOG.lock()
+        && let Some(watchdog) = watchdog.as_mut()
+    {
+        watchdog.excluded += elapsed;
+    }
+
+    result
+}
+
+/// Stop the watchdog and log how long init took in total
+pub fn finish(success: bool) {
+    let Ok(mut watchdog) = WATCHDOG.lock() else {
+        return;
+    };
+
+    let Some(watchdog) = watchdog.as_mut() else {
+        return;
+    };
+
+    if watchdog.done {
+        return;
+    }
+
+    watchdog.done = true;
+
+    let elapsed = watchdog
+        .start
+        .elapsed()
+        .saturating_sub(watchdog.excluded)
+        .as_millis();
+
+    if success {
+        info!("init completed in {elapsed}ms");
+    } else {
+        warn!("init failed after {elapsed}ms");
+    }
+}
+
+/// Guard for a running init phase
+#[must_use = "the phase ends when this is dropped"]
+pub struct Phase {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+
+        let mut late = None;
+        if let Ok(mut watchdog) = WATCHDOG.lock()
+            && let Some(watchdog) = watchdog.as_mut()
+        {
+            // phases are nearly always dropped in reverse, so search from the back
+            if let Some(idx) = watchdog
+                .running
+                .iter()
+                .rposition(|p| p.name == self.name && p.start == self.start)
+            {
+                let phase = watchdog.running.remove(idx);
+                // the watchdog already warned about this one while it was running
+                if !phase.warned && elapsed > watchdog.deadline {
+                    late = Some(watchdog.deadline);
+                }
+            }
+        }
+
+        match late {
+            Some(deadline) => warn!(
+                "init phase `{}` took {}ms (deadline {}ms)",
+                self.name,
+                elapsed.as_millis(),
+                deadline.as_millis()
+            ),
+            None => info!("init phase `{}` took {}ms", self.name, elapsed.as_millis()),
+        }
+    }
+}
@@ -0,0 +1,278 @@
+#[cfg(windows)]
+use std::ffi::c_void;
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, Once},
+    time::Duration,
+};
+#[cfg(not(windows))]
+use std::{thread, time::Instant};
+
+use eyre::Result;
+use log::{debug, error, warn};
+#[cfg(windows)]
+use windows::{
+    Win32::{
+        Foundation::WAIT_OBJECT_0,
+        System::Threading::{
+            CreateThread, SetThreadDescription, THREAD_CREATE_RUN_IMMEDIATELY, WaitForSingleObject,
+        },
+    },
+    core::{HSTRING, Owned},
+};
+
+use crate::shutdown::{self, Detach};
+#[cfg(windows)]
+use crate::utils::{OwnedHandle, ThreadedWrapper};
+
+/// Cooperative cancellation for a worker thread
+///
+/// Workers should check this regularly, and return as soon as it's cancelled.
+/// Prefer [`CancellationToken::wait`] over `thread::sleep`, it wakes up the moment we're cancelled
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<(Mutex<bool>, Condvar)>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        let (cancelled, cvar) = &*self.0;
+        if let Ok(mut cancelled) = cancelled.lock() {
+            *cancelled = true;
+        }
+
+        cvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        let (cancelled, _) = &*self.0;
+        cancelled.lock().map(|c| *c).unwrap_or(true)
+    }
+
+    /// Sleep for `timeout`, or until cancelled. Returns whether we were cancelled
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (cancelled, cvar) = &*self.0;
+
+        let Ok(guard) = cancelled.lock() else {
+            return true;
+        };
+
+        cvar.wait_timeout_while(guard, timeout, |cancelled| !*cancelled)
+            .map(|(cancelled, _)| *cancelled)
+            .unwrap_or(true)
+    }
+}
+
+struct Worker {
+    name: String,
+    thread: Thread,
+    token: CancellationToken,
+}
+
+/// We do not use thread::spawn on windows, we want the handle to be ours
+#[cfg(windows)]
+struct Thread(ThreadedWrapper<OwnedHandle>);
+
+#[cfg(windows)]
+impl Thread {
+    fn spawn(name: &str, job: Job) -> Result<Self> {
+        // double box so we can pass a thin pointer through
+        let param = Box::into_raw(Box::new(job));
+
+        extern "system" fn trampoline(param: *mut c_void) -> u32 {
+            let job = unsafe { Box::from_raw(param as *mut Job) };
+            job();
+            0
+        }
+
+        let handle = unsafe {
+            CreateThread(
+                None,
+                0,
+                Some(trampoline),
+                Some(param as *const c_void),
+                THREAD_CREATE_RUN_IMMEDIATELY,
+                None,
+            )
+        };
+
+        let handle = match handle {
+            Ok(handle) => unsafe { Owned::new(handle) },
+            Err(e) => {
+                // the thread never started, so it's still ours to free
+                drop(unsafe { Box::from_raw(param) });
+                return Err(e.into());
+            }
+        };
+
+        // shows up in debuggers and crash dumps
+        if let Err(e) = unsafe { SetThreadDescription(*handle, &HSTRING::from(name)) } {
+            warn!("failed to name worker thread {name}: {e}");
+        }
+
+        Ok(Self(unsafe { ThreadedWrapper::new(handle) }))
+    }
+
+    /// Wait up to `timeout` for the thread to return
+    fn join(&self, timeout: Duration) -> bool {
+        let timeout = timeout.as_millis().min(u32::MAX as u128) as u32;
+        unsafe { WaitForSingleObject(**self.0.inner(), timeout) == WAIT_OBJECT_0 }
+    }
+}
+
+/// Linux only runs the tests, there's no loader lock to worry about
+#[cfg(not(windows))]
+struct Thread(thread::JoinHandle<()>);
+
+#[cfg(not(windows))]
+impl Thread {
+    fn spawn(name: &str, job: Job) -> Result<Self> {
+        Ok(Self(
+            thread::Builder::new().name(name.to_owned()).spawn(job)?,
+        ))
+    }
+
+    /// Wait up to `timeout` for the thread to return
+    fn join(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.0.is_finished() {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        true
+    }
+}
+
+/// Workers that haven't been seen exiting yet
+static WORKERS: Mutex<Vec<Worker>> = Mutex::new(Vec::new());
+
+/// How long [`stop_all`] waits for each worker when we're unloaded through `Uninit`
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Spawn a named, owned worker thread
+///
+/// The thread is handed a [`CancellationToken`] which is cancelled when the plugin is unloaded,
+/// or when [`stop_all`] is called. Whoever unloads us should call our `Uninit` export first, which
+/// waits for the workers to return. We can't wait under the loader lock, so any worker still
+/// running once `DllMain` sees the detach is reported in the log; its code is about to be unmapped.
+///
+/// Panics inside of the worker are caught (and logged by the panic hook)
+pub fn spawn(name: &str, f: impl FnOnce(CancellationToken) + Send + 'static) -> Result<()> {
+    register_teardown();
+
+    let token = CancellationToken::new();
+
+    let job: Job = {
+        let token = token.clone();
+        Box::new(move || {
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| f(token))) {
+                // same as in Init; the panic hook already logged it
+                mem::forget(e);
+            }
+        })
+    };
+
+    let thread = Thread::spawn(name, job)?;
+
+    debug!("spawned worker thread {name}");
+
+    if let Ok(mut workers) = WORKERS.lock() {
+        // forget about the ones that are done, so their handles don't pile up
+        workers.retain(|w| !w.thread.join(Duration::ZERO));
+
+        workers.push(Worker {
+            name: name.to_owned(),
+            thread,
+            token,
+        });
+    }
+
+    Ok(())
+}
+
+/// Spawn a worker that calls `f` every `interval` until cancelled, e.g. for polling or autosaving
+pub fn spawn_periodic(
+    name: &str,
+    interval: Duration,
+    mut f: impl FnMut() + Send + 'static,
+) -> Result<()> {
+    spawn(name, move |token| {
+        while !token.wait(interval) {
+            f();
+        }
+    })
+}
+
+/// Cancel every worker and wait up to `timeout` for each of them to exit
+///
+/// Must NOT be called under the loader lock (i.e. from `DllMain`) with a timeout,
+/// since exiting threads need the loader lock too. Workers that don't exit in time are kept
+/// around, and reported again on unload
+pub fn stop_all(timeout: Duration) {
+    let workers = match WORKERS.lock() {
+        Ok(mut workers) => mem::take(&mut *workers),
+        Err(_) => return,
+    };
+
+    for worker in &workers {
+        worker.token.cancel();
+    }
+
+    let mut running = Vec::new();
+    for worker in workers {
+        if worker.thread.join(timeout) {
+            debug!("worker thread {} exited", worker.name);
+        } else {
+            warn!(
+                "worker thread {} did not exit within {}ms",
+                worker.name,
+                timeout.as_millis()
+            );
+            running.push(worker);
+        }
+    }
+
+    if let Ok(mut workers) = WORKERS.lock() {
+        workers.extend(running);
+    }
+}
+
+fn register_teardown() {
+    static TEARDOWN: Once = Once::new();
+
+    TEARDOWN.call_once(|| {
+        shutdown::register("workers", |reason| {
+            // every other thread is already gone on process exit
+            if reason == Detach::ProcessExit {
+                return;
+            }
+
+            // no waiting under the loader lock. if `Uninit` was called, everyone is long gone.
+            // if not, whoever is still running is about to run into unmapped code
+            let workers = match WORKERS.lock() {
+                Ok(mut workers) => mem::take(&mut *workers),
+                Err(_) => return,
+            };
+
+            for worker in workers {
+                worker.token.cancel();
+
+                if !worker.thread.join(Duration::ZERO) {
+                    error!(
+                        "worker thread {} was still running when we were unloaded; call `Uninit` before FreeLibrary",
+                        worker.name
+                    );
+                }
+            }
+        });
+    });
+}